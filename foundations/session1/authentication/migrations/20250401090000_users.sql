@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS users (
+    username TEXT PRIMARY KEY NOT NULL,
+    password TEXT NOT NULL,
+    role TEXT NOT NULL
+);
@@ -0,0 +1,21 @@
+CREATE TABLE IF NOT EXISTS roles (
+    name TEXT PRIMARY KEY NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS role_permissions (
+    role TEXT NOT NULL,
+    permission TEXT NOT NULL,
+    PRIMARY KEY (role, permission)
+);
+
+CREATE TABLE IF NOT EXISTS user_roles (
+    username TEXT NOT NULL,
+    role TEXT NOT NULL,
+    PRIMARY KEY (username, role)
+);
+
+-- The built in roles, which replace the Admin and User role column
+INSERT INTO roles (name) VALUES ('admin'), ('user');
+INSERT INTO role_permissions (role, permission) VALUES ('admin', '*'), ('user', 'metrics.read');
+INSERT INTO user_roles (username, role) SELECT username, lower(role) FROM users;
+ALTER TABLE users DROP COLUMN role;
@@ -0,0 +1,250 @@
+use crate::AuthError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+// The hash the first record in a log chains from
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// AuditEvent is something that happened that an administrator may need to
+// look back on
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    LoginGranted { username: String },
+    // LoginDenied covers wrong passwords, wrong second factor codes and
+    // usernames that don't exist
+    LoginDenied { username: String },
+    LoginLocked { username: String, until: u64 },
+    UserAdded { username: String, roles: BTreeSet<String> },
+    UserDeleted { username: String },
+    // RolesChanged records the roles a user has after the change
+    RolesChanged { username: String, roles: BTreeSet<String> },
+    PasswordChanged { username: String },
+}
+
+impl AuditEvent {
+    // kind is the name an event is stored and filtered by, such as login_denied
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AuditEvent::LoginGranted { .. } => "login_granted",
+            AuditEvent::LoginDenied { .. } => "login_denied",
+            AuditEvent::LoginLocked { .. } => "login_locked",
+            AuditEvent::UserAdded { .. } => "user_added",
+            AuditEvent::UserDeleted { .. } => "user_deleted",
+            AuditEvent::RolesChanged { .. } => "roles_changed",
+            AuditEvent::PasswordChanged { .. } => "password_changed",
+        }
+    }
+
+    // username is the user the event is about
+    pub fn username(&self) -> &str {
+        match self {
+            AuditEvent::LoginGranted { username }
+            | AuditEvent::LoginDenied { username }
+            | AuditEvent::LoginLocked { username, .. }
+            | AuditEvent::UserAdded { username, .. }
+            | AuditEvent::UserDeleted { username }
+            | AuditEvent::RolesChanged { username, .. }
+            | AuditEvent::PasswordChanged { username } => username,
+        }
+    }
+}
+
+// AuditRecord is one line of the log. Each record holds the hash of the one
+// before it, and its own hash covers everything else in it, so editing,
+// removing or reordering records breaks the chain from that point on.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub seq: u64,
+    pub time: u64,
+    // who made the change, for events that aren't a user logging in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+    pub prev: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hash: String,
+}
+
+impl AuditRecord {
+    // compute_hash is the SHA-256 of the record's JSON without its hash
+    fn compute_hash(&self) -> Result<String, AuthError> {
+        use sha2::Digest;
+        let unhashed = AuditRecord { hash: String::new(), ..self.clone() };
+        Ok(format!("{:x}", sha2::Sha256::digest(serde_json::to_vec(&unhashed)?)))
+    }
+}
+
+// AuditLog appends records to a JSON lines file. Records are only ever
+// appended, under an exclusive lock on the file so processes sharing a log
+// keep one chain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditLog {
+    pub path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>) -> AuditLog {
+        AuditLog { path: path.into() }
+    }
+
+    // from_env returns the log named by AUTH_AUDIT_LOG, if it is set
+    pub fn from_env() -> Option<AuditLog> {
+        std::env::var_os("AUTH_AUDIT_LOG").map(AuditLog::new)
+    }
+
+    // record appends an event to the log, chained to the last record in it
+    pub fn record(&self, actor: Option<&str>, event: AuditEvent, now: u64) -> Result<AuditRecord, AuthError> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(&self.path)?;
+        file.lock()?;
+        let (seq, prev) = match last_line(&mut file)? {
+            Some(line) => {
+                let last: AuditRecord = serde_json::from_str(&line)
+                    .map_err(|_| AuthError::AuditChain("the last record can't be read".to_string()))?;
+                (last.seq + 1, last.hash)
+            }
+            None => (1, GENESIS.to_string()),
+        };
+        let mut record = AuditRecord {
+            seq,
+            time: now,
+            actor: actor.map(str::to_string),
+            event,
+            prev,
+            hash: String::new(),
+        };
+        record.hash = record.compute_hash()?;
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        file.sync_all()?;
+        Ok(record)
+    }
+
+    // verify reads the whole log and checks its chain, returning the records
+    // or the first line that doesn't fit. A log that doesn't exist yet is
+    // empty.
+    pub fn verify(&self) -> Result<Vec<AuditRecord>, AuthError> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut records: Vec<AuditRecord> = Vec::new();
+        for (index, line) in std::io::BufReader::new(file).lines().enumerate() {
+            let broken = |reason: &str| AuthError::AuditChain(format!("line {}: {reason}", index + 1));
+            let record: AuditRecord = serde_json::from_str(&line?).map_err(|_| broken("not a valid record"))?;
+            let (seq, prev) = match records.last() {
+                Some(last) => (last.seq + 1, last.hash.as_str()),
+                None => (1, GENESIS),
+            };
+            if record.seq != seq || record.prev != prev {
+                return Err(broken("records are missing or out of order"));
+            }
+            if record.hash != record.compute_hash()? {
+                return Err(broken("the record has been changed"));
+            }
+            records.push(record);
+        }
+        Ok(records)
+    }
+}
+
+// last_line reads the file backwards from the end until it has the whole last
+// line, so appending doesn't get slower as the log grows
+fn last_line(file: &mut File) -> Result<Option<String>, AuthError> {
+    let mut end = file.seek(SeekFrom::End(0))?;
+    let mut tail: Vec<u8> = Vec::new();
+    while end > 0 {
+        let start = end.saturating_sub(4096);
+        let mut chunk = vec![0; (end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut chunk)?;
+        chunk.extend_from_slice(&tail);
+        tail = chunk;
+        end = start;
+        let trimmed = tail.strip_suffix(b"\n").unwrap_or(&tail);
+        if let Some(newline) = trimmed.iter().rposition(|byte| *byte == b'\n') {
+            return Ok(Some(String::from_utf8_lossy(&trimmed[newline + 1..]).into_owned()));
+        }
+    }
+    let trimmed = tail.strip_suffix(b"\n").unwrap_or(&tail);
+    if trimmed.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(trimmed).into_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{login_with, roles, LoginConfig, MemoryStore, PasswordParams, User, UserStore};
+
+    fn scratch_log(name: &str) -> AuditLog {
+        let path = std::env::temp_dir().join(format!("audit-{}-{name}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        AuditLog::new(path)
+    }
+
+    #[test]
+    fn test_chain() {
+        let log = scratch_log("chain");
+        assert!(log.verify().unwrap().is_empty());
+        let added = AuditEvent::UserAdded {
+            username: "mike".to_string(),
+            roles: BTreeSet::from([roles::USER.to_string()]),
+        };
+        log.record(Some("root"), added.clone(), 10).unwrap();
+        // A long record makes the last line span more than one read
+        let long = "x".repeat(10000);
+        log.record(Some(&long), AuditEvent::PasswordChanged { username: "mike".to_string() }, 20).unwrap();
+        let last = log.record(None, AuditEvent::LoginGranted { username: "mike".to_string() }, 30).unwrap();
+
+        let records = log.verify().unwrap();
+        assert_eq!(3, records.len());
+        assert_eq!(added, records[0].event);
+        assert_eq!(records[0].hash, records[1].prev);
+        assert_eq!(last, records[2]);
+        let text = std::fs::read_to_string(&log.path).unwrap();
+        assert!(text.starts_with(r#"{"seq":1,"time":10,"actor":"root","event":"user_added","username":"mike","roles":["user"],"prev":"000"#));
+
+        // Changing a record breaks the chain at that record
+        std::fs::write(&log.path, text.replacen("mike", "zoe", 1)).unwrap();
+        assert!(matches!(log.verify(), Err(AuthError::AuditChain(reason)) if reason.starts_with("line 1:")));
+        // And so does taking one out
+        let lines: Vec<&str> = text.lines().collect();
+        std::fs::write(&log.path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        assert!(matches!(log.verify(), Err(AuthError::AuditChain(reason)) if reason.starts_with("line 2:")));
+        std::fs::remove_file(&log.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_login_events() {
+        let log = scratch_log("login");
+        let config = LoginConfig {
+            password: PasswordParams { memory_kib: 1024, iterations: 1, parallelism: 1 },
+            audit: Some(log.clone()),
+            ..LoginConfig::default()
+        };
+        let store = MemoryStore::new();
+        store.save_user(&User::new("mike", "password", roles::USER).unwrap()).await.unwrap();
+        login_with(&store, "MIKE", "password", None, &config, 0).await.unwrap();
+        login_with(&store, "mike", "wrong", None, &config, 1).await.unwrap();
+        login_with(&store, "nobody", "password", None, &config, 2).await.unwrap();
+
+        let events: Vec<AuditEvent> = log.verify().unwrap().into_iter().map(|record| record.event).collect();
+        assert_eq!(
+            vec![
+                AuditEvent::LoginGranted { username: "mike".to_string() },
+                AuditEvent::LoginDenied { username: "mike".to_string() },
+                AuditEvent::LoginDenied { username: "nobody".to_string() },
+            ],
+            events
+        );
+        std::fs::remove_file(&log.path).unwrap();
+    }
+}
@@ -1,44 +1,261 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use subtle::ConstantTimeEq;
 
-// hash_password hashes a password using the sha2 crate
-pub fn hash_password(password: &str) -> String {
+pub mod audit;
+mod lockout;
+pub mod policy;
+pub mod roles;
+pub mod session;
+mod store;
+pub mod totp;
+pub use audit::{AuditEvent, AuditLog, AuditRecord};
+pub use lockout::{now, LockoutPolicy};
+pub use policy::{PasswordPolicy, PolicyViolation};
+pub use roles::{has_permission, Permissions, Role};
+pub use session::{Claims, Signer, TokenIssuer, TokenPair, Verifier};
+use roles::LegacyRole;
+pub use totp::Totp;
+pub use store::{AnyStore, JsonStore, MemoryStore, SqliteStore, StoreLock, UserStore};
+
+// AuthError is returned by anything that reads or writes the user store
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("user store I/O failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("user store is not valid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("user database failed: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("user database migration failed: {0}")]
+    Migrate(#[from] sqlx::migrate::MigrateError),
+    #[error("invalid token: {0}")]
+    InvalidToken(String),
+    #[error("token has expired")]
+    TokenExpired,
+    #[error("token has been revoked")]
+    TokenRevoked,
+    #[error("audit log is broken: {0}")]
+    AuditChain(String),
+    #[error("invalid configuration: {0}")]
+    Config(String),
+}
+
+// PasswordParams are the Argon2id cost parameters used for new hashes. They
+// default to the argon2 crate's recommendations and can be raised with the
+// AUTH_ARGON2_MEMORY_KIB, AUTH_ARGON2_ITERATIONS and AUTH_ARGON2_PARALLELISM
+// environment variables.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PasswordParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for PasswordParams {
+    fn default() -> Self {
+        PasswordParams {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl PasswordParams {
+    // new checks the parameters are ones Argon2 accepts, e.g. that there is at
+    // least one iteration and enough memory for the parallelism
+    pub fn new(memory_kib: u32, iterations: u32, parallelism: u32) -> Result<PasswordParams, AuthError> {
+        let params = PasswordParams { memory_kib, iterations, parallelism };
+        params.argon2()?;
+        Ok(params)
+    }
+
+    // from_env reads the parameters from the environment, keeping the default
+    // for any variable that is missing. A value that isn't a number, or that
+    // Argon2 won't accept, is an error.
+    pub fn from_env() -> Result<PasswordParams, AuthError> {
+        let var = |name: &str, default: u32| match std::env::var(name) {
+            Ok(value) => value.parse().map_err(|_| AuthError::Config(format!("{name} is not a number: {value:?}"))),
+            Err(_) => Ok(default),
+        };
+        let defaults = PasswordParams::default();
+        PasswordParams::new(
+            var("AUTH_ARGON2_MEMORY_KIB", defaults.memory_kib)?,
+            var("AUTH_ARGON2_ITERATIONS", defaults.iterations)?,
+            var("AUTH_ARGON2_PARALLELISM", defaults.parallelism)?,
+        )
+    }
+
+    fn argon2(&self) -> Result<Argon2<'static>, AuthError> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(|e| AuthError::Config(format!("invalid Argon2 parameters {self:?}: {e}")))?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+}
+
+// hash_password hashes a password with Argon2id and a random salt, returning
+// a PHC string such as $argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>
+pub fn hash_password(password: &str) -> Result<String, AuthError> {
+    hash_password_with(password, &PasswordParams::from_env()?)
+}
+
+// hash_password_with hashes a password using the given Argon2id parameters
+pub fn hash_password_with(password: &str, params: &PasswordParams) -> Result<String, AuthError> {
+    Ok(hash_with(&params.argon2()?, password))
+}
+
+fn hash_with(argon2: &Argon2, password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    argon2
+        .hash_password(password.as_bytes(), &salt)
+        .expect("failed to hash password")
+        .to_string()
+}
+
+// legacy_hash is the unsalted uppercase hex SHA-256 that passwords used to be
+// stored as. It is only used to check, and then upgrade, old users.json files.
+fn legacy_hash(password: &str) -> String {
     use sha2::Digest;
     let mut hasher = sha2::Sha256::new();
     hasher.update(password);
     format!("{:X}", hasher.finalize()) // hexadecimal representation of a number
 }
 
+// verify_password checks a password against a stored Argon2 PHC string or a
+// legacy SHA-256 hash. Both comparisons take constant time.
+pub fn verify_password(password: &str, stored: &str) -> bool {
+    if stored.starts_with("$argon2") {
+        match PasswordHash::new(stored) {
+            Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+            Err(_) => false,
+        }
+    } else {
+        legacy_hash(password).as_bytes().ct_eq(stored.as_bytes()).into()
+    }
+}
+
+// dummy_hash returns a fixed Argon2id hash made with the given parameters.
+// Logins for usernames that don't exist check the password against it, so
+// they take as long as logins for users that do and timing doesn't give away
+// which usernames exist. Each set of parameters is only hashed once.
+fn dummy_hash(params: &PasswordParams) -> Result<String, AuthError> {
+    static HASHES: std::sync::Mutex<Vec<(PasswordParams, String)>> = std::sync::Mutex::new(Vec::new());
+    let mut hashes = HASHES.lock().unwrap();
+    if let Some((_, hash)) = hashes.iter().find(|(hashed, _)| hashed == params) {
+        return Ok(hash.clone());
+    }
+    let hash = hash_password_with("not a real password", params)?;
+    hashes.push((*params, hash.clone()));
+    Ok(hash)
+}
+
+// needs_rehash returns true if a stored hash isn't Argon2id with the given
+// parameters, which includes all legacy SHA-256 hashes
+pub fn needs_rehash(stored: &str, params: &PasswordParams) -> bool {
+    let Ok(hash) = PasswordHash::new(stored) else {
+        return true;
+    };
+    if hash.algorithm != Algorithm::Argon2id.ident() {
+        return true;
+    }
+    match Params::try_from(&hash) {
+        Ok(current) => {
+            current.m_cost() != params.memory_kib
+                || current.t_cost() != params.iterations
+                || current.p_cost() != params.parallelism
+        }
+        Err(_) => true,
+    }
+}
+
 pub fn greet_user(name: &str) -> String{
   format!("Hello, {}!", name)
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum LoginAction {
-    Granted(LoginRole),
+    Granted(Permissions),
     Denied,
+    // Locked means too many wrong passwords were tried, and no password will
+    // be accepted until `until`, in seconds since the Unix epoch
+    Locked { until: u64 },
+    // NeedsSecondFactor means the password was right but the user has two
+    // factor authentication, so login_second_factor has to be called with a
+    // code from their authenticator app or one of their recovery codes
+    NeedsSecondFactor,
 }
 
-#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
-pub enum LoginRole {
-    Admin,
-    User,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "StoredUser")]
 pub struct User {
     pub username: String,
     pub password: String,
-    pub role: LoginRole,
+    // names of the roles the user's permissions come from
+    pub roles: BTreeSet<String>,
+    // wrong passwords tried since the last successful login
+    pub failed_logins: u32,
+    pub locked_until: Option<u64>,
+    // base32 TOTP secret, for users with two factor authentication
+    pub totp_secret: Option<String>,
+    // the last TOTP step a code was accepted for, so codes can't be replayed
+    pub totp_last_step: Option<u64>,
+    // hashes of the unused recovery codes
+    pub recovery_codes: Vec<String>,
 }
 
 impl User {
-    pub fn new(username: &str, password: &str, role: LoginRole) -> User {
-        User {
+    pub fn new(username: &str, password: &str, role: &str) -> Result<User, AuthError> {
+        Ok(User {
             username: username.to_lowercase(),
-            password: hash_password(password),
-            role,
+            password: hash_password(password)?,
+            roles: BTreeSet::from([role.to_lowercase()]),
+            failed_logins: 0,
+            locked_until: None,
+            totp_secret: None,
+            totp_last_step: None,
+            recovery_codes: Vec::new(),
+        })
+    }
+}
+
+// StoredUser is a user as read from JSON, which may still have the single
+// Admin or User role older versions wrote
+#[derive(Deserialize)]
+struct StoredUser {
+    username: String,
+    password: String,
+    #[serde(default)]
+    roles: BTreeSet<String>,
+    #[serde(default)]
+    role: Option<LegacyRole>,
+    #[serde(default)]
+    failed_logins: u32,
+    #[serde(default)]
+    locked_until: Option<u64>,
+    #[serde(default)]
+    totp_secret: Option<String>,
+    #[serde(default)]
+    totp_last_step: Option<u64>,
+    #[serde(default)]
+    recovery_codes: Vec<String>,
+}
+
+impl From<StoredUser> for User {
+    fn from(stored: StoredUser) -> User {
+        let mut roles = stored.roles;
+        roles.extend(stored.role.map(|role| role.name().to_string()));
+        User {
+            username: stored.username,
+            password: stored.password,
+            roles,
+            failed_logins: stored.failed_logins,
+            locked_until: stored.locked_until,
+            totp_secret: stored.totp_secret,
+            totp_last_step: stored.totp_last_step,
+            recovery_codes: stored.recovery_codes,
         }
     }
 }
@@ -46,7 +263,7 @@ impl User {
 /*
 fn get_admin_users() {
     let _admin: Vec<String> = get_users().into_iter()
-        .filter(|user| user.role == LoginRole::Admin)
+        .filter(|user| user.roles.contains(roles::ADMIN))
         .map(|user| user.username)
         .collect();
 }
@@ -56,90 +273,158 @@ fn get_admin_users() {
  * get_users using vectors
 pub fn get_users() -> Vec<User> {
     vec![
-        User::new("admin", "password", LoginRole::Admin),
-        User::new("mike", "password", LoginRole::User),
+        User::new("admin", "password", roles::ADMIN),
+        User::new("mike", "password", roles::USER),
     ]
 }
 */
 
 // get_default_users returns a HashMap of default users with the username as the key
 // and the User struct as the value
-pub fn get_default_users() -> HashMap<String, User> {
+pub fn get_default_users() -> Result<HashMap<String, User>, AuthError> {
     let mut users: HashMap<String, User> = HashMap::new();
-    users.insert("admin".to_string(), User::new("admin", "password", LoginRole::Admin));
-    users.insert("mike".to_string(), User::new("mike", "password", LoginRole::User));
-    users
-}
-
-//save_users saves the users to the users.json file using serde_json
-pub fn save_users(users: HashMap<String, User>){
-    let users_path: &Path = Path::new("users.json");
-    let users_json = serde_json::to_string(&users).unwrap();
-    std::fs::write(users_path, users_json).unwrap();
-}
-
-//get_users reads the users.json file and returns a Vec<User> if the file exists
-//otherwise it creates the file and returns a Vec<User>
-pub fn get_users() -> HashMap<String, User> {
-    let users_path: &Path = Path::new("users.json");
-    if users_path.exists() {
-        //Load file
-        let users_json = std::fs::read_to_string(users_path).unwrap();
-        let users: HashMap<String, User> = serde_json::from_str(&users_json).unwrap();
-        users
-    } else {
-        //Create file and returns it
-        let users: HashMap<String, User> = get_default_users();
-        let users_json = serde_json::to_string(&users).unwrap();
-        std::fs::write(users_path, users_json).unwrap();
-        users
+    users.insert("admin".to_string(), User::new("admin", "password", roles::ADMIN)?);
+    users.insert("mike".to_string(), User::new("mike", "password", roles::USER)?);
+    Ok(users)
+}
+
+// LoginConfig is everything that tunes how login checks passwords, and where
+// the outcome of each attempt is logged to
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LoginConfig {
+    pub password: PasswordParams,
+    pub lockout: LockoutPolicy,
+    pub audit: Option<AuditLog>,
+}
+
+impl LoginConfig {
+    pub fn from_env() -> Result<LoginConfig, AuthError> {
+        Ok(LoginConfig {
+            password: PasswordParams::from_env()?,
+            lockout: LockoutPolicy::from_env(),
+            audit: AuditLog::from_env(),
+        })
+    }
+
+    // audit logs an event if there is an audit log
+    fn audit(&self, event: AuditEvent, now: u64) -> Result<(), AuthError> {
+        if let Some(log) = &self.audit {
+            log.record(None, event, now)?;
+        }
+        Ok(())
     }
 }
 
 // login checks if the username and password are correct and returns a LoginAction
-// if the username and password are correct, it returns LoginAction::Granted(role)
+// if the username and password are correct, it returns LoginAction::Granted(permissions)
 // if the username and password are incorrect, it returns LoginAction::Denied
+// if the user has tried too many wrong passwords, it returns LoginAction::Locked
+// if the user has two factor authentication, it returns LoginAction::NeedsSecondFactor
 // if the username is not found, it returns None
-pub fn login(username: &str, password: &str) -> Option<LoginAction> {
+pub async fn login(store: &(impl UserStore + Sync), username: &str, password: &str) -> Result<Option<LoginAction>, AuthError> {
+    login_with(store, username, password, None, &LoginConfig::from_env()?, lockout::now()).await
+}
 
-    let username: String = username.to_lowercase();
-    let password: String = hash_password(password); //shadowing password
+// login_second_factor completes a login that returned NeedsSecondFactor. The
+// password is checked again along with the code, which can be a TOTP code or
+// a recovery code.
+pub async fn login_second_factor(
+    store: &(impl UserStore + Sync),
+    username: &str,
+    password: &str,
+    code: &str,
+) -> Result<Option<LoginAction>, AuthError> {
+    login_with(store, username, password, Some(code), &LoginConfig::from_env()?, lockout::now()).await
+}
 
-    let users: HashMap<String, User> = get_users();
-    if let Some(user) = users.get(&username) {
-        if user.password == password {
-            return Some(LoginAction::Granted(user.role.clone()));
-        } else {
-            return Some(LoginAction::Denied);
+// login_with is login with an optional second factor code and explicit
+// settings and time. Failed attempts are counted in the store so they survive
+// restarts, and a user whose hash is legacy SHA-256 or uses outdated
+// parameters gets rehashed on a successful login.
+pub async fn login_with(
+    store: &(impl UserStore + Sync),
+    username: &str,
+    password: &str,
+    code: Option<&str>,
+    config: &LoginConfig,
+    now: u64,
+) -> Result<Option<LoginAction>, AuthError> {
+    let username = username.to_lowercase();
+    let argon2 = config.password.argon2()?;
+    // The attempt is checked and recorded as one update, so concurrent
+    // attempts can't lose each other's failures. It gives back the user if
+    // they are let in.
+    let attempt = store
+        .update_user(&username, |user| {
+            // While locked the password isn't even checked, so guesses tell nothing
+            if let Some(until) = user.locked_until.filter(|until| *until > now) {
+                return Err(LoginAction::Locked { until });
+            }
+            let verified = verify_password(password, &user.password)
+                && match (&user.totp_secret, code) {
+                    (None, _) => true,
+                    (Some(_), None) => return Err(LoginAction::NeedsSecondFactor),
+                    (Some(_), Some(code)) => check_second_factor(user, code, now),
+                };
+            if !verified {
+                config.lockout.record_failure(user, now);
+                return Err(match user.locked_until {
+                    Some(until) if until > now => LoginAction::Locked { until },
+                    _ => LoginAction::Denied,
+                });
+            }
+            user.failed_logins = 0;
+            user.locked_until = None;
+            if needs_rehash(&user.password, &config.password) {
+                user.password = hash_with(&argon2, password);
+            }
+            Ok(user.clone())
+        })
+        .await?;
+    let action = match attempt {
+        None => {
+            verify_password(password, &dummy_hash(&config.password)?);
+            config.audit(AuditEvent::LoginDenied { username }, now)?;
+            return Ok(None);
         }
-    }
-
-
-    /*
-    /*
-    let users: Vec<User> = get_users(); 
-    Searching for the User
-    users.iter():
-        Creates an iterator over the users array, where each item is a reference to a User (&User).
-    .find(|user: &&User| user.username == username):
-        The find method looks for the first User in the array where user.username == username.
-        |user: &&User|: A closure that takes a reference to a reference (&&User) because the iterator produces &User.
-    if let Some(user):
-        If a matching user is found, the Some(user) branch is executed.
-        The user variable is assigned the matching User.
-    */
-    if let Some(user) = users.iter().find(|user: &&User| user.username == username) {
-        if user.password == password {
-            return Some(LoginAction::Granted(user.role.clone()));
-        } else {
-            return Some(LoginAction::Denied);
+        Some(Ok(user)) => {
+            config.audit(AuditEvent::LoginGranted { username }, now)?;
+            LoginAction::Granted(Permissions::resolve(store, &user).await?)
         }
-    }
-    */
-    None
+        Some(Err(action)) => {
+            match action {
+                LoginAction::Locked { until } => config.audit(AuditEvent::LoginLocked { username, until }, now)?,
+                LoginAction::Denied => config.audit(AuditEvent::LoginDenied { username }, now)?,
+                _ => {}
+            }
+            action
+        }
+    };
+    Ok(Some(action))
 }
 
+// check_second_factor accepts a TOTP code that hasn't been used yet, or uses
+// up one of the user's recovery codes
+fn check_second_factor(user: &mut User, code: &str, now: u64) -> bool {
+    let secret = user.totp_secret.as_deref().and_then(Totp::from_base32);
+    if let Some(step) = secret.and_then(|totp| totp.verify(code, now, user.totp_last_step)) {
+        user.totp_last_step = Some(step);
+        return true;
+    }
+    totp::use_recovery_code(&mut user.recovery_codes, code)
+}
 
+// unlock clears a user's failed logins and lockout, returning false if there
+// is no such user
+pub async fn unlock(store: &(impl UserStore + Sync), username: &str) -> Result<bool, AuthError> {
+    let unlocked = store
+        .update_user(&username.to_lowercase(), |user| {
+            user.failed_logins = 0;
+            user.locked_until = None;
+        })
+        .await?;
+    Ok(unlocked.is_some())
+}
 
 // read_line reads a line from the standard input and returns an input
 pub fn read_line () -> String {
@@ -150,21 +435,188 @@ pub fn read_line () -> String {
     input.trim().to_string()
 }
 
+// read_password prompts for a password without showing what is typed. When
+// standard input isn't a terminal, such as when a script pipes the password
+// in, a line is read from it instead. Spaces are part of the password, so
+// only the line ending is removed.
+pub fn read_password(prompt: &str) -> std::io::Result<String> {
+    use std::io::IsTerminal;
+    if std::io::stdin().is_terminal() {
+        return rpassword::prompt_password(prompt);
+    }
+    println!("{prompt}");
+    read_password_line(&mut std::io::stdin().lock())
+}
+
+// read_password_line reads a password given on a line of its own, as with
+// login_manager's --password-stdin
+pub fn read_password_line(input: &mut impl std::io::BufRead) -> std::io::Result<String> {
+    let mut password = String::new();
+    input.read_line(&mut password)?;
+    let trimmed = password.trim_end_matches(['\n', '\r']).len();
+    password.truncate(trimmed);
+    Ok(password)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use roles::default_roles;
 
     #[test]
     fn test_greet_user() {
         assert_eq!("Hello, Michael!", greet_user("Michael"));
     }
 
+    #[tokio::test]
+    async fn test_login() {
+        let store = MemoryStore::with_users(get_default_users().unwrap());
+        let login = |username, password| login(&store, username, password);
+        let granted = |role: &str| {
+            let role = &default_roles()[role];
+            Some(LoginAction::Granted(Permissions {
+                roles: BTreeSet::from([role.name.clone()]),
+                permissions: role.permissions.clone(),
+            }))
+        };
+        assert_eq!(granted(roles::ADMIN), login("ADMIN", "password").await.unwrap());
+        assert_eq!(granted(roles::ADMIN), login("admin", "password").await.unwrap());
+        assert_eq!(granted(roles::USER), login("mike", "password").await.unwrap());
+        assert_eq!(Some(LoginAction::Denied), login("admin", "wrong_password").await.unwrap());
+        assert_eq!(None, login("wrong_username", "password").await.unwrap());
+    }
+
+    // Cheap parameters keep the tests fast
+    const TEST_PARAMS: PasswordParams = PasswordParams { memory_kib: 1024, iterations: 1, parallelism: 1 };
+    const TEST_CONFIG: LoginConfig = LoginConfig {
+        password: TEST_PARAMS,
+        lockout: LockoutPolicy { threshold: 3, cooldown: 60, max_cooldown: 3600 },
+        audit: None,
+    };
+
     #[test]
-    fn test_login() {
-        assert_eq!(Some(LoginAction::Granted(LoginRole::Admin)), login("ADMIN", "password"));
-        assert_eq!(Some(LoginAction::Granted(LoginRole::Admin)), login("admin", "password"));
-        assert_eq!(Some(LoginAction::Granted(LoginRole::User)), login("mike", "password"));
-        assert_eq!(Some(LoginAction::Denied), login("admin", "wrong_password"));
-        assert_eq!(None, login("wrong_username", "password"));
+    fn test_read_password_line() {
+        let mut input = std::io::Cursor::new(" two  spaces \r\nnext\n");
+        assert_eq!(" two  spaces ", read_password_line(&mut input).unwrap());
+        assert_eq!("next", read_password_line(&mut input).unwrap());
+        assert_eq!("", read_password_line(&mut input).unwrap());
+    }
+
+    #[test]
+    fn test_hash_password() {
+        let first = hash_password_with("password", &TEST_PARAMS).unwrap();
+        let second = hash_password_with("password", &TEST_PARAMS).unwrap();
+        assert!(first.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+        // Every hash has its own salt
+        assert_ne!(first, second);
+        assert!(verify_password("password", &first));
+        assert!(verify_password("password", &second));
+        assert!(!verify_password("wrong_password", &first));
+        assert!(!verify_password("password", "$argon2id$garbage"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_password_params() {
+        assert_eq!(PasswordParams::new(1024, 1, 1).unwrap(), TEST_PARAMS);
+        assert!(matches!(PasswordParams::new(1024, 0, 1), Err(AuthError::Config(_))));
+        assert!(matches!(PasswordParams::new(1, 1, 1), Err(AuthError::Config(_))));
+        // Parameters built by hand are checked when they are used, rather than panicking
+        let no_iterations = PasswordParams { iterations: 0, ..TEST_PARAMS };
+        assert!(hash_password_with("password", &no_iterations).is_err());
+        let config = LoginConfig { password: no_iterations, ..TEST_CONFIG };
+        let store = MemoryStore::new();
+        assert!(login_with(&store, "mike", "password", None, &config, 0).await.is_err());
+    }
+
+    #[test]
+    fn test_dummy_hash() {
+        let hash = dummy_hash(&TEST_PARAMS).unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+        assert_eq!(hash, dummy_hash(&TEST_PARAMS).unwrap());
+        // Other parameters get their own hash, so it costs what real ones do
+        let slower = PasswordParams { iterations: 2, ..TEST_PARAMS };
+        assert!(!needs_rehash(&dummy_hash(&slower).unwrap(), &slower));
+    }
+
+    #[test]
+    fn test_needs_rehash() {
+        let hash = hash_password_with("password", &TEST_PARAMS).unwrap();
+        assert!(!needs_rehash(&hash, &TEST_PARAMS));
+        assert!(needs_rehash(&hash, &PasswordParams { iterations: 2, ..TEST_PARAMS }));
+        assert!(needs_rehash(&legacy_hash("password"), &TEST_PARAMS));
+    }
+
+    #[tokio::test]
+    async fn test_legacy_upgrade() {
+        let legacy = legacy_hash("password");
+        let store = MemoryStore::new();
+        let user = User { password: legacy.clone(), ..User::new("mike", "", roles::USER).unwrap() };
+        store.save_user(&user).await.unwrap();
+        let stored = || async { store.get_user("mike").await.unwrap().unwrap().password };
+
+        // A wrong password leaves the legacy hash alone
+        let denied = login_with(&store, "mike", "wrong", None, &TEST_CONFIG, 0).await.unwrap();
+        assert_eq!(Some(LoginAction::Denied), denied);
+        assert_eq!(legacy, stored().await);
+
+        let granted = Some(LoginAction::Granted(Permissions::resolve(&store, &user).await.unwrap()));
+        assert_eq!(granted, login_with(&store, "MIKE", "password", None, &TEST_CONFIG, 0).await.unwrap());
+        let upgraded = stored().await;
+        assert!(upgraded.starts_with("$argon2id$"));
+        // Once upgraded the hash is kept as it is
+        assert_eq!(granted, login_with(&store, "mike", "password", None, &TEST_CONFIG, 0).await.unwrap());
+        assert_eq!(upgraded, stored().await);
+    }
+
+    #[tokio::test]
+    async fn test_lockout() {
+        let store = MemoryStore::new();
+        store.save_user(&User::new("mike", "password", roles::USER).unwrap()).await.unwrap();
+        let login = |password, now| login_with(&store, "mike", password, None, &TEST_CONFIG, now);
+
+        // A success in between resets the count
+        assert_eq!(Some(LoginAction::Denied), login("wrong", 0).await.unwrap());
+        assert!(matches!(login("password", 1).await.unwrap(), Some(LoginAction::Granted(_))));
+        assert_eq!(Some(LoginAction::Denied), login("wrong", 2).await.unwrap());
+        assert_eq!(Some(LoginAction::Denied), login("wrong", 3).await.unwrap());
+        assert_eq!(Some(LoginAction::Locked { until: 64 }), login("wrong", 4).await.unwrap());
+        // Even the right password is refused until the cooldown is over
+        assert_eq!(Some(LoginAction::Locked { until: 64 }), login("password", 10).await.unwrap());
+        assert_eq!(3, store.get_user("mike").await.unwrap().unwrap().failed_logins);
+
+        // Another wrong guess after the cooldown locks the account for twice as long
+        assert_eq!(Some(LoginAction::Locked { until: 184 }), login("wrong", 64).await.unwrap());
+        assert!(unlock(&store, "MIKE").await.unwrap());
+        assert!(matches!(login("password", 65).await.unwrap(), Some(LoginAction::Granted(_))));
+        assert!(!unlock(&store, "nobody").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_second_factor() {
+        let store = MemoryStore::new();
+        let totp = Totp::new(b"12345678901234567890".to_vec());
+        let (codes, hashes) = totp::generate_recovery_codes();
+        let user = User {
+            totp_secret: Some(totp.secret_base32()),
+            recovery_codes: hashes,
+            ..User::new("admin", "password", roles::ADMIN).unwrap()
+        };
+        store.save_user(&user).await.unwrap();
+        let login = |password, code, now| login_with(&store, "admin", password, code, &TEST_CONFIG, now);
+        let granted = Some(LoginAction::Granted(Permissions::resolve(&store, &user).await.unwrap()));
+
+        // The password alone only gets as far as asking for a code
+        assert_eq!(Some(LoginAction::NeedsSecondFactor), login("password", None, 59).await.unwrap());
+        assert_eq!(Some(LoginAction::Denied), login("wrong", None, 59).await.unwrap());
+        assert_eq!(Some(LoginAction::Denied), login("password", Some("000000"), 59).await.unwrap());
+
+        assert_eq!(granted, login("password", Some("287082"), 59).await.unwrap());
+        // The same code can't be replayed
+        assert_eq!(Some(LoginAction::Denied), login("password", Some("287082"), 59).await.unwrap());
+
+        // A recovery code works once in place of a TOTP code
+        assert_eq!(granted, login("password", Some(&codes[0]), 200).await.unwrap());
+        assert_eq!(Some(LoginAction::Denied), login("password", Some(&codes[0]), 200).await.unwrap());
+        assert_eq!(9, store.get_user("admin").await.unwrap().unwrap().recovery_codes.len());
     }
 }
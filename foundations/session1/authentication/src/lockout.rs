@@ -0,0 +1,84 @@
+use crate::User;
+
+// LockoutPolicy decides when repeated wrong passwords lock an account. Once a
+// user reaches `threshold` failures in a row they are locked for `cooldown`
+// seconds, and every failure after that doubles the cooldown up to
+// `max_cooldown`, so guessing is slowed to a crawl. A successful login resets
+// the count. The AUTH_LOCKOUT_THRESHOLD, AUTH_LOCKOUT_SECONDS and
+// AUTH_LOCKOUT_MAX_SECONDS environment variables override the defaults, and a
+// threshold of 0 turns lockout off.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LockoutPolicy {
+    pub threshold: u32,
+    pub cooldown: u64,
+    pub max_cooldown: u64,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        LockoutPolicy {
+            threshold: 5,
+            cooldown: 60,
+            max_cooldown: 86400,
+        }
+    }
+}
+
+impl LockoutPolicy {
+    pub fn from_env() -> LockoutPolicy {
+        fn var<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+        }
+        let defaults = LockoutPolicy::default();
+        LockoutPolicy {
+            threshold: var("AUTH_LOCKOUT_THRESHOLD", defaults.threshold),
+            cooldown: var("AUTH_LOCKOUT_SECONDS", defaults.cooldown),
+            max_cooldown: var("AUTH_LOCKOUT_MAX_SECONDS", defaults.max_cooldown),
+        }
+    }
+
+    // cooldown_after returns how long an account is locked for after a number
+    // of failures in a row, if it is locked at all
+    pub fn cooldown_after(&self, failures: u32) -> Option<u64> {
+        if self.threshold == 0 || failures < self.threshold {
+            return None;
+        }
+        let doublings = (failures - self.threshold).min(32);
+        Some(self.cooldown.saturating_mul(1 << doublings).min(self.max_cooldown))
+    }
+
+    // record_failure counts a wrong password against the user, locking them if
+    // that takes them over the threshold
+    pub fn record_failure(&self, user: &mut User, now: u64) {
+        user.failed_logins = user.failed_logins.saturating_add(1);
+        if let Some(cooldown) = self.cooldown_after(user.failed_logins) {
+            user.locked_until = Some(now + cooldown);
+        }
+    }
+}
+
+// now returns the current time in seconds since the Unix epoch
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cooldown_after() {
+        let policy = LockoutPolicy { threshold: 3, cooldown: 60, max_cooldown: 300 };
+        assert_eq!(None, policy.cooldown_after(2));
+        assert_eq!(Some(60), policy.cooldown_after(3));
+        assert_eq!(Some(120), policy.cooldown_after(4));
+        assert_eq!(Some(240), policy.cooldown_after(5));
+        assert_eq!(Some(300), policy.cooldown_after(6));
+        assert_eq!(Some(300), policy.cooldown_after(u32::MAX));
+        let disabled = LockoutPolicy { threshold: 0, ..policy };
+        assert_eq!(None, disabled.cooldown_after(100));
+    }
+}
@@ -0,0 +1,161 @@
+use crate::AuthError;
+use std::io::BufRead;
+use std::path::PathBuf;
+
+// PasswordPolicy decides which new passwords are accepted. A password needs
+// `min_length` characters from at least `min_classes` of lowercase letters,
+// uppercase letters, digits and symbols, can't contain the username, and can't
+// be in the breached password list if there is one. The AUTH_PASSWORD_MIN_LENGTH,
+// AUTH_PASSWORD_MIN_CLASSES and AUTH_BREACHED_PASSWORDS environment variables
+// override the defaults.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub min_classes: usize,
+    // a file of uppercase hex SHA-1 prefixes of breached passwords, one per
+    // line, so the check works offline
+    pub breached_list: Option<PathBuf>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 12,
+            min_classes: 2,
+            breached_list: None,
+        }
+    }
+}
+
+// PolicyViolation is a reason a password was refused
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum PolicyViolation {
+    #[error("it must be at least {0} characters long")]
+    TooShort(usize),
+    #[error("it must mix at least {0} of lowercase letters, uppercase letters, digits and symbols")]
+    TooFewClasses(usize),
+    #[error("it must not contain the username")]
+    ContainsUsername,
+    #[error("it has appeared in a data breach, so attackers will try it")]
+    Breached,
+}
+
+impl PasswordPolicy {
+    pub fn from_env() -> PasswordPolicy {
+        fn var<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+        }
+        let defaults = PasswordPolicy::default();
+        PasswordPolicy {
+            min_length: var("AUTH_PASSWORD_MIN_LENGTH", defaults.min_length),
+            min_classes: var("AUTH_PASSWORD_MIN_CLASSES", defaults.min_classes),
+            breached_list: std::env::var_os("AUTH_BREACHED_PASSWORDS").map(PathBuf::from),
+        }
+    }
+
+    // check returns everything wrong with a password, which is empty if it is
+    // acceptable. It only fails if the breached password list can't be read.
+    pub fn check(&self, username: &str, password: &str) -> Result<Vec<PolicyViolation>, AuthError> {
+        let mut violations = Vec::new();
+        if password.chars().count() < self.min_length {
+            violations.push(PolicyViolation::TooShort(self.min_length));
+        }
+        if character_classes(password) < self.min_classes {
+            violations.push(PolicyViolation::TooFewClasses(self.min_classes));
+        }
+        let username = username.trim().to_lowercase();
+        if !username.is_empty() && password.to_lowercase().contains(&username) {
+            violations.push(PolicyViolation::ContainsUsername);
+        }
+        if let Some(path) = &self.breached_list {
+            if is_breached(path, password)? {
+                violations.push(PolicyViolation::Breached);
+            }
+        }
+        Ok(violations)
+    }
+}
+
+// character_classes counts which of lowercase letters, uppercase letters,
+// digits and anything else a password uses
+fn character_classes(password: &str) -> usize {
+    let classes = [
+        password.chars().any(char::is_lowercase),
+        password.chars().any(char::is_uppercase),
+        password.chars().any(char::is_numeric),
+        password.chars().any(|c| !c.is_lowercase() && !c.is_uppercase() && !c.is_numeric()),
+    ];
+    classes.iter().filter(|used| **used).count()
+}
+
+// breached_prefix returns the line to put in a breached password list for a
+// password: the first `length` hex digits of its SHA-1
+pub fn breached_prefix(password: &str, length: usize) -> String {
+    let mut hash = sha1_hex(password);
+    hash.truncate(length);
+    hash
+}
+
+fn sha1_hex(password: &str) -> String {
+    use sha1::Digest;
+    format!("{:X}", sha1::Sha1::digest(password.as_bytes()))
+}
+
+// is_breached looks for a prefix of the password's SHA-1 in the list. Lines
+// may have a `:count` after the prefix as in downloaded lists, and blank lines
+// and lines starting with # are skipped. The file is read a line at a time so
+// large lists don't have to fit in memory.
+fn is_breached(path: &std::path::Path, password: &str) -> Result<bool, AuthError> {
+    let hash = sha1_hex(password);
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    for line in file.lines() {
+        let line = line?;
+        let prefix = line.split(':').next().unwrap_or_default().trim();
+        if prefix.is_empty() || prefix.starts_with('#') {
+            continue;
+        }
+        if hash.starts_with(&prefix.to_uppercase()) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let policy = PasswordPolicy::default();
+        assert_eq!(Vec::<PolicyViolation>::new(), policy.check("mike", "correct horse battery").unwrap());
+        assert_eq!(
+            vec![PolicyViolation::TooShort(12), PolicyViolation::TooFewClasses(2)],
+            policy.check("mike", "").unwrap()
+        );
+        assert_eq!(vec![PolicyViolation::TooFewClasses(2)], policy.check("mike", "abcdefghijklmn").unwrap());
+        assert_eq!(vec![PolicyViolation::ContainsUsername], policy.check("mike", "I am MIKE, 2024").unwrap());
+        // Length is counted in characters, not bytes
+        assert_eq!(vec![PolicyViolation::TooShort(12)], policy.check("mike", "ééééé11").unwrap());
+    }
+
+    #[test]
+    fn test_breached() {
+        let path = std::env::temp_dir().join(format!("breached-passwords-{}", std::process::id()));
+        let list = format!(
+            "# breached passwords\n\n{}:3861493\n{}\n",
+            breached_prefix("Password1234", 10),
+            breached_prefix("correct horse battery", 6).to_lowercase()
+        );
+        std::fs::write(&path, list).unwrap();
+        let policy = PasswordPolicy {
+            breached_list: Some(path.clone()),
+            ..PasswordPolicy::default()
+        };
+        assert_eq!(vec![PolicyViolation::Breached], policy.check("mike", "Password1234").unwrap());
+        assert_eq!(vec![PolicyViolation::Breached], policy.check("mike", "correct horse battery").unwrap());
+        assert!(policy.check("mike", "Tr0ub4dor&3xyz").unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+        assert!(policy.check("mike", "Tr0ub4dor&3xyz").is_err());
+    }
+}
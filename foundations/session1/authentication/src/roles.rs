@@ -0,0 +1,149 @@
+use crate::{AuthError, User, UserStore};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+// The permissions the tools in this repository check for. Roles can hold any
+// other dotted name too.
+pub const USERS_READ: &str = "users.read";
+pub const USERS_WRITE: &str = "users.write";
+pub const METRICS_READ: &str = "metrics.read";
+
+// The roles every store starts out with, which the old Admin and User roles
+// turn into
+pub const ADMIN: &str = "admin";
+pub const USER: &str = "user";
+
+// Role is a named set of permissions. A permission can end in `*` to grant
+// everything under it, so `users.*` covers `users.read` and `users.write`, and
+// a bare `*` covers every permission.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Role {
+    pub name: String,
+    pub permissions: BTreeSet<String>,
+}
+
+impl Role {
+    pub fn new(name: &str, permissions: &[&str]) -> Role {
+        Role {
+            name: name.to_lowercase(),
+            permissions: permissions.iter().map(|permission| permission.to_string()).collect(),
+        }
+    }
+}
+
+// default_roles returns the built in roles: admin can do anything, and user
+// can read metrics
+pub fn default_roles() -> HashMap<String, Role> {
+    [Role::new(ADMIN, &["*"]), Role::new(USER, &[METRICS_READ])]
+        .into_iter()
+        .map(|role| (role.name.clone(), role))
+        .collect()
+}
+
+// valid_name accepts role names and the parts of permissions: lowercase
+// letters, digits, underscores and dashes
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+// valid_permission accepts dotted names such as users.read, optionally ending
+// in a `*` wildcard
+pub fn valid_permission(permission: &str) -> bool {
+    let mut parts: Vec<&str> = permission.split('.').collect();
+    if parts.last() == Some(&"*") {
+        parts.pop();
+    }
+    parts.iter().all(|part| valid_name(part))
+}
+
+// Permissions are what a user is allowed to do, resolved from their roles
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Permissions {
+    pub roles: BTreeSet<String>,
+    pub permissions: BTreeSet<String>,
+}
+
+impl Permissions {
+    // resolve looks up the user's roles in the store. Roles that no longer
+    // exist grant nothing.
+    pub async fn resolve(store: &impl UserStore, user: &User) -> Result<Permissions, AuthError> {
+        let mut permissions = BTreeSet::new();
+        for name in &user.roles {
+            if let Some(role) = store.get_role(name).await? {
+                permissions.extend(role.permissions);
+            }
+        }
+        Ok(Permissions {
+            roles: user.roles.clone(),
+            permissions,
+        })
+    }
+
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.permissions.iter().any(|granted| match granted.strip_suffix('*') {
+            Some(prefix) => permission.starts_with(prefix),
+            None => granted == permission,
+        })
+    }
+}
+
+// has_permission checks whether a user may do something, returning false for
+// users that don't exist
+pub async fn has_permission(store: &impl UserStore, username: &str, permission: &str) -> Result<bool, AuthError> {
+    match store.get_user(&username.to_lowercase()).await? {
+        Some(user) => Ok(Permissions::resolve(store, &user).await?.has_permission(permission)),
+        None => Ok(false),
+    }
+}
+
+// LegacyRole is how users.json files stored a user's role before there were
+// named roles. Admin and User become the admin and user roles.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub(crate) enum LegacyRole {
+    Admin,
+    User,
+}
+
+impl LegacyRole {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            LegacyRole::Admin => ADMIN,
+            LegacyRole::User => USER,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_has_permission() {
+        let permissions = Permissions {
+            roles: BTreeSet::new(),
+            permissions: [METRICS_READ, "users.*"].iter().map(|p| p.to_string()).collect(),
+        };
+        assert!(permissions.has_permission(METRICS_READ));
+        assert!(permissions.has_permission(USERS_READ));
+        assert!(permissions.has_permission(USERS_WRITE));
+        assert!(!permissions.has_permission("metrics.write"));
+        assert!(!Permissions::default().has_permission(METRICS_READ));
+
+        let admin = Permissions {
+            roles: BTreeSet::new(),
+            permissions: default_roles()[ADMIN].permissions.clone(),
+        };
+        assert!(admin.has_permission("anything.at.all"));
+    }
+
+    #[test]
+    fn test_valid_permission() {
+        assert!(valid_permission("users.read"));
+        assert!(valid_permission("users.*"));
+        assert!(valid_permission("*"));
+        assert!(!valid_permission("users..read"));
+        assert!(!valid_permission("users.re*"));
+        assert!(!valid_permission("Users.read"));
+        assert!(!valid_permission(""));
+    }
+}
@@ -0,0 +1,434 @@
+use crate::{AuthError, Permissions, UserStore};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use data_encoding::{BASE64URL_NOPAD, HEXUPPER};
+use ed25519_dalek::{Signer as _, Verifier as _};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use subtle::ConstantTimeEq;
+
+// Claims are what an access token says about its holder
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub iat: u64,
+    pub exp: u64,
+    // unique id of the token, which is what gets revoked
+    pub jti: String,
+    pub roles: BTreeSet<String>,
+    pub permissions: BTreeSet<String>,
+}
+
+impl Claims {
+    pub fn has_permission(&self, permission: &str) -> bool {
+        Permissions {
+            roles: self.roles.clone(),
+            permissions: self.permissions.clone(),
+        }
+        .has_permission(permission)
+    }
+}
+
+// Signer holds the key tokens are signed with: a shared secret for HS256, or
+// an Ed25519 private key so services only need the public key to verify
+#[derive(Clone)]
+pub enum Signer {
+    Hs256(Vec<u8>),
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+impl std::fmt::Debug for Signer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Keep keys out of logs
+        match self {
+            Signer::Hs256(_) => f.write_str("Signer::Hs256(..)"),
+            Signer::Ed25519(_) => f.write_str("Signer::Ed25519(..)"),
+        }
+    }
+}
+
+// MIN_SECRET_LENGTH is the shortest HS256 secret accepted, in bytes, which
+// is as long as the SHA-256 output it keys
+pub const MIN_SECRET_LENGTH: usize = 32;
+
+impl Signer {
+    // from_env reads the key from AUTH_JWT_SECRET for HS256, or from
+    // AUTH_JWT_ED25519_KEY holding a base64url encoded 32 byte Ed25519 seed.
+    // It returns None if neither is set, and an error if the key is unusable.
+    pub fn from_env() -> Result<Option<Signer>, AuthError> {
+        if let Ok(seed) = std::env::var("AUTH_JWT_ED25519_KEY") {
+            return Signer::ed25519(&seed).map(Some);
+        }
+        std::env::var("AUTH_JWT_SECRET").ok().map(|secret| Signer::hs256(secret.into_bytes())).transpose()
+    }
+
+    // hs256 makes an HS256 signer, refusing a secret too short to be safe
+    pub fn hs256(secret: Vec<u8>) -> Result<Signer, AuthError> {
+        if secret.len() < MIN_SECRET_LENGTH {
+            return Err(AuthError::Config(format!("AUTH_JWT_SECRET must be at least {MIN_SECRET_LENGTH} bytes")));
+        }
+        Ok(Signer::Hs256(secret))
+    }
+
+    // ed25519 makes an Ed25519 signer from a base64url encoded 32 byte seed
+    pub fn ed25519(seed: &str) -> Result<Signer, AuthError> {
+        let seed: [u8; 32] = BASE64URL_NOPAD
+            .decode(seed.trim().as_bytes())
+            .ok()
+            .and_then(|seed| seed.try_into().ok())
+            .ok_or_else(|| AuthError::Config("AUTH_JWT_ED25519_KEY is not a base64url encoded 32 byte seed".to_string()))?;
+        Ok(Signer::Ed25519(ed25519_dalek::SigningKey::from_bytes(&seed)))
+    }
+
+    // generate_ed25519 creates a new random Ed25519 key
+    pub fn generate_ed25519() -> Signer {
+        let mut seed = [0; 32];
+        OsRng.fill_bytes(&mut seed);
+        Signer::Ed25519(ed25519_dalek::SigningKey::from_bytes(&seed))
+    }
+
+    pub fn verifier(&self) -> Verifier {
+        match self {
+            Signer::Hs256(secret) => Verifier::Hs256(secret.clone()),
+            Signer::Ed25519(key) => Verifier::Ed25519(key.verifying_key()),
+        }
+    }
+
+    fn algorithm(&self) -> &'static str {
+        match self {
+            Signer::Hs256(_) => "HS256",
+            Signer::Ed25519(_) => "EdDSA",
+        }
+    }
+
+    fn sign(&self, message: &[u8]) -> Vec<u8> {
+        match self {
+            Signer::Hs256(secret) => hs256(secret, message),
+            Signer::Ed25519(key) => key.sign(message).to_bytes().to_vec(),
+        }
+    }
+
+    // token encodes and signs claims as a JWT
+    pub fn token(&self, claims: &Claims) -> Result<String, AuthError> {
+        let header = serde_json::json!({ "alg": self.algorithm(), "typ": "JWT" });
+        let mut token = format!(
+            "{}.{}",
+            BASE64URL_NOPAD.encode(&serde_json::to_vec(&header)?),
+            BASE64URL_NOPAD.encode(&serde_json::to_vec(claims)?)
+        );
+        let signature = self.sign(token.as_bytes());
+        token.push('.');
+        token.push_str(&BASE64URL_NOPAD.encode(&signature));
+        Ok(token)
+    }
+}
+
+fn hs256(secret: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = <Hmac<sha2::Sha256> as Mac>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Verifier checks tokens made by the matching Signer. It only accepts the one
+// algorithm its key is for, so a token can't pick a weaker one in its header.
+#[derive(Debug, Clone)]
+pub enum Verifier {
+    Hs256(Vec<u8>),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+}
+
+impl Verifier {
+    // verify checks a token's signature and expiry and returns its claims. It
+    // doesn't know about revoked tokens, which TokenIssuer::verify checks too.
+    pub fn verify(&self, token: &str, now: u64) -> Result<Claims, AuthError> {
+        let invalid = |reason: &str| AuthError::InvalidToken(reason.to_string());
+        let (message, signature) = token.rsplit_once('.').ok_or_else(|| invalid("not a JWT"))?;
+        let (header, claims) = message.split_once('.').ok_or_else(|| invalid("not a JWT"))?;
+        let decode = |part: &str| BASE64URL_NOPAD.decode(part.as_bytes()).map_err(|_| invalid("bad base64"));
+        let header: Header = serde_json::from_slice(&decode(header)?).map_err(|_| invalid("bad header"))?;
+        let signature = decode(signature)?;
+
+        let valid = match self {
+            Verifier::Hs256(secret) => {
+                header.alg == "HS256" && bool::from(hs256(secret, message.as_bytes()).ct_eq(&signature))
+            }
+            Verifier::Ed25519(key) => {
+                header.alg == "EdDSA"
+                    && ed25519_dalek::Signature::from_slice(&signature)
+                        .is_ok_and(|signature| key.verify(message.as_bytes(), &signature).is_ok())
+            }
+        };
+        if !valid {
+            return Err(invalid("bad signature"));
+        }
+        let claims: Claims = serde_json::from_slice(&decode(claims)?).map_err(|_| invalid("bad claims"))?;
+        if claims.exp <= now {
+            return Err(AuthError::TokenExpired);
+        }
+        Ok(claims)
+    }
+}
+
+// RefreshToken is what a store keeps about a refresh token. The token itself
+// is only known to its holder; the store has its hash. Tokens issued by
+// refreshing one another form a family, which is revoked as a whole if a token
+// that was already used turns up again, since then it has been stolen.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RefreshToken {
+    pub hash: String,
+    pub username: String,
+    pub family: String,
+    pub expires: u64,
+    pub used: bool,
+}
+
+// TokenPair is what a client gets after logging in or refreshing
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub refresh_token: String,
+    // seconds until the access token expires
+    pub expires_in: u64,
+}
+
+fn random_id() -> String {
+    let mut bytes = [0; 32];
+    OsRng.fill_bytes(&mut bytes);
+    BASE64URL_NOPAD.encode(&bytes)
+}
+
+fn hash_token(token: &str) -> String {
+    use sha2::Digest;
+    HEXUPPER.encode(&sha2::Sha256::digest(token.as_bytes()))
+}
+
+// TokenIssuer hands out short lived access tokens along with long lived
+// refresh tokens, which are rotated on every use
+#[derive(Debug, Clone)]
+pub struct TokenIssuer {
+    pub signer: Signer,
+    // lifetimes of access and refresh tokens in seconds
+    pub access_ttl: u64,
+    pub refresh_ttl: u64,
+}
+
+impl TokenIssuer {
+    pub fn new(signer: Signer) -> TokenIssuer {
+        TokenIssuer {
+            signer,
+            access_ttl: 15 * 60,
+            refresh_ttl: 30 * 86400,
+        }
+    }
+
+    // issue starts a new session for a user who has just logged in
+    pub async fn issue(
+        &self,
+        store: &impl UserStore,
+        username: &str,
+        permissions: &Permissions,
+        now: u64,
+    ) -> Result<TokenPair, AuthError> {
+        self.issue_in_family(store, username, permissions, &random_id(), now).await
+    }
+
+    async fn issue_in_family(
+        &self,
+        store: &impl UserStore,
+        username: &str,
+        permissions: &Permissions,
+        family: &str,
+        now: u64,
+    ) -> Result<TokenPair, AuthError> {
+        let claims = Claims {
+            sub: username.to_string(),
+            iat: now,
+            exp: now + self.access_ttl,
+            jti: random_id(),
+            roles: permissions.roles.clone(),
+            permissions: permissions.permissions.clone(),
+        };
+        let refresh_token = random_id();
+        store
+            .save_refresh_token(&RefreshToken {
+                hash: hash_token(&refresh_token),
+                username: username.to_string(),
+                family: family.to_string(),
+                expires: now + self.refresh_ttl,
+                used: false,
+            })
+            .await?;
+        Ok(TokenPair {
+            access_token: self.signer.token(&claims)?,
+            refresh_token,
+            expires_in: self.access_ttl,
+        })
+    }
+
+    // refresh swaps a refresh token for a new pair. The user's permissions are
+    // looked up again, so role changes take effect at the next refresh.
+    pub async fn refresh(&self, store: &impl UserStore, refresh_token: &str, now: u64) -> Result<TokenPair, AuthError> {
+        let invalid = || AuthError::InvalidToken("unknown refresh token".to_string());
+        let token = store.use_refresh_token(&hash_token(refresh_token)).await?.ok_or_else(invalid)?;
+        if token.used {
+            store.revoke_refresh_tokens(&token.family).await?;
+            return Err(AuthError::TokenRevoked);
+        }
+        if token.expires <= now {
+            return Err(AuthError::TokenExpired);
+        }
+        let user = store.get_user(&token.username).await?.ok_or_else(invalid)?;
+        if user.locked_until.is_some_and(|until| until > now) {
+            return Err(AuthError::TokenRevoked);
+        }
+        let permissions = Permissions::resolve(store, &user).await?;
+        self.issue_in_family(store, &user.username, &permissions, &token.family, now).await
+    }
+
+    // verify checks an access token, including whether it has been revoked
+    pub async fn verify(&self, store: &impl UserStore, token: &str, now: u64) -> Result<Claims, AuthError> {
+        let claims = self.signer.verifier().verify(token, now)?;
+        if store.is_revoked(&claims.jti).await? {
+            return Err(AuthError::TokenRevoked);
+        }
+        Ok(claims)
+    }
+
+    // logout ends a session: the access token is revoked until it would have
+    // expired anyway, and its refresh token family can't be used any more
+    pub async fn logout(
+        &self,
+        store: &impl UserStore,
+        access_token: &str,
+        refresh_token: Option<&str>,
+        now: u64,
+    ) -> Result<(), AuthError> {
+        let claims = self.signer.verifier().verify(access_token, now)?;
+        store.revoke_token(&claims.jti, claims.exp).await?;
+        if let Some(refresh_token) = refresh_token {
+            if let Some(token) = store.use_refresh_token(&hash_token(refresh_token)).await? {
+                store.revoke_refresh_tokens(&token.family).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryStore, User};
+
+    fn claims(exp: u64) -> Claims {
+        Claims {
+            sub: "mike".to_string(),
+            iat: 0,
+            exp,
+            jti: "1".to_string(),
+            roles: BTreeSet::from(["user".to_string()]),
+            permissions: BTreeSet::from(["metrics.read".to_string()]),
+        }
+    }
+
+    #[test]
+    fn test_hs256() {
+        let signer = Signer::Hs256(b"secret".to_vec());
+        let token = signer.token(&claims(100)).unwrap();
+        assert!(token.starts_with("eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9."));
+        assert_eq!(claims(100), signer.verifier().verify(&token, 50).unwrap());
+        assert!(matches!(signer.verifier().verify(&token, 100), Err(AuthError::TokenExpired)));
+        let other = Verifier::Hs256(b"other".to_vec());
+        assert!(matches!(other.verify(&token, 50), Err(AuthError::InvalidToken(_))));
+
+        // Changing the claims breaks the signature
+        let (header, rest) = token.split_once('.').unwrap();
+        let signature = rest.split_once('.').unwrap().1;
+        let forged = BASE64URL_NOPAD.encode(&serde_json::to_vec(&claims(1000)).unwrap());
+        let forged = format!("{header}.{forged}.{signature}");
+        assert!(matches!(signer.verifier().verify(&forged, 50), Err(AuthError::InvalidToken(_))));
+    }
+
+    #[test]
+    fn test_ed25519() {
+        let signer = Signer::generate_ed25519();
+        let token = signer.token(&claims(100)).unwrap();
+        assert_eq!(claims(100), signer.verifier().verify(&token, 50).unwrap());
+        assert!(Signer::generate_ed25519().verifier().verify(&token, 50).is_err());
+
+        // An HS256 verifier doesn't accept an EdDSA token, and vice versa
+        let hs256 = Signer::Hs256(b"secret".to_vec());
+        assert!(Verifier::Hs256(b"secret".to_vec()).verify(&token, 50).is_err());
+        assert!(signer.verifier().verify(&hs256.token(&claims(100)).unwrap(), 50).is_err());
+    }
+
+    #[test]
+    fn test_signer_keys() {
+        assert!(matches!(Signer::hs256(b"secret".to_vec()), Err(AuthError::Config(_))));
+        assert!(Signer::hs256(vec![b'x'; MIN_SECRET_LENGTH]).is_ok());
+
+        let seed = BASE64URL_NOPAD.encode(&[7; 32]);
+        let signer = Signer::ed25519(&format!("{seed}\n")).unwrap();
+        assert!(matches!(signer, Signer::Ed25519(_)));
+        assert!(matches!(Signer::ed25519("not base64!"), Err(AuthError::Config(_))));
+        assert!(matches!(Signer::ed25519(&BASE64URL_NOPAD.encode(&[7; 16])), Err(AuthError::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn test_refresh_rotation() {
+        let store = MemoryStore::new();
+        let user = User::new("mike", "", "user").unwrap();
+        store.save_user(&user).await.unwrap();
+        let issuer = TokenIssuer::new(Signer::Hs256(b"secret".to_vec()));
+        let permissions = Permissions::resolve(&store, &user).await.unwrap();
+        // Stores forget expired tokens by the clock, so the tests go by it too
+        let now = crate::now();
+
+        let first = issuer.issue(&store, "mike", &permissions, now).await.unwrap();
+        let claims = issuer.verify(&store, &first.access_token, now + 10).await.unwrap();
+        assert_eq!("mike", claims.sub);
+        assert!(claims.has_permission("metrics.read"));
+        assert!(!claims.has_permission("users.write"));
+
+        // Refreshing picks up the user's new roles
+        let mut admin = user.clone();
+        admin.roles.insert("admin".to_string());
+        store.save_user(&admin).await.unwrap();
+        let second = issuer.refresh(&store, &first.refresh_token, now + 20).await.unwrap();
+        assert_ne!(first.refresh_token, second.refresh_token);
+        assert!(issuer.verify(&store, &second.access_token, now + 30).await.unwrap().has_permission("users.write"));
+
+        // Reusing the old refresh token revokes the whole family
+        assert!(matches!(issuer.refresh(&store, &first.refresh_token, now + 30).await, Err(AuthError::TokenRevoked)));
+        assert!(issuer.refresh(&store, &second.refresh_token, now + 40).await.is_err());
+        assert!(issuer.refresh(&store, "made up", now + 40).await.is_err());
+
+        // Refresh tokens expire too
+        let third = issuer.issue(&store, "mike", &permissions, now).await.unwrap();
+        let late = now + issuer.refresh_ttl + 1;
+        assert!(matches!(issuer.refresh(&store, &third.refresh_token, late).await, Err(AuthError::TokenExpired)));
+    }
+
+    #[tokio::test]
+    async fn test_logout() {
+        let store = MemoryStore::new();
+        let user = User::new("mike", "", "user").unwrap();
+        store.save_user(&user).await.unwrap();
+        let issuer = TokenIssuer::new(Signer::Hs256(b"secret".to_vec()));
+        let permissions = Permissions::resolve(&store, &user).await.unwrap();
+        let now = crate::now();
+        let pair = issuer.issue(&store, "mike", &permissions, now).await.unwrap();
+        let other = issuer.issue(&store, "mike", &permissions, now).await.unwrap();
+
+        issuer.logout(&store, &pair.access_token, Some(&pair.refresh_token), now + 10).await.unwrap();
+        assert!(matches!(issuer.verify(&store, &pair.access_token, now + 20).await, Err(AuthError::TokenRevoked)));
+        assert!(issuer.refresh(&store, &pair.refresh_token, now + 20).await.is_err());
+        // Other sessions carry on
+        assert!(issuer.verify(&store, &other.access_token, now + 20).await.is_ok());
+        assert!(issuer.refresh(&store, &other.refresh_token, now + 20).await.is_ok());
+    }
+}
@@ -0,0 +1,1258 @@
+use crate::roles::default_roles;
+use crate::session::RefreshToken;
+use crate::{get_default_users, now, AuthError, Role, User};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqliteConnection, SqlitePool};
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// UserStore is where users and their password hashes are kept. Usernames are
+// stored lowercase, as User::new creates them.
+pub trait UserStore {
+    fn get_user(&self, username: &str) -> impl Future<Output = Result<Option<User>, AuthError>> + Send;
+
+    // list_users returns every user, sorted by username
+    fn list_users(&self) -> impl Future<Output = Result<Vec<User>, AuthError>> + Send;
+
+    // save_user adds a user or replaces the one with the same username
+    fn save_user(&self, user: &User) -> impl Future<Output = Result<(), AuthError>> + Send;
+
+    // delete_user removes a user, returning false if there was no such user
+    fn delete_user(&self, username: &str) -> impl Future<Output = Result<bool, AuthError>> + Send;
+
+    fn get_role(&self, name: &str) -> impl Future<Output = Result<Option<Role>, AuthError>> + Send;
+
+    // list_roles returns every role, sorted by name
+    fn list_roles(&self) -> impl Future<Output = Result<Vec<Role>, AuthError>> + Send;
+
+    // save_role adds a role or replaces the permissions of the one with the same name
+    fn save_role(&self, role: &Role) -> impl Future<Output = Result<(), AuthError>> + Send;
+
+    // delete_role removes a role, returning false if there was no such role.
+    // Users keep the role's name but it no longer grants them anything.
+    fn delete_role(&self, name: &str) -> impl Future<Output = Result<bool, AuthError>> + Send;
+
+    fn save_refresh_token(&self, token: &RefreshToken) -> impl Future<Output = Result<(), AuthError>> + Send;
+
+    // use_refresh_token marks a refresh token used and returns it as it was
+    // before, so only one caller ever sees it unused
+    fn use_refresh_token(&self, hash: &str) -> impl Future<Output = Result<Option<RefreshToken>, AuthError>> + Send;
+
+    // revoke_refresh_tokens forgets every refresh token in a family
+    fn revoke_refresh_tokens(&self, family: &str) -> impl Future<Output = Result<(), AuthError>> + Send;
+
+    // revoke_token adds an access token's id to the revocation list until it expires
+    fn revoke_token(&self, jti: &str, expires: u64) -> impl Future<Output = Result<(), AuthError>> + Send;
+
+    fn is_revoked(&self, jti: &str) -> impl Future<Output = Result<bool, AuthError>> + Send;
+
+    // lock stops other tasks and processes changing the store until the
+    // returned guard is dropped, so a command can read, modify and write users
+    // without losing anyone else's changes. Stores that are only changed one
+    // row at a time don't need it.
+    fn lock(&self) -> impl Future<Output = Result<StoreLock<'_>, AuthError>> + Send {
+        async { Ok(StoreLock::default()) }
+    }
+
+    // update_user reads a user, changes them with `update` and saves them if
+    // anything changed, without any other change to the user coming in
+    // between. It returns what `update` returned, or None if there is no such
+    // user.
+    fn update_user<T: Send>(
+        &self,
+        username: &str,
+        update: impl FnOnce(&mut User) -> T + Send,
+    ) -> impl Future<Output = Result<Option<T>, AuthError>> + Send
+    where
+        Self: Sync,
+    {
+        async move {
+            let _lock = self.lock().await?;
+            let Some(mut user) = self.get_user(username).await? else {
+                return Ok(None);
+            };
+            let before = user.clone();
+            let result = update(&mut user);
+            if user != before {
+                self.save_user(&user).await?;
+            }
+            Ok(Some(result))
+        }
+    }
+}
+
+// Records are everything the JSON and in-memory stores hold
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Records {
+    users: HashMap<String, User>,
+    roles: HashMap<String, Role>,
+    // refresh tokens by hash
+    #[serde(default)]
+    refresh_tokens: HashMap<String, RefreshToken>,
+    // revoked access token ids and when they expire
+    #[serde(default)]
+    revoked: HashMap<String, u64>,
+}
+
+impl Records {
+    fn new(users: HashMap<String, User>) -> Records {
+        Records {
+            users,
+            roles: default_roles(),
+            refresh_tokens: HashMap::new(),
+            revoked: HashMap::new(),
+        }
+    }
+
+    // prune forgets tokens that have expired, so they don't pile up
+    fn prune(&mut self) {
+        let now = now();
+        self.refresh_tokens.retain(|_, token| token.expires > now);
+        self.revoked.retain(|_, expires| *expires > now);
+    }
+
+    fn save_refresh_token(&mut self, token: &RefreshToken) {
+        self.prune();
+        self.refresh_tokens.insert(token.hash.clone(), token.clone());
+    }
+
+    fn use_refresh_token(&mut self, hash: &str) -> Option<RefreshToken> {
+        let token = self.refresh_tokens.get_mut(hash)?;
+        let before = token.clone();
+        token.used = true;
+        Some(before)
+    }
+
+    fn revoke_token(&mut self, jti: &str, expires: u64) {
+        self.prune();
+        self.revoked.insert(jti.to_string(), expires);
+    }
+
+    fn users(&self) -> Vec<User> {
+        let mut users: Vec<User> = self.users.values().cloned().collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        users
+    }
+
+    fn roles(&self) -> Vec<Role> {
+        let mut roles: Vec<Role> = self.roles.values().cloned().collect();
+        roles.sort_by(|a, b| a.name.cmp(&b.name));
+        roles
+    }
+}
+
+// StoredRecords is a users file as read from disk. Older files were just a
+// map of users, from before there were roles.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredRecords {
+    Current(Records),
+    Legacy(HashMap<String, User>),
+}
+
+impl From<StoredRecords> for Records {
+    fn from(stored: StoredRecords) -> Records {
+        match stored {
+            StoredRecords::Current(records) => records,
+            StoredRecords::Legacy(users) => Records::new(users),
+        }
+    }
+}
+
+// MemoryStore keeps users in memory only, which is mostly useful for tests.
+// It starts out with the default roles.
+#[derive(Debug)]
+pub struct MemoryStore {
+    records: Mutex<Records>,
+    transaction: tokio::sync::Mutex<()>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore::with_users(HashMap::new())
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    pub fn with_users(users: HashMap<String, User>) -> MemoryStore {
+        MemoryStore {
+            records: Mutex::new(Records::new(users)),
+            transaction: tokio::sync::Mutex::new(()),
+        }
+    }
+}
+
+impl UserStore for MemoryStore {
+    async fn get_user(&self, username: &str) -> Result<Option<User>, AuthError> {
+        Ok(self.records.lock().unwrap().users.get(username).cloned())
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, AuthError> {
+        Ok(self.records.lock().unwrap().users())
+    }
+
+    async fn save_user(&self, user: &User) -> Result<(), AuthError> {
+        self.records.lock().unwrap().users.insert(user.username.clone(), user.clone());
+        Ok(())
+    }
+
+    async fn delete_user(&self, username: &str) -> Result<bool, AuthError> {
+        Ok(self.records.lock().unwrap().users.remove(username).is_some())
+    }
+
+    async fn get_role(&self, name: &str) -> Result<Option<Role>, AuthError> {
+        Ok(self.records.lock().unwrap().roles.get(name).cloned())
+    }
+
+    async fn list_roles(&self) -> Result<Vec<Role>, AuthError> {
+        Ok(self.records.lock().unwrap().roles())
+    }
+
+    async fn save_role(&self, role: &Role) -> Result<(), AuthError> {
+        self.records.lock().unwrap().roles.insert(role.name.clone(), role.clone());
+        Ok(())
+    }
+
+    async fn delete_role(&self, name: &str) -> Result<bool, AuthError> {
+        Ok(self.records.lock().unwrap().roles.remove(name).is_some())
+    }
+
+    async fn save_refresh_token(&self, token: &RefreshToken) -> Result<(), AuthError> {
+        self.records.lock().unwrap().save_refresh_token(token);
+        Ok(())
+    }
+
+    async fn use_refresh_token(&self, hash: &str) -> Result<Option<RefreshToken>, AuthError> {
+        Ok(self.records.lock().unwrap().use_refresh_token(hash))
+    }
+
+    async fn revoke_refresh_tokens(&self, family: &str) -> Result<(), AuthError> {
+        self.records.lock().unwrap().refresh_tokens.retain(|_, token| token.family != family);
+        Ok(())
+    }
+
+    async fn revoke_token(&self, jti: &str, expires: u64) -> Result<(), AuthError> {
+        self.records.lock().unwrap().revoke_token(jti, expires);
+        Ok(())
+    }
+
+    async fn is_revoked(&self, jti: &str) -> Result<bool, AuthError> {
+        Ok(self.records.lock().unwrap().revoked.contains_key(jti))
+    }
+
+    async fn lock(&self) -> Result<StoreLock<'_>, AuthError> {
+        Ok(StoreLock {
+            file: None,
+            transaction: Some(self.transaction.lock().await),
+        })
+    }
+}
+
+// How many previous versions of a JSON users file are kept, as users.json.1
+// (the newest) to users.json.3
+const BACKUPS: usize = 3;
+
+// StoreLock keeps other tasks and processes from changing a store until it is
+// dropped
+#[derive(Debug, Default)]
+pub struct StoreLock<'a> {
+    // the advisory lock on the file, which is shared by everything in this
+    // process that holds it
+    file: Option<Arc<Mutex<FileLock>>>,
+    // keeps out other tasks in this process that want the lock
+    transaction: Option<tokio::sync::MutexGuard<'a, ()>>,
+}
+
+impl Drop for StoreLock<'_> {
+    fn drop(&mut self) {
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap();
+            file.holders -= 1;
+            // Closing the lock file releases the lock
+            if file.holders == 0 {
+                file.file = None;
+            }
+        }
+    }
+}
+
+// FileLock is the open lock file while this process holds the advisory lock,
+// and how many StoreLocks are using it
+#[derive(Debug, Default)]
+struct FileLock {
+    file: Option<File>,
+    holders: usize,
+}
+
+// JsonStore keeps users and roles in a JSON file. Files written before there
+// were roles, which map usernames straight to users, are read as having the
+// default roles and rewritten in the current format on the next change. Changes are
+// made under an advisory lock on a users.json.lock file next to it, re-reading
+// the file first so other processes' changes aren't lost. The new version is
+// written to a temporary file and renamed into place, so a crash leaves either
+// the old or the new file, and the old one is kept as a backup. Waiting for
+// the lock and the file IO happen on tokio's blocking threads.
+#[derive(Debug)]
+pub struct JsonStore {
+    file: Arc<JsonFile>,
+    transaction: tokio::sync::Mutex<()>,
+}
+
+// JsonFile is the users file and its cached contents, which are shared with
+// the blocking tasks that read and write it
+#[derive(Debug)]
+struct JsonFile {
+    path: PathBuf,
+    records: Mutex<Records>,
+    file_lock: Arc<Mutex<FileLock>>,
+}
+
+impl JsonStore {
+    // open reads the users file, creating it with the default users if it
+    // doesn't exist yet. It blocks, so async code should use AnyStore::open.
+    pub fn open(path: impl AsRef<Path>) -> Result<JsonStore, AuthError> {
+        let file = JsonFile {
+            path: path.as_ref().to_path_buf(),
+            records: Mutex::new(Records::new(HashMap::new())),
+            file_lock: Arc::new(Mutex::new(FileLock::default())),
+        };
+        if file.path.exists() {
+            file.reload()?;
+        } else {
+            let _lock = file.lock_file()?;
+            if !file.path.exists() {
+                let records = Records::new(get_default_users()?);
+                file.write(&records)?;
+                *file.records.lock().unwrap() = records;
+            }
+        }
+        Ok(JsonStore {
+            file: Arc::new(file),
+            transaction: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    // blocking runs file IO on a blocking thread so it doesn't hold up the runtime
+    async fn blocking<T: Send + 'static>(
+        &self,
+        work: impl FnOnce(&JsonFile) -> Result<T, AuthError> + Send + 'static,
+    ) -> Result<T, AuthError> {
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || work(&file))
+            .await
+            .map_err(|e| AuthError::Io(std::io::Error::other(e)))?
+    }
+
+    // change makes a change to the records under the file lock. `change`
+    // returns its result and whether it changed anything that needs writing.
+    async fn change<T: Send + 'static>(
+        &self,
+        change: impl FnOnce(&mut Records) -> (T, bool) + Send + 'static,
+    ) -> Result<T, AuthError> {
+        self.blocking(|file| {
+            let _lock = file.lock_file()?;
+            let mut records = file.records.lock().unwrap();
+            let (result, changed) = change(&mut records);
+            if changed {
+                file.write(&records)?;
+            }
+            Ok(result)
+        })
+        .await
+    }
+}
+
+impl JsonFile {
+    fn sibling(&self, extension: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(extension);
+        PathBuf::from(path)
+    }
+
+    fn reload(&self) -> Result<(), AuthError> {
+        let records = match std::fs::read_to_string(&self.path) {
+            Ok(users_json) => serde_json::from_str::<StoredRecords>(&users_json)?.into(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Records::new(HashMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+        *self.records.lock().unwrap() = records;
+        Ok(())
+    }
+
+    // lock_file takes the advisory lock, waiting for other processes to let go
+    // of it, and brings the cached users up to date. While this process already
+    // holds it, the lock is shared and released when the last guard is dropped.
+    // It doesn't keep out other tasks in this process; JsonStore::lock does that too.
+    fn lock_file(&self) -> Result<StoreLock<'static>, AuthError> {
+        let mut held = self.file_lock.lock().unwrap();
+        let fresh = held.holders == 0;
+        if fresh {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(self.sibling(".lock"))?;
+            file.lock()?;
+            held.file = Some(file);
+        }
+        held.holders += 1;
+        drop(held);
+        let lock = StoreLock {
+            file: Some(self.file_lock.clone()),
+            transaction: None,
+        };
+        if fresh {
+            self.reload()?;
+        }
+        Ok(lock)
+    }
+
+    // write replaces the users file, keeping the version it replaces as a
+    // backup. The file holds password hashes and TOTP secrets, so on unix it
+    // and its backups can only be read by their owner.
+    fn write(&self, records: &Records) -> Result<(), AuthError> {
+        let users_json = serde_json::to_string(records)?;
+        let temporary = self.sibling(".tmp");
+        // A temporary file left by a crash may have been created with other
+        // permissions, so it is made again rather than reused
+        match std::fs::remove_file(&temporary) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&temporary)?;
+        file.write_all(users_json.as_bytes())?;
+        file.sync_all()?;
+
+        if self.path.exists() {
+            for n in (1..BACKUPS).rev() {
+                let backup = self.sibling(&format!(".{n}"));
+                if backup.exists() {
+                    std::fs::rename(backup, self.sibling(&format!(".{}", n + 1)))?;
+                }
+            }
+            let backup = self.sibling(".1");
+            std::fs::copy(&self.path, &backup)?;
+            // copy keeps the users file's permissions, which are wider if it
+            // was written before files were made private
+            #[cfg(unix)]
+            std::fs::set_permissions(&backup, std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        }
+        std::fs::rename(&temporary, &self.path)?;
+        // Make the rename itself durable
+        #[cfg(unix)]
+        if let Some(directory) = self.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            File::open(directory)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl UserStore for JsonStore {
+    async fn get_user(&self, username: &str) -> Result<Option<User>, AuthError> {
+        Ok(self.file.records.lock().unwrap().users.get(username).cloned())
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, AuthError> {
+        Ok(self.file.records.lock().unwrap().users())
+    }
+
+    async fn save_user(&self, user: &User) -> Result<(), AuthError> {
+        let user = user.clone();
+        self.change(move |records| (records.users.insert(user.username.clone(), user), true)).await?;
+        Ok(())
+    }
+
+    async fn delete_user(&self, username: &str) -> Result<bool, AuthError> {
+        let username = username.to_string();
+        self.change(move |records| {
+            let deleted = records.users.remove(&username).is_some();
+            (deleted, deleted)
+        })
+        .await
+    }
+
+    async fn get_role(&self, name: &str) -> Result<Option<Role>, AuthError> {
+        Ok(self.file.records.lock().unwrap().roles.get(name).cloned())
+    }
+
+    async fn list_roles(&self) -> Result<Vec<Role>, AuthError> {
+        Ok(self.file.records.lock().unwrap().roles())
+    }
+
+    async fn save_role(&self, role: &Role) -> Result<(), AuthError> {
+        let role = role.clone();
+        self.change(move |records| (records.roles.insert(role.name.clone(), role), true)).await?;
+        Ok(())
+    }
+
+    async fn delete_role(&self, name: &str) -> Result<bool, AuthError> {
+        let name = name.to_string();
+        self.change(move |records| {
+            let deleted = records.roles.remove(&name).is_some();
+            (deleted, deleted)
+        })
+        .await
+    }
+
+    async fn save_refresh_token(&self, token: &RefreshToken) -> Result<(), AuthError> {
+        let token = token.clone();
+        self.change(move |records| (records.save_refresh_token(&token), true)).await
+    }
+
+    async fn use_refresh_token(&self, hash: &str) -> Result<Option<RefreshToken>, AuthError> {
+        let hash = hash.to_string();
+        self.change(move |records| {
+            let token = records.use_refresh_token(&hash);
+            let changed = token.as_ref().is_some_and(|token| !token.used);
+            (token, changed)
+        })
+        .await
+    }
+
+    async fn revoke_refresh_tokens(&self, family: &str) -> Result<(), AuthError> {
+        let family = family.to_string();
+        self.change(move |records| (records.refresh_tokens.retain(|_, token| token.family != family), true)).await
+    }
+
+    async fn revoke_token(&self, jti: &str, expires: u64) -> Result<(), AuthError> {
+        let jti = jti.to_string();
+        self.change(move |records| (records.revoke_token(&jti, expires), true)).await
+    }
+
+    async fn is_revoked(&self, jti: &str) -> Result<bool, AuthError> {
+        // Another process may have revoked the token since the file was read
+        self.blocking(JsonFile::reload).await?;
+        Ok(self.file.records.lock().unwrap().revoked.contains_key(jti))
+    }
+
+    async fn lock(&self) -> Result<StoreLock<'_>, AuthError> {
+        let transaction = self.transaction.lock().await;
+        let mut lock = self.blocking(JsonFile::lock_file).await?;
+        lock.transaction = Some(transaction);
+        Ok(lock)
+    }
+}
+
+// SqliteStore keeps users in the users table of a SQLite database, with roles
+// in the roles and role_permissions tables and who has which in user_roles
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    // open connects to a database URL such as sqlite:users.db?mode=rwc, brings
+    // its schema up to date and adds the default users to an empty database
+    pub async fn open(url: &str) -> Result<SqliteStore, AuthError> {
+        let store = SqliteStore::new(SqlitePool::connect(url).await?).await?;
+        if store.list_users().await?.is_empty() {
+            for user in get_default_users()?.values() {
+                store.save_user(user).await?;
+            }
+        }
+        Ok(store)
+    }
+
+    // new uses an existing pool, running the migrations on it
+    pub async fn new(pool: SqlitePool) -> Result<SqliteStore, AuthError> {
+        sqlx::migrate!().run(&pool).await?;
+        Ok(SqliteStore { pool })
+    }
+}
+
+const USER_COLUMNS: &str =
+    "username, password, failed_logins, locked_until, totp_secret, totp_last_step, recovery_codes";
+const ROLES_COLUMN: &str =
+    "(SELECT json_group_array(role) FROM user_roles r WHERE r.username = users.username) AS roles";
+const PERMISSIONS_COLUMN: &str =
+    "(SELECT json_group_array(permission) FROM role_permissions p WHERE p.role = roles.name) AS permissions";
+
+// UserRow is a row of the users table
+#[derive(FromRow)]
+struct UserRow {
+    username: String,
+    password: String,
+    roles: String,
+    failed_logins: i64,
+    locked_until: Option<i64>,
+    totp_secret: Option<String>,
+    totp_last_step: Option<i64>,
+    recovery_codes: String,
+}
+
+impl TryFrom<UserRow> for User {
+    type Error = AuthError;
+
+    fn try_from(row: UserRow) -> Result<User, AuthError> {
+        Ok(User {
+            username: row.username,
+            password: row.password,
+            roles: serde_json::from_str(&row.roles)?,
+            failed_logins: row.failed_logins as u32,
+            locked_until: row.locked_until.map(|until| until as u64),
+            totp_secret: row.totp_secret,
+            totp_last_step: row.totp_last_step.map(|step| step as u64),
+            recovery_codes: serde_json::from_str(&row.recovery_codes)?,
+        })
+    }
+}
+
+// RoleRow is a row of the roles table with its permissions as a JSON array
+#[derive(FromRow)]
+struct RoleRow {
+    name: String,
+    permissions: String,
+}
+
+impl TryFrom<RoleRow> for Role {
+    type Error = AuthError;
+
+    fn try_from(row: RoleRow) -> Result<Role, AuthError> {
+        Ok(Role {
+            name: row.name,
+            permissions: serde_json::from_str::<BTreeSet<String>>(&row.permissions)?,
+        })
+    }
+}
+
+// fetch_user reads a user over a connection, which may be in a transaction
+async fn fetch_user(connection: &mut SqliteConnection, username: &str) -> Result<Option<User>, AuthError> {
+    sqlx::query_as::<_, UserRow>(&format!("SELECT {USER_COLUMNS}, {ROLES_COLUMN} FROM users WHERE username = ?"))
+        .bind(username)
+        .fetch_optional(connection)
+        .await?
+        .map(User::try_from)
+        .transpose()
+}
+
+// write_user saves a user and their roles, and should be given a transaction
+// so they are saved together
+async fn write_user(connection: &mut SqliteConnection, user: &User) -> Result<(), AuthError> {
+    sqlx::query(&format!("INSERT OR REPLACE INTO users ({USER_COLUMNS}) VALUES (?, ?, ?, ?, ?, ?, ?)"))
+        .bind(&user.username)
+        .bind(&user.password)
+        .bind(user.failed_logins as i64)
+        .bind(user.locked_until.map(|until| until as i64))
+        .bind(&user.totp_secret)
+        .bind(user.totp_last_step.map(|step| step as i64))
+        .bind(serde_json::to_string(&user.recovery_codes)?)
+        .execute(&mut *connection)
+        .await?;
+    sqlx::query("DELETE FROM user_roles WHERE username = ?")
+        .bind(&user.username)
+        .execute(&mut *connection)
+        .await?;
+    for role in &user.roles {
+        sqlx::query("INSERT INTO user_roles (username, role) VALUES (?, ?)")
+            .bind(&user.username)
+            .bind(role)
+            .execute(&mut *connection)
+            .await?;
+    }
+    Ok(())
+}
+
+impl UserStore for SqliteStore {
+    async fn get_user(&self, username: &str) -> Result<Option<User>, AuthError> {
+        fetch_user(&mut *self.pool.acquire().await?, username).await
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, AuthError> {
+        sqlx::query_as::<_, UserRow>(&format!("SELECT {USER_COLUMNS}, {ROLES_COLUMN} FROM users ORDER BY username"))
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(User::try_from)
+            .collect()
+    }
+
+    async fn save_user(&self, user: &User) -> Result<(), AuthError> {
+        let mut transaction = self.pool.begin().await?;
+        write_user(&mut transaction, user).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn delete_user(&self, username: &str) -> Result<bool, AuthError> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query("DELETE FROM user_roles WHERE username = ?")
+            .bind(username)
+            .execute(&mut *transaction)
+            .await?;
+        let result = sqlx::query("DELETE FROM users WHERE username = ?")
+            .bind(username)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_role(&self, name: &str) -> Result<Option<Role>, AuthError> {
+        sqlx::query_as::<_, RoleRow>(&format!("SELECT name, {PERMISSIONS_COLUMN} FROM roles WHERE name = ?"))
+            .bind(name)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(Role::try_from)
+            .transpose()
+    }
+
+    async fn list_roles(&self) -> Result<Vec<Role>, AuthError> {
+        sqlx::query_as::<_, RoleRow>(&format!("SELECT name, {PERMISSIONS_COLUMN} FROM roles ORDER BY name"))
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(Role::try_from)
+            .collect()
+    }
+
+    async fn save_role(&self, role: &Role) -> Result<(), AuthError> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query("INSERT OR IGNORE INTO roles (name) VALUES (?)")
+            .bind(&role.name)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query("DELETE FROM role_permissions WHERE role = ?")
+            .bind(&role.name)
+            .execute(&mut *transaction)
+            .await?;
+        for permission in &role.permissions {
+            sqlx::query("INSERT INTO role_permissions (role, permission) VALUES (?, ?)")
+                .bind(&role.name)
+                .bind(permission)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn delete_role(&self, name: &str) -> Result<bool, AuthError> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query("DELETE FROM role_permissions WHERE role = ?")
+            .bind(name)
+            .execute(&mut *transaction)
+            .await?;
+        let result = sqlx::query("DELETE FROM roles WHERE name = ?")
+            .bind(name)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn save_refresh_token(&self, token: &RefreshToken) -> Result<(), AuthError> {
+        sqlx::query("DELETE FROM refresh_tokens WHERE expires <= ?")
+            .bind(now() as i64)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query("INSERT OR REPLACE INTO refresh_tokens (hash, username, family, expires, used) VALUES (?, ?, ?, ?, ?)")
+            .bind(&token.hash)
+            .bind(&token.username)
+            .bind(&token.family)
+            .bind(token.expires as i64)
+            .bind(token.used)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn use_refresh_token(&self, hash: &str) -> Result<Option<RefreshToken>, AuthError> {
+        // The update only changes a row that is still unused, so of two
+        // callers racing to use the same token only one sees it unused
+        let unused = sqlx::query("UPDATE refresh_tokens SET used = TRUE WHERE hash = ? AND NOT used")
+            .bind(hash)
+            .execute(&self.pool)
+            .await?
+            .rows_affected()
+            > 0;
+        let token = sqlx::query_as::<_, (String, String, String, i64)>(
+            "SELECT hash, username, family, expires FROM refresh_tokens WHERE hash = ?",
+        )
+        .bind(hash)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(token.map(|(hash, username, family, expires)| RefreshToken {
+            hash,
+            username,
+            family,
+            expires: expires as u64,
+            used: !unused,
+        }))
+    }
+
+    async fn revoke_refresh_tokens(&self, family: &str) -> Result<(), AuthError> {
+        sqlx::query("DELETE FROM refresh_tokens WHERE family = ?")
+            .bind(family)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn revoke_token(&self, jti: &str, expires: u64) -> Result<(), AuthError> {
+        sqlx::query("DELETE FROM revoked_tokens WHERE expires <= ?")
+            .bind(now() as i64)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query("INSERT OR REPLACE INTO revoked_tokens (jti, expires) VALUES (?, ?)")
+            .bind(jti)
+            .bind(expires as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn is_revoked(&self, jti: &str) -> Result<bool, AuthError> {
+        let revoked = sqlx::query("SELECT 1 FROM revoked_tokens WHERE jti = ?")
+            .bind(jti)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(revoked.is_some())
+    }
+
+    async fn update_user<T: Send>(
+        &self,
+        username: &str,
+        update: impl FnOnce(&mut User) -> T + Send,
+    ) -> Result<Option<T>, AuthError> {
+        let mut transaction = self.pool.begin().await?;
+        // Writing first takes SQLite's write lock for the whole transaction,
+        // so other updates wait rather than failing when this one writes
+        sqlx::query("UPDATE users SET username = username WHERE username = ?")
+            .bind(username)
+            .execute(&mut *transaction)
+            .await?;
+        let Some(mut user) = fetch_user(&mut transaction, username).await? else {
+            return Ok(None);
+        };
+        let before = user.clone();
+        let result = update(&mut user);
+        if user != before {
+            write_user(&mut transaction, &user).await?;
+        }
+        transaction.commit().await?;
+        Ok(Some(result))
+    }
+}
+
+// AnyStore is one of the stores above, picked at runtime from a location
+#[derive(Debug)]
+pub enum AnyStore {
+    Memory(MemoryStore),
+    Json(JsonStore),
+    Sqlite(SqliteStore),
+}
+
+impl AnyStore {
+    // open picks the store from a location: a sqlite: URL, memory: for a
+    // throwaway store with the default users, or otherwise a JSON file path
+    pub async fn open(location: &str) -> Result<AnyStore, AuthError> {
+        if location.starts_with("sqlite:") {
+            Ok(AnyStore::Sqlite(SqliteStore::open(location).await?))
+        } else if location == "memory:" {
+            Ok(AnyStore::Memory(MemoryStore::with_users(get_default_users()?)))
+        } else {
+            let location = location.to_string();
+            let store = tokio::task::spawn_blocking(move || JsonStore::open(location))
+                .await
+                .map_err(|e| AuthError::Io(std::io::Error::other(e)))??;
+            Ok(AnyStore::Json(store))
+        }
+    }
+}
+
+impl UserStore for AnyStore {
+    async fn get_user(&self, username: &str) -> Result<Option<User>, AuthError> {
+        match self {
+            AnyStore::Memory(store) => store.get_user(username).await,
+            AnyStore::Json(store) => store.get_user(username).await,
+            AnyStore::Sqlite(store) => store.get_user(username).await,
+        }
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, AuthError> {
+        match self {
+            AnyStore::Memory(store) => store.list_users().await,
+            AnyStore::Json(store) => store.list_users().await,
+            AnyStore::Sqlite(store) => store.list_users().await,
+        }
+    }
+
+    async fn save_user(&self, user: &User) -> Result<(), AuthError> {
+        match self {
+            AnyStore::Memory(store) => store.save_user(user).await,
+            AnyStore::Json(store) => store.save_user(user).await,
+            AnyStore::Sqlite(store) => store.save_user(user).await,
+        }
+    }
+
+    async fn delete_user(&self, username: &str) -> Result<bool, AuthError> {
+        match self {
+            AnyStore::Memory(store) => store.delete_user(username).await,
+            AnyStore::Json(store) => store.delete_user(username).await,
+            AnyStore::Sqlite(store) => store.delete_user(username).await,
+        }
+    }
+
+    async fn get_role(&self, name: &str) -> Result<Option<Role>, AuthError> {
+        match self {
+            AnyStore::Memory(store) => store.get_role(name).await,
+            AnyStore::Json(store) => store.get_role(name).await,
+            AnyStore::Sqlite(store) => store.get_role(name).await,
+        }
+    }
+
+    async fn list_roles(&self) -> Result<Vec<Role>, AuthError> {
+        match self {
+            AnyStore::Memory(store) => store.list_roles().await,
+            AnyStore::Json(store) => store.list_roles().await,
+            AnyStore::Sqlite(store) => store.list_roles().await,
+        }
+    }
+
+    async fn save_role(&self, role: &Role) -> Result<(), AuthError> {
+        match self {
+            AnyStore::Memory(store) => store.save_role(role).await,
+            AnyStore::Json(store) => store.save_role(role).await,
+            AnyStore::Sqlite(store) => store.save_role(role).await,
+        }
+    }
+
+    async fn delete_role(&self, name: &str) -> Result<bool, AuthError> {
+        match self {
+            AnyStore::Memory(store) => store.delete_role(name).await,
+            AnyStore::Json(store) => store.delete_role(name).await,
+            AnyStore::Sqlite(store) => store.delete_role(name).await,
+        }
+    }
+
+    async fn save_refresh_token(&self, token: &RefreshToken) -> Result<(), AuthError> {
+        match self {
+            AnyStore::Memory(store) => store.save_refresh_token(token).await,
+            AnyStore::Json(store) => store.save_refresh_token(token).await,
+            AnyStore::Sqlite(store) => store.save_refresh_token(token).await,
+        }
+    }
+
+    async fn use_refresh_token(&self, hash: &str) -> Result<Option<RefreshToken>, AuthError> {
+        match self {
+            AnyStore::Memory(store) => store.use_refresh_token(hash).await,
+            AnyStore::Json(store) => store.use_refresh_token(hash).await,
+            AnyStore::Sqlite(store) => store.use_refresh_token(hash).await,
+        }
+    }
+
+    async fn revoke_refresh_tokens(&self, family: &str) -> Result<(), AuthError> {
+        match self {
+            AnyStore::Memory(store) => store.revoke_refresh_tokens(family).await,
+            AnyStore::Json(store) => store.revoke_refresh_tokens(family).await,
+            AnyStore::Sqlite(store) => store.revoke_refresh_tokens(family).await,
+        }
+    }
+
+    async fn revoke_token(&self, jti: &str, expires: u64) -> Result<(), AuthError> {
+        match self {
+            AnyStore::Memory(store) => store.revoke_token(jti, expires).await,
+            AnyStore::Json(store) => store.revoke_token(jti, expires).await,
+            AnyStore::Sqlite(store) => store.revoke_token(jti, expires).await,
+        }
+    }
+
+    async fn is_revoked(&self, jti: &str) -> Result<bool, AuthError> {
+        match self {
+            AnyStore::Memory(store) => store.is_revoked(jti).await,
+            AnyStore::Json(store) => store.is_revoked(jti).await,
+            AnyStore::Sqlite(store) => store.is_revoked(jti).await,
+        }
+    }
+
+    async fn lock(&self) -> Result<StoreLock<'_>, AuthError> {
+        match self {
+            AnyStore::Memory(store) => store.lock().await,
+            AnyStore::Json(store) => store.lock().await,
+            AnyStore::Sqlite(store) => store.lock().await,
+        }
+    }
+
+    async fn update_user<T: Send>(
+        &self,
+        username: &str,
+        update: impl FnOnce(&mut User) -> T + Send,
+    ) -> Result<Option<T>, AuthError> {
+        match self {
+            AnyStore::Memory(store) => store.update_user(username, update).await,
+            AnyStore::Json(store) => store.update_user(username, update).await,
+            AnyStore::Sqlite(store) => store.update_user(username, update).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(username: &str, role: &str) -> User {
+        User { password: "hash".to_string(), ..User::new(username, "", role).unwrap() }
+    }
+
+    // exercise runs the same checks against every kind of store
+    async fn exercise(store: impl UserStore) {
+        store.save_user(&user("zoe", "user")).await.unwrap();
+        store.save_user(&user("amy", "user")).await.unwrap();
+        store.save_user(&user("amy", "admin")).await.unwrap();
+
+        let mut amy = store.get_user("amy").await.unwrap().unwrap();
+        amy.failed_logins = 3;
+        amy.locked_until = Some(1_700_000_000);
+        amy.totp_secret = Some("GEZDGNBVGY3TQOJQ".to_string());
+        amy.totp_last_step = Some(56_666_666);
+        amy.recovery_codes = vec!["A".to_string(), "B".to_string()];
+        store.save_user(&amy).await.unwrap();
+        let stored = store.get_user("amy").await.unwrap().unwrap();
+        assert_eq!((3, Some(1_700_000_000)), (stored.failed_logins, stored.locked_until));
+        assert_eq!(amy.totp_secret, stored.totp_secret);
+        assert_eq!(amy.totp_last_step, stored.totp_last_step);
+        assert_eq!(amy.recovery_codes, stored.recovery_codes);
+
+        let users = store.list_users().await.unwrap();
+        let names: Vec<&str> = users.iter().map(|user| user.username.as_str()).collect();
+        assert_eq!(vec!["amy", "zoe"], names);
+        assert_eq!(BTreeSet::from(["admin".to_string()]), store.get_user("amy").await.unwrap().unwrap().roles);
+        assert!(store.get_user("bob").await.unwrap().is_none());
+
+        assert!(store.delete_user("zoe").await.unwrap());
+        assert!(!store.delete_user("zoe").await.unwrap());
+        assert_eq!(1, store.list_users().await.unwrap().len());
+
+        // Every store starts with the default roles
+        let roles: Vec<String> = store.list_roles().await.unwrap().into_iter().map(|role| role.name).collect();
+        assert_eq!(vec!["admin", "user"], roles);
+        store.save_role(&Role::new("auditor", &["metrics.read"])).await.unwrap();
+        store.save_role(&Role::new("auditor", &["audit.read"])).await.unwrap();
+        store.save_role(&Role::new("temporary", &[])).await.unwrap();
+        assert_eq!(Some(Role::new("auditor", &["audit.read"])), store.get_role("auditor").await.unwrap());
+        assert_eq!(Some(Role::new("temporary", &[])), store.get_role("temporary").await.unwrap());
+        assert!(store.delete_role("temporary").await.unwrap());
+        assert!(!store.delete_role("temporary").await.unwrap());
+        assert_eq!(3, store.list_roles().await.unwrap().len());
+
+        let mut amy = store.get_user("amy").await.unwrap().unwrap();
+        amy.roles.insert("auditor".to_string());
+        store.save_user(&amy).await.unwrap();
+        assert_eq!(amy.roles, store.get_user("amy").await.unwrap().unwrap().roles);
+        assert!(crate::has_permission(&store, "amy", "audit.read").await.unwrap());
+        assert!(!crate::has_permission(&store, "nobody", "audit.read").await.unwrap());
+
+        let expires = now() + 60;
+        let token = |hash: &str, family: &str| RefreshToken {
+            hash: hash.to_string(),
+            username: "amy".to_string(),
+            family: family.to_string(),
+            expires,
+            used: false,
+        };
+        store.save_refresh_token(&token("1", "a")).await.unwrap();
+        store.save_refresh_token(&token("2", "a")).await.unwrap();
+        store.save_refresh_token(&token("3", "b")).await.unwrap();
+        // Only the first use of a refresh token sees it unused
+        assert_eq!(Some(token("1", "a")), store.use_refresh_token("1").await.unwrap());
+        assert!(store.use_refresh_token("1").await.unwrap().unwrap().used);
+        assert!(store.use_refresh_token("4").await.unwrap().is_none());
+        store.revoke_refresh_tokens("a").await.unwrap();
+        assert!(store.use_refresh_token("2").await.unwrap().is_none());
+        assert!(store.use_refresh_token("3").await.unwrap().is_some());
+
+        assert!(!store.is_revoked("jti").await.unwrap());
+        store.revoke_token("jti", expires).await.unwrap();
+        assert!(store.is_revoked("jti").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        exercise(MemoryStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        exercise(SqliteStore::new(pool).await.unwrap()).await;
+    }
+
+    // scratch_dir returns an empty directory for a test's files
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("authentication-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_json_store() {
+        let dir = scratch_dir("json");
+        let path = dir.join("users.json");
+
+        // A new file starts out with the default users
+        let store = JsonStore::open(&path).unwrap();
+        assert_eq!(2, store.list_users().await.unwrap().len());
+        for user in store.list_users().await.unwrap() {
+            store.delete_user(&user.username).await.unwrap();
+        }
+        exercise(store).await;
+
+        // Changes were written through to the file
+        let reopened = JsonStore::open(&path).unwrap();
+        let roles = BTreeSet::from(["admin".to_string(), "auditor".to_string()]);
+        assert_eq!(roles, reopened.get_user("amy").await.unwrap().unwrap().roles);
+        assert_eq!(Some(Role::new("auditor", &["audit.read"])), reopened.get_role("auditor").await.unwrap());
+        std::fs::write(&path, "not json").unwrap();
+        assert!(matches!(JsonStore::open(&path), Err(AuthError::Json(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_json_backups() {
+        let dir = scratch_dir("backups");
+        let path = dir.join("users.json");
+        let store = JsonStore::open(&path).unwrap();
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+
+        let mut versions = vec![read("users.json")];
+        for name in ["a", "b", "c", "d"] {
+            store.save_user(&user(name, "user")).await.unwrap();
+            versions.push(read("users.json"));
+        }
+        // The three versions before the current one are kept, newest first
+        assert_eq!(versions[3], read("users.json.1"));
+        assert_eq!(versions[2], read("users.json.2"));
+        assert_eq!(versions[1], read("users.json.3"));
+        assert!(!dir.join("users.json.4").exists());
+        assert!(!dir.join("users.json.tmp").exists());
+        // Only the owner can read the users file and its backups
+        #[cfg(unix)]
+        for name in ["users.json", "users.json.1", "users.json.3"] {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(dir.join(name)).unwrap().permissions().mode();
+            assert_eq!(0o600, mode & 0o777, "{name}");
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_json_lock() {
+        let dir = scratch_dir("lock");
+        let path = dir.join("users.json");
+        let first = JsonStore::open(&path).unwrap();
+        let second = JsonStore::open(&path).unwrap();
+
+        // Another process's change waits for the lock and is made on top of
+        // the locked changes rather than overwriting them
+        let lock = first.lock().await.unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let writer = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(second.save_user(&user("bob", "user"))).unwrap();
+            sender.send(()).unwrap();
+        });
+        assert!(receiver.recv_timeout(std::time::Duration::from_millis(200)).is_err());
+        first.save_user(&user("amy", "user")).await.unwrap();
+        drop(lock);
+        writer.join().unwrap();
+
+        let users = JsonStore::open(&path).unwrap().list_users().await.unwrap();
+        let names: Vec<&str> = users.iter().map(|user| user.username.as_str()).collect();
+        assert_eq!(vec!["admin", "amy", "bob", "mike"], names);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_json_lock_wait_does_not_block() {
+        let dir = scratch_dir("lock_wait");
+        let path = dir.join("users.json");
+        let first = JsonStore::open(&path).unwrap();
+        let second = JsonStore::open(&path).unwrap();
+
+        // A task waiting for the lock leaves the runtime's only thread free
+        // for the task holding it
+        let lock = first.lock().await.unwrap();
+        let waiting = tokio::spawn(async move { second.save_user(&user("bob", "user")).await });
+        tokio::task::yield_now().await;
+        first.save_user(&user("amy", "user")).await.unwrap();
+        assert!(!waiting.is_finished());
+        drop(lock);
+        waiting.await.unwrap().unwrap();
+
+        let users = JsonStore::open(&path).unwrap().list_users().await.unwrap();
+        assert_eq!(4, users.len());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // concurrent_updates has tasks sharing one store all update the same user
+    // at once, none of which should be lost
+    async fn concurrent_updates(store: impl UserStore + Send + Sync + 'static) {
+        let store = std::sync::Arc::new(store);
+        store.save_user(&user("amy", "user")).await.unwrap();
+        let tasks: Vec<_> = (0..20)
+            .map(|_| {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    store.update_user("amy", |user| user.failed_logins += 1).await.unwrap().unwrap();
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(20, store.get_user("amy").await.unwrap().unwrap().failed_logins);
+        assert!(store.update_user("nobody", |_| ()).await.unwrap().is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_updates() {
+        concurrent_updates(MemoryStore::new()).await;
+        let dir = scratch_dir("concurrent");
+        concurrent_updates(JsonStore::open(dir.join("users.json")).unwrap()).await;
+        let url = format!("sqlite:{}?mode=rwc", dir.join("users.db").display());
+        concurrent_updates(SqliteStore::open(&url).await.unwrap()).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_json_legacy_roles() {
+        let dir = scratch_dir("legacy");
+        let path = dir.join("users.json");
+        // A users.json as written before there were named roles
+        std::fs::write(
+            &path,
+            r#"{"admin":{"username":"admin","password":"hash","role":"Admin"},"mike":{"username":"mike","password":"hash","role":"User"}}"#,
+        )
+        .unwrap();
+
+        let store = JsonStore::open(&path).unwrap();
+        let roles = |user: &User| user.roles.iter().cloned().collect::<Vec<_>>();
+        assert_eq!(vec!["admin"], roles(&store.get_user("admin").await.unwrap().unwrap()));
+        assert_eq!(vec!["user"], roles(&store.get_user("mike").await.unwrap().unwrap()));
+        assert!(crate::has_permission(&store, "admin", crate::roles::USERS_WRITE).await.unwrap());
+        assert!(crate::has_permission(&store, "mike", crate::roles::METRICS_READ).await.unwrap());
+        assert!(!crate::has_permission(&store, "mike", crate::roles::USERS_WRITE).await.unwrap());
+
+        // The next change writes the file in the current format
+        store.save_user(&user("bob", "user")).await.unwrap();
+        let written: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(serde_json::json!(["admin"]), written["users"]["admin"]["roles"]);
+        assert!(written["users"]["admin"].get("role").is_none());
+        assert_eq!(serde_json::json!(["*"]), written["roles"]["admin"]["permissions"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
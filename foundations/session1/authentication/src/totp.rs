@@ -0,0 +1,253 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use subtle::ConstantTimeEq;
+
+// How many steps either side of the current one a code is accepted for, to
+// allow for clocks that are a little off
+const SKEW: u64 = 1;
+const RECOVERY_CODES: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TotpAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl TotpAlgorithm {
+    fn name(&self) -> &'static str {
+        match self {
+            TotpAlgorithm::Sha1 => "SHA1",
+            TotpAlgorithm::Sha256 => "SHA256",
+            TotpAlgorithm::Sha512 => "SHA512",
+        }
+    }
+
+    fn hmac(&self, key: &[u8], message: &[u8]) -> Vec<u8> {
+        fn mac<M: Mac + hmac::digest::KeyInit>(key: &[u8], message: &[u8]) -> Vec<u8> {
+            let mut mac = <M as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+            mac.update(message);
+            mac.finalize().into_bytes().to_vec()
+        }
+        match self {
+            TotpAlgorithm::Sha1 => mac::<Hmac<sha1::Sha1>>(key, message),
+            TotpAlgorithm::Sha256 => mac::<Hmac<sha2::Sha256>>(key, message),
+            TotpAlgorithm::Sha512 => mac::<Hmac<sha2::Sha512>>(key, message),
+        }
+    }
+}
+
+// Totp generates and checks RFC 6238 time-based one time passwords. Users'
+// secrets use the parameters authenticator apps expect by default: SHA-1, six
+// digits and a 30 second step.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Totp {
+    pub secret: Vec<u8>,
+    pub algorithm: TotpAlgorithm,
+    pub digits: u32,
+    pub period: u64,
+}
+
+impl Totp {
+    pub fn new(secret: Vec<u8>) -> Totp {
+        Totp {
+            secret,
+            algorithm: TotpAlgorithm::Sha1,
+            digits: 6,
+            period: 30,
+        }
+    }
+
+    // generate creates a Totp with a new random 160 bit secret
+    pub fn generate() -> Totp {
+        let mut secret = vec![0; 20];
+        OsRng.fill_bytes(&mut secret);
+        Totp::new(secret)
+    }
+
+    // from_base32 reads a secret as stored with a user
+    pub fn from_base32(secret: &str) -> Option<Totp> {
+        BASE32_NOPAD.decode(secret.as_bytes()).ok().map(Totp::new)
+    }
+
+    pub fn secret_base32(&self) -> String {
+        BASE32_NOPAD.encode(&self.secret)
+    }
+
+    fn step(&self, time: u64) -> u64 {
+        time / self.period
+    }
+
+    // code_at_step is the HOTP (RFC 4226) value for a counter
+    fn code_at_step(&self, step: u64) -> String {
+        let hash = self.algorithm.hmac(&self.secret, &step.to_be_bytes());
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let binary = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]) & 0x7fff_ffff;
+        let code = binary as u64 % 10u64.pow(self.digits);
+        format!("{code:0width$}", width = self.digits as usize)
+    }
+
+    // code returns the code for a time in seconds since the Unix epoch
+    pub fn code(&self, time: u64) -> String {
+        self.code_at_step(self.step(time))
+    }
+
+    // verify checks a code against the steps around `now`, returning the step
+    // it matched. Steps up to and including `last_step` are refused, so a code
+    // can't be used twice.
+    pub fn verify(&self, code: &str, now: u64, last_step: Option<u64>) -> Option<u64> {
+        let current = self.step(now);
+        let code = code.trim();
+        (current.saturating_sub(SKEW)..=current + SKEW)
+            .filter(|step| last_step.is_none_or(|last| *step > last))
+            .find(|step| bool::from(self.code_at_step(*step).as_bytes().ct_eq(code.as_bytes())))
+    }
+
+    // uri returns the otpauth:// URI that authenticator apps read from a QR code
+    pub fn uri(&self, issuer: &str, account: &str) -> String {
+        format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm={}&digits={}&period={}",
+            percent_encode(issuer),
+            percent_encode(account),
+            self.secret_base32(),
+            percent_encode(issuer),
+            self.algorithm.name(),
+            self.digits,
+            self.period
+        )
+    }
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+// qr_code renders a QR code of the text with block characters for a terminal
+pub fn qr_code(text: &str) -> String {
+    match qrcode::QrCode::new(text.as_bytes()) {
+        Ok(code) => code
+            .render::<qrcode::render::unicode::Dense1x2>()
+            .dark_color(qrcode::render::unicode::Dense1x2::Light)
+            .light_color(qrcode::render::unicode::Dense1x2::Dark)
+            .build(),
+        Err(_) => String::new(),
+    }
+}
+
+// generate_recovery_codes returns new single use recovery codes, such as
+// 7XKQ-M2PA-H4, along with the hashes that are stored in their place
+pub fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let mut bytes = [0; 7];
+            OsRng.fill_bytes(&mut bytes);
+            let encoded = BASE32_NOPAD.encode(&bytes);
+            let code = format!("{}-{}-{}", &encoded[0..4], &encoded[4..8], &encoded[8..10]);
+            let hash = hash_recovery_code(&code);
+            (code, hash)
+        })
+        .unzip()
+}
+
+// hash_recovery_code hashes a recovery code, ignoring case and dashes. The
+// codes are random enough that a plain SHA-256 is enough to protect them.
+pub fn hash_recovery_code(code: &str) -> String {
+    use sha2::Digest;
+    let normalised: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    format!("{:X}", sha2::Sha256::digest(normalised))
+}
+
+// use_recovery_code removes the code from the stored hashes if it is one of
+// them, returning whether it was
+pub fn use_recovery_code(hashes: &mut Vec<String>, code: &str) -> bool {
+    let hash = hash_recovery_code(code);
+    let found = hashes.iter().position(|stored| bool::from(stored.as_bytes().ct_eq(hash.as_bytes())));
+    match found {
+        Some(index) => {
+            hashes.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The test vectors from appendix B of RFC 6238
+    #[test]
+    fn test_rfc_6238_vectors() {
+        let secrets = [
+            (TotpAlgorithm::Sha1, &b"12345678901234567890"[..]),
+            (TotpAlgorithm::Sha256, &b"12345678901234567890123456789012"[..]),
+            (TotpAlgorithm::Sha512, &b"1234567890123456789012345678901234567890123456789012345678901234"[..]),
+        ];
+        let vectors: [(u64, [&str; 3]); 6] = [
+            (59, ["94287082", "46119246", "90693936"]),
+            (1111111109, ["07081804", "68084774", "25091201"]),
+            (1111111111, ["14050471", "67062674", "99943326"]),
+            (1234567890, ["89005924", "91819424", "93441116"]),
+            (2000000000, ["69279037", "90698825", "38618901"]),
+            (20000000000, ["65353130", "77737706", "47863826"]),
+        ];
+        for (time, codes) in vectors {
+            for ((algorithm, secret), code) in secrets.iter().zip(codes) {
+                let totp = Totp {
+                    secret: secret.to_vec(),
+                    algorithm: *algorithm,
+                    digits: 8,
+                    period: 30,
+                };
+                assert_eq!(code, totp.code(time), "{algorithm:?} at {time}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let totp = Totp::new(b"12345678901234567890".to_vec());
+        // 287082 is the six digit code for the step holding time 59
+        assert_eq!("287082", totp.code(59));
+        assert_eq!(Some(1), totp.verify("287082", 59, None));
+        // A step either side is still accepted, further away is not
+        assert_eq!(Some(1), totp.verify("287082", 89, None));
+        assert_eq!(None, totp.verify("287082", 120, None));
+        assert_eq!(None, totp.verify("000000", 59, None));
+        // A code that was already used is refused
+        assert_eq!(None, totp.verify("287082", 59, Some(1)));
+    }
+
+    #[test]
+    fn test_uri() {
+        let totp = Totp::new(b"12345678901234567890".to_vec());
+        assert_eq!("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", totp.secret_base32());
+        assert_eq!(Some(totp.clone()), Totp::from_base32("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"));
+        assert_eq!(
+            "otpauth://totp/Widget%20Corp:mike?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=Widget%20Corp&algorithm=SHA1&digits=6&period=30",
+            totp.uri("Widget Corp", "mike")
+        );
+        assert!(!qr_code(&totp.uri("Widget Corp", "mike")).is_empty());
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let (codes, mut hashes) = generate_recovery_codes();
+        assert_eq!(RECOVERY_CODES, codes.len());
+        assert_eq!(12, codes[0].len());
+        assert!(use_recovery_code(&mut hashes, &codes[3].to_lowercase().replace('-', "")));
+        // Each code works once
+        assert!(!use_recovery_code(&mut hashes, &codes[3]));
+        assert_eq!(RECOVERY_CODES - 1, hashes.len());
+    }
+}
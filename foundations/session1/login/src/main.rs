@@ -1,25 +1,50 @@
-use authentication::{login, read_line};
+use authentication::{login, login_second_factor, read_line, read_password, AnyStore, AuthError, LoginAction};
 
-fn main() {
+#[tokio::main]
+async fn main() -> Result<(), AuthError> {
+    // Users are read from a JSON file, or a database when AUTH_STORE is a sqlite: URL
+    let location = std::env::var("AUTH_STORE").unwrap_or_else(|_| "users.json".to_string());
+    let store = AnyStore::open(&location).await?;
     let mut tries: i32 = 3;
 
     loop {
         println!("Enter a username: ");
         let username: String = read_line();
-        println!("Enter a password: ");
-        let password: String = read_line();
+        let password: String = read_password("Enter a password: ")?;
 
-        match login(&username, &password) {
-           Some(authentication::LoginAction::Granted(login_role)) => {
-               match login_role {
-                   authentication::LoginRole::Admin => println!("Admin login successful"),
-                   authentication::LoginRole::User => println!("User login successful"),
+        let mut action = login(&store, &username, &password).await?;
+        if action == Some(LoginAction::NeedsSecondFactor) {
+            println!("Enter the code from your authenticator app, or a recovery code: ");
+            let code: String = read_line();
+            action = login_second_factor(&store, &username, &password, &code).await?;
+        }
+
+        match action {
+           Some(authentication::LoginAction::Granted(permissions)) => {
+               if permissions.has_permission(authentication::roles::USERS_WRITE) {
+                   println!("Admin login successful");
+               } else {
+                   println!("User login successful");
+               }
+               let roles: Vec<&str> = permissions.roles.iter().map(String::as_str).collect();
+               println!("Roles: {}", roles.join(", "));
+               // A session token is handed out when a signing key is configured
+               if let Some(signer) = authentication::Signer::from_env()? {
+                   let issuer = authentication::TokenIssuer::new(signer);
+                   let tokens = issuer.issue(&store, &username.to_lowercase(), &permissions, authentication::now()).await?;
+                   println!("Access token: {}", tokens.access_token);
+                   println!("Refresh token: {}", tokens.refresh_token);
                }
                break;
            }
-           Some(authentication::LoginAction::Denied) => {
+           Some(authentication::LoginAction::Denied | authentication::LoginAction::NeedsSecondFactor) => {
                //Do nothing
            }
+           Some(authentication::LoginAction::Locked { until }) => {
+               let wait = until.saturating_sub(authentication::now());
+               println!("Too many failed attempts, the account is locked for {wait} more seconds");
+               break;
+           }
            None => {
                println!("Invalid username");
            }
@@ -32,4 +57,5 @@ fn main() {
             break;
         }
     }
+    Ok(())
 }
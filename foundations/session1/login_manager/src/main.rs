@@ -1,17 +1,22 @@
-use authentication::{User, LoginRole, get_users, save_users};
-use std::collections::HashMap;
+use authentication::{
+    read_line, read_password, roles, totp, AnyStore, AuditEvent, AuditLog, AuthError, PasswordPolicy, Role, Totp, User, UserStore,
+};
 use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
 #[command()]
-
 struct Args {
+    /// Where users are kept: a JSON file, or a sqlite: database URL
+    #[arg(long, env = "AUTH_STORE", default_value = "users.json")]
+    store: String,
+    /// JSON lines file that logins and changes to users are recorded in
+    #[arg(long, env = "AUTH_AUDIT_LOG")]
+    audit_log: Option<String>,
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
 #[derive(Subcommand)]
-
 enum Commands {
     /// List all users.
     List,
@@ -19,11 +24,15 @@ enum Commands {
     Add{
         /// The user's login name
         username: String,
-        /// The user's password (plaintext)
-        password: String,
+        /// Read the password from the first line of standard input instead of prompting
+        #[arg(long)]
+        password_stdin: bool,
         /// Optional - mark as an admin
         #[arg(long)]
-        admin: Option<bool>
+        admin: Option<bool>,
+        /// Roles to give the user instead of admin or user, may be repeated
+        #[arg(long = "role")]
+        roles: Vec<String>,
     },
     /// Delete a user.
     Delete{
@@ -34,80 +43,462 @@ enum Commands {
     ChangePassword{
         /// The user's login name
         username: String,
-        /// The user's new password
-        password: String,
+        /// Read the password from the first line of standard input instead of prompting
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Turn on two factor authentication for a user
+    EnableTotp{
+        /// The user's login name
+        username: String,
+    },
+    /// Turn off two factor authentication for a user
+    DisableTotp{
+        /// The user's login name
+        username: String,
+    },
+    /// List all roles and their permissions
+    Roles,
+    /// Create a role, or replace the permissions of an existing one
+    CreateRole{
+        /// The role's name
+        name: String,
+        /// Permissions such as users.read, or users.* for all under users
+        permissions: Vec<String>,
+    },
+    /// Delete a role
+    DeleteRole{
+        /// The role's name
+        name: String,
+    },
+    /// Give a user a role
+    Assign{
+        /// The user's login name
+        username: String,
+        /// The role's name
+        role: String,
+    },
+    /// Take a role away from a user
+    Unassign{
+        /// The user's login name
+        username: String,
+        /// The role's name
+        role: String,
+    },
+    /// Clear a user's failed logins and lift any lockout
+    Unlock{
+        /// The user's login name
+        username: String,
+    },
+    /// Check the audit log hasn't been tampered with and show its events
+    Audit{
+        /// Only show events about this user
+        #[arg(long)]
+        user: Option<String>,
+        /// Only show events of this kind, such as login_denied or password_changed
+        #[arg(long)]
+        event: Option<String>,
+        /// Only show events at or after this time, in seconds since the Unix epoch
+        #[arg(long)]
+        since: Option<u64>,
+    },
+}
+
+// audit records a change made with this tool, naming the operating system
+// user who ran it
+fn audit(log: Option<&AuditLog>, event: AuditEvent) -> Result<(), AuthError> {
+    if let Some(log) = log {
+        let actor = std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_else(|_| "unknown".to_string());
+        log.record(Some(&actor), event, authentication::now())?;
     }
+    Ok(())
 }
 
-fn list_users() {
-    println!("{:<20}{:<20}", "Username", "Role"); //padding of 20
+async fn list_users(store: &impl UserStore) -> Result<(), AuthError> {
+    println!("{:<20}{:<20}", "Username", "Roles"); //padding of 20
     println!("{:-<40}", "");
 
-    let users: HashMap<String, User> = get_users();
-    users
+    store
+        .list_users()
+        .await?
         .iter()
-        .for_each(|(_, user)|{
-            println!("{:<20}{:20?}", user.username, user.role); //padding of 20
-        })
+        .for_each(|user|{
+            let roles: Vec<&str> = user.roles.iter().map(String::as_str).collect();
+            println!("{:<20}{:<20}", user.username, roles.join(", ")); //padding of 20
+        });
+    Ok(())
 }
 
-fn add_users(username: String, password: String, admin: bool){
-   let mut users: HashMap<String, User> = get_users();
-   let role = if admin {LoginRole::Admin} else {LoginRole::User};
+// new_password asks for a password twice, so a typo isn't saved, without
+// showing it. With --password-stdin it is read once from standard input
+// instead, for scripts.
+fn new_password(password_stdin: bool) -> Result<Option<String>, AuthError> {
+    if password_stdin {
+        return Ok(Some(authentication::read_password_line(&mut std::io::stdin().lock())?));
+    }
+    let password = read_password("New password: ")?;
+    if read_password("Confirm password: ")? != password {
+        println!("Passwords don't match");
+        return Ok(None);
+    }
+    Ok(Some(password))
+}
 
-   let user = User::new(&username, &password, role);
-   users.insert(username, user);
-   save_users(users);
-   println!("User added successfully");
+// password_allowed checks a new password against the policy, explaining what
+// is wrong with it if it is refused
+fn password_allowed(username: &str, password: &str) -> Result<bool, AuthError> {
+    let violations = PasswordPolicy::from_env().check(username, password)?;
+    if violations.is_empty() {
+        return Ok(true);
+    }
+    println!("Password not accepted:");
+    for violation in violations {
+        println!("  - {violation}");
+    }
+    Ok(false)
+}
 
+async fn add_users(
+    store: &impl UserStore,
+    log: Option<&AuditLog>,
+    username: String,
+    password_stdin: bool,
+    roles: Vec<String>,
+) -> Result<(), AuthError> {
+   // Checked before asking for a password, and again under the lock in case
+   // someone else added the user in the meantime
+   if store.get_user(&username.to_lowercase()).await?.is_some() {
+       println!("User {username} already exists, use change-password or assign to change them");
+       return Ok(());
+   }
+   let Some(password) = new_password(password_stdin)? else {
+       return Ok(());
+   };
+   if !password_allowed(&username, &password)? {
+       return Ok(());
+   }
+   let _lock = store.lock().await?;
+   if store.get_user(&username.to_lowercase()).await?.is_some() {
+       println!("User {username} already exists");
+       return Ok(());
+   }
+   for role in &roles {
+       if store.get_role(&role.to_lowercase()).await?.is_none() {
+           println!("Role {role} not found");
+           return Ok(());
+       }
+   }
+
+   let mut user = User::new(&username, &password, &roles[0])?;
+   user.roles.extend(roles.iter().map(|role| role.to_lowercase()));
+   store.save_user(&user).await?;
+   audit(log, AuditEvent::UserAdded { username: user.username, roles: user.roles })?;
+   println!("User added successfully");
+   Ok(())
 }
 
-fn delete_user(username: String){
-    let mut users: HashMap<String, User> = get_users();
-    if users.contains_key(&username){
-        users.remove(&username);
-        save_users(users);
+async fn delete_user(store: &impl UserStore, log: Option<&AuditLog>, username: String) -> Result<(), AuthError> {
+    let _lock = store.lock().await?;
+    let username = username.to_lowercase();
+    if store.delete_user(&username).await? {
+        audit(log, AuditEvent::UserDeleted { username })?;
         println!("User deleted successfully");
     } else {
         println!("User not found");
     }
+    Ok(())
 }
 
-fn update_password(username: String, password: String){
-    let mut users: HashMap<String, User> = get_users();
-    if users.contains_key(&username){
-        let user = users.get_mut(&username).unwrap();
-        user.password = authentication::hash_password(&password);
-        save_users(users);
+async fn update_password(
+    store: &(impl UserStore + Sync),
+    log: Option<&AuditLog>,
+    username: String,
+    password_stdin: bool,
+) -> Result<(), AuthError> {
+    if store.get_user(&username.to_lowercase()).await?.is_none() {
+        println!("User not found");
+        return Ok(());
+    }
+    let Some(password) = new_password(password_stdin)? else {
+        return Ok(());
+    };
+    if !password_allowed(&username, &password)? {
+        return Ok(());
+    }
+    let hash = authentication::hash_password(&password)?;
+    // Only the password is changed, so a concurrent change to anything else
+    // about the user isn't lost
+    let username = username.to_lowercase();
+    if store.update_user(&username, |user| user.password = hash).await?.is_some() {
+        audit(log, AuditEvent::PasswordChanged { username })?;
         println!("Password updated successfully");
     } else {
         println!("User not found");
     }
+    Ok(())
 }
 
-fn main() {
+// enable_totp shows a new TOTP secret as a QR code and saves it once the user
+// has proved their authenticator app works by entering a code from it
+async fn enable_totp(store: &(impl UserStore + Sync), username: String) -> Result<(), AuthError> {
+    let username = username.to_lowercase();
+    if store.get_user(&username).await?.is_none() {
+        println!("User not found");
+        return Ok(());
+    }
+    let totp = Totp::generate();
+    let issuer = std::env::var("AUTH_TOTP_ISSUER").unwrap_or_else(|_| "WidgetCorp".to_string());
+    let uri = totp.uri(&issuer, &username);
+    println!("{}", totp::qr_code(&uri));
+    println!("Scan the QR code, or add this URI to your authenticator app:");
+    println!("{uri}");
+    println!("Enter the code it shows to confirm: ");
+    let Some(step) = totp.verify(&read_line(), authentication::now(), None) else {
+        println!("Wrong code, two factor authentication was not enabled");
+        return Ok(());
+    };
+
+    let (codes, hashes) = totp::generate_recovery_codes();
+    let enabled = store
+        .update_user(&username, |user| {
+            user.totp_secret = Some(totp.secret_base32());
+            user.totp_last_step = Some(step);
+            user.recovery_codes = hashes;
+        })
+        .await?;
+    if enabled.is_none() {
+        println!("User not found");
+        return Ok(());
+    }
+    println!("Two factor authentication enabled. Keep these recovery codes somewhere safe,");
+    println!("each can be used once instead of a code:");
+    for code in codes {
+        println!("  {code}");
+    }
+    Ok(())
+}
+
+async fn disable_totp(store: &(impl UserStore + Sync), username: String) -> Result<(), AuthError> {
+    let disabled = store
+        .update_user(&username.to_lowercase(), |user| {
+            user.totp_secret = None;
+            user.totp_last_step = None;
+            user.recovery_codes.clear();
+        })
+        .await?;
+    if disabled.is_some() {
+        println!("Two factor authentication disabled");
+    } else {
+        println!("User not found");
+    }
+    Ok(())
+}
+
+async fn list_roles(store: &impl UserStore) -> Result<(), AuthError> {
+    println!("{:<20}{:<20}", "Role", "Permissions"); //padding of 20
+    println!("{:-<40}", "");
+
+    for role in store.list_roles().await? {
+        let permissions: Vec<&str> = role.permissions.iter().map(String::as_str).collect();
+        println!("{:<20}{:<20}", role.name, permissions.join(", ")); //padding of 20
+    }
+    Ok(())
+}
+
+async fn create_role(store: &impl UserStore, name: String, permissions: Vec<String>) -> Result<(), AuthError> {
+    let name = name.to_lowercase();
+    if !roles::valid_name(&name) {
+        println!("Role names can only have letters, digits, underscores and dashes");
+        return Ok(());
+    }
+    if let Some(permission) = permissions.iter().find(|permission| !roles::valid_permission(permission)) {
+        println!("{permission} is not a valid permission");
+        return Ok(());
+    }
+    let permissions: Vec<&str> = permissions.iter().map(String::as_str).collect();
+    let _lock = store.lock().await?;
+    store.save_role(&Role::new(&name, &permissions)).await?;
+    println!("Role saved successfully");
+    Ok(())
+}
+
+// delete_role deletes a role that no user has any more, so nobody is left
+// holding a role that doesn't exist
+async fn delete_role(store: &impl UserStore, name: String) -> Result<(), AuthError> {
+    let name = name.to_lowercase();
+    let _lock = store.lock().await?;
+    let holders: Vec<String> = store
+        .list_users()
+        .await?
+        .into_iter()
+        .filter(|user| user.roles.contains(&name))
+        .map(|user| user.username)
+        .collect();
+    if !holders.is_empty() {
+        println!("Role is still assigned to {}, unassign it from them first", holders.join(", "));
+        return Ok(());
+    }
+    if store.delete_role(&name).await? {
+        println!("Role deleted successfully");
+    } else {
+        println!("Role not found");
+    }
+    Ok(())
+}
+
+// assign_role gives a user a role, or takes it away
+async fn assign_role(
+    store: &(impl UserStore + Sync),
+    log: Option<&AuditLog>,
+    username: String,
+    role: String,
+    assign: bool,
+) -> Result<(), AuthError> {
+    let username = username.to_lowercase();
+    let role = role.to_lowercase();
+    if assign && store.get_role(&role).await?.is_none() {
+        println!("Role not found");
+        return Ok(());
+    }
+    // The roles the user ends up with, if they changed
+    let changed = store
+        .update_user(&username, |user| {
+            let changed = if assign { user.roles.insert(role) } else { user.roles.remove(&role) };
+            changed.then(|| user.roles.clone())
+        })
+        .await?;
+    match changed {
+        None => println!("User not found"),
+        Some(Some(roles)) => {
+            audit(log, AuditEvent::RolesChanged { username, roles })?;
+            if assign {
+                println!("Role assigned successfully");
+            } else {
+                println!("Role removed successfully");
+            }
+        }
+        Some(None) if assign => println!("User already has that role"),
+        Some(None) => println!("User doesn't have that role"),
+    }
+    Ok(())
+}
+
+async fn unlock_user(store: &(impl UserStore + Sync), username: String) -> Result<(), AuthError> {
+    if authentication::unlock(store, &username).await? {
+        println!("User unlocked successfully");
+    } else {
+        println!("User not found");
+    }
+    Ok(())
+}
+
+// show_audit_log verifies the whole chain before showing any events, so
+// nothing is shown from a log that has been tampered with
+fn show_audit_log(log: Option<&AuditLog>, user: Option<String>, event: Option<String>, since: Option<u64>) -> Result<(), AuthError> {
+    let Some(log) = log else {
+        println!("No audit log, set AUTH_AUDIT_LOG or --audit-log");
+        return Ok(());
+    };
+    let records = match log.verify() {
+        Ok(records) => records,
+        Err(AuthError::AuditChain(reason)) => {
+            println!("Audit log verification FAILED at {reason}");
+            std::process::exit(1);
+        }
+        Err(err) => return Err(err),
+    };
+    println!("Audit log verified, {} events", records.len());
+    println!();
+    println!("{:<8}{:<12}{:<18}{:<20}{:<20}Details", "Seq", "Time", "Event", "Username", "By"); //padding of 20
+    println!("{:-<90}", "");
+
+    let user = user.map(|user| user.to_lowercase());
+    records
+        .iter()
+        .filter(|record| user.as_deref().is_none_or(|user| record.event.username() == user))
+        .filter(|record| event.as_deref().is_none_or(|event| record.event.kind() == event))
+        .filter(|record| since.is_none_or(|since| record.time >= since))
+        .for_each(|record| {
+            let details = match &record.event {
+                AuditEvent::LoginLocked { until, .. } => format!("locked until {until}"),
+                AuditEvent::UserAdded { roles, .. } | AuditEvent::RolesChanged { roles, .. } => {
+                    let roles: Vec<&str> = roles.iter().map(String::as_str).collect();
+                    format!("roles: {}", roles.join(", "))
+                }
+                _ => String::new(),
+            };
+            let line = format!(
+                "{:<8}{:<12}{:<18}{:<20}{:<20}{}",
+                record.seq,
+                record.time,
+                record.event.kind(),
+                record.event.username(),
+                record.actor.as_deref().unwrap_or("-"),
+                details
+            );
+            println!("{}", line.trim_end());
+        });
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), AuthError> {
     /*
      * cargo run -- list 
      * cargo run -- --help
     */
     let cli = Args::parse();
+    let store = AnyStore::open(&cli.store).await?;
+    let log = cli.audit_log.map(AuditLog::new);
+    let log = log.as_ref();
     match cli.command{
         Some(Commands::List) => {
             //println!("List users here");
-            list_users();
+            list_users(&store).await?;
         }
-        Some(Commands::Add{username, password, admin}) => {
+        Some(Commands::Add{username, password_stdin, admin, mut roles}) => {
             // As admin is an optional field, it's unwrapped with a default value of false
-            add_users(username, password, admin.unwrap_or(false));
+            if roles.is_empty() {
+                roles.push(if admin.unwrap_or(false) { roles::ADMIN } else { roles::USER }.to_string());
+            }
+            add_users(&store, log, username, password_stdin, roles).await?;
         }
         Some(Commands::Delete{username}) => {
-            delete_user(username); 
+            delete_user(&store, log, username).await?;
+        }
+        Some(Commands::ChangePassword{username, password_stdin}) => {
+            update_password(&store, log, username, password_stdin).await?;
+        }
+        Some(Commands::EnableTotp{username}) => {
+            enable_totp(&store, username).await?;
+        }
+        Some(Commands::DisableTotp{username}) => {
+            disable_totp(&store, username).await?;
+        }
+        Some(Commands::Roles) => {
+            list_roles(&store).await?;
+        }
+        Some(Commands::CreateRole{name, permissions}) => {
+            create_role(&store, name, permissions).await?;
+        }
+        Some(Commands::DeleteRole{name}) => {
+            delete_role(&store, name).await?;
+        }
+        Some(Commands::Assign{username, role}) => {
+            assign_role(&store, log, username, role, true).await?;
+        }
+        Some(Commands::Unassign{username, role}) => {
+            assign_role(&store, log, username, role, false).await?;
+        }
+        Some(Commands::Unlock{username}) => {
+            unlock_user(&store, username).await?;
         }
-        Some(Commands::ChangePassword{username, password}) => {
-            update_password(username, password);
+        Some(Commands::Audit{user, event, since}) => {
+            show_audit_log(log, user, event, since)?;
         }
         None => {
             println!("Run with --help to see instructions.");
         }
     }
+    Ok(())
 }
@@ -1,3 +1,4 @@
+use shared_data::ErrorCode;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -8,4 +9,6 @@ pub enum CollectorError {
     UnableToSend,
     #[error("Failed to receive data")]
     UnableToReceive,
+    #[error("Server rejected the data: {0:?}")]
+    Rejected(ErrorCode),
 }
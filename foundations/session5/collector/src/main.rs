@@ -17,11 +17,30 @@ fn get_uuid() -> u128 {
     }
 }
 
+// get_labels reads the labels describing this host from COLLECTOR_LABELS,
+// written as comma separated name=value pairs, e.g. "role=db,region=eu"
+fn get_labels() -> Vec<(String, String)> {
+    std::env::var("COLLECTOR_LABELS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            Some((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
 fn main() {
     let uuid = get_uuid();
 
     let (tx, rx) = std::sync::mpsc::channel::<CollectorCommandV1>();
 
+    // Tell the server about this host before the first sample
+    let labels = get_labels();
+    if !labels.is_empty() {
+        tx.send(CollectorCommandV1::SetLabels { collector_id: uuid, labels }).unwrap();
+    }
+
     // Start the collector thread
     let _collector_thread = std::thread::spawn(move || {
         data_collector::collect_data(tx, uuid);
@@ -1,5 +1,5 @@
 use crate::errors::CollectorError;
-use shared_data::{decode_response_v1, CollectorResponseV1, DATA_COLLECTOR_ADDRESS};
+use shared_data::{decode_response_v1, CollectorResponseV1, ErrorCode, DATA_COLLECTOR_ADDRESS};
 use std::collections::VecDeque;
 use std::io::{Read, Write};
 
@@ -38,12 +38,19 @@ pub fn send_queue(queue: &mut VecDeque<Vec<u8>>) -> Result<(), CollectorError>{
         // Decode the response
         let ack = decode_response_v1(&buf[0..bytes_read]);
         // if the response is not an ack, put the command back in the queue and return an error
-        if ack != CollectorResponseV1::Ack(0) {
-            queue.push_front(command);
-            return Err(CollectorError::UnableToReceive);
-        } else {
-            // Ack received
-            println!("Ack received");
+        match ack {
+            CollectorResponseV1::Ack(0) => println!("Ack received"),
+            CollectorResponseV1::Error(code) => {
+                // Retrying a frame the server can never accept would block the queue forever
+                if !matches!(code, ErrorCode::FrameTooLarge | ErrorCode::InvalidFrame) {
+                    queue.push_front(command);
+                }
+                return Err(CollectorError::Rejected(code));
+            }
+            CollectorResponseV1::Ack(_) => {
+                queue.push_front(command);
+                return Err(CollectorError::UnableToReceive);
+            }
         }
     }
     Ok(())
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS alert_rules
+(
+    name VARCHAR(255) PRIMARY KEY,
+    condition TEXT NOT NULL,
+    repeat_seconds INTEGER NOT NULL
+)
@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS collectors
+(
+    collector_id VARCHAR(255) PRIMARY KEY,
+    label VARCHAR(255),
+    version INTEGER NOT NULL DEFAULT 1,
+    first_seen TIMESTAMP NOT NULL,
+    last_seen TIMESTAMP NOT NULL,
+    decommissioned BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE IF NOT EXISTS collector_tags
+(
+    collector_id VARCHAR(255) NOT NULL REFERENCES collectors (collector_id) ON DELETE CASCADE,
+    tag VARCHAR(255) NOT NULL,
+    PRIMARY KEY (collector_id, tag)
+);
+
+-- Register every collector that already has data
+INSERT OR IGNORE INTO collectors (collector_id, first_seen, last_seen)
+SELECT collector_id, MIN(received), MAX(received) FROM timeseries GROUP BY collector_id;
@@ -0,0 +1,2 @@
+-- Most queries look up a collector's samples in time order
+CREATE INDEX IF NOT EXISTS timeseries_collector_received ON timeseries (collector_id, received)
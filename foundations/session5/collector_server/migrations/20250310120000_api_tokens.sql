@@ -0,0 +1,16 @@
+-- Every collector belongs to an organisation, existing ones to the default one
+ALTER TABLE collectors ADD COLUMN organisation VARCHAR(255) NOT NULL DEFAULT 'default';
+
+CREATE INDEX IF NOT EXISTS collectors_organisation ON collectors (organisation);
+
+-- Only a hash of each token is kept, the token itself is shown once when issued
+CREATE TABLE IF NOT EXISTS api_tokens
+(
+    token_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    name VARCHAR(255) NOT NULL,
+    organisation VARCHAR(255) NOT NULL,
+    scope VARCHAR(16) NOT NULL,
+    created TIMESTAMP NOT NULL,
+    revoked BOOLEAN NOT NULL DEFAULT FALSE
+);
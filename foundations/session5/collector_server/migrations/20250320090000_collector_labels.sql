@@ -0,0 +1,10 @@
+-- Key/value labels, set by the collector itself or through the API
+CREATE TABLE IF NOT EXISTS collector_labels
+(
+    collector_id VARCHAR(255) NOT NULL REFERENCES collectors (collector_id) ON DELETE CASCADE,
+    name VARCHAR(64) NOT NULL,
+    value VARCHAR(255) NOT NULL,
+    PRIMARY KEY (collector_id, name)
+);
+
+CREATE INDEX IF NOT EXISTS collector_labels_name_value ON collector_labels (name, value);
@@ -0,0 +1,16 @@
+-- Alert rules belong to an organisation, existing ones to the default one,
+-- and rule names only need to be unique within it
+CREATE TABLE alert_rules_by_organisation
+(
+    organisation VARCHAR(255) NOT NULL DEFAULT 'default',
+    name VARCHAR(255) NOT NULL,
+    condition TEXT NOT NULL,
+    repeat_seconds INTEGER NOT NULL,
+    PRIMARY KEY (organisation, name)
+);
+
+INSERT INTO alert_rules_by_organisation (name, condition, repeat_seconds)
+SELECT name, condition, repeat_seconds FROM alert_rules;
+
+DROP TABLE alert_rules;
+ALTER TABLE alert_rules_by_organisation RENAME TO alert_rules;
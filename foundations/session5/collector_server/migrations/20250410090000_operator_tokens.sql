@@ -0,0 +1,18 @@
+-- A token without an organisation belongs to an operator, who can see every
+-- organisation and move collectors between them
+CREATE TABLE api_tokens_with_operators
+(
+    token_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    token_hash VARCHAR(64) NOT NULL UNIQUE,
+    name VARCHAR(255) NOT NULL,
+    organisation VARCHAR(255),
+    scope VARCHAR(16) NOT NULL,
+    created TIMESTAMP NOT NULL,
+    revoked BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+INSERT INTO api_tokens_with_operators (token_id, token_hash, name, organisation, scope, created, revoked)
+SELECT token_id, token_hash, name, organisation, scope, created, revoked FROM api_tokens;
+
+DROP TABLE api_tokens;
+ALTER TABLE api_tokens_with_operators RENAME TO api_tokens;
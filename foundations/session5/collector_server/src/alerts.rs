@@ -0,0 +1,206 @@
+use crate::anomaly::Scores;
+use crate::collector::Sample;
+use crate::notify::{Alert, AlertStatus, Dispatcher};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Row, Sqlite};
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    Cpu,    // average CPU usage in percent
+    Memory, // used memory as a percentage of total memory
+}
+
+impl Metric {
+    pub fn value(&self, sample: &Sample) -> f32 {
+        match self {
+            Metric::Cpu => sample.average_cpu,
+            Metric::Memory => sample.used_memory as f32 / sample.total_memory.max(1) as f32 * 100.0,
+        }
+    }
+}
+
+// AlertCondition is stored as JSON so new condition types don't need a migration
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AlertCondition {
+    Threshold { metric: Metric, above: f32 },
+    // Anomaly fires when the metric strays from its learned baseline. Without
+    // a z_score the one from the [anomaly] configuration is used.
+    Anomaly {
+        metric: Metric,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        z_score: Option<f32>,
+    },
+}
+
+impl AlertCondition {
+    // check returns the observed value if the sample breaches the condition
+    fn check(&self, sample: &Sample, scores: &Scores) -> Option<f32> {
+        match self {
+            AlertCondition::Threshold { metric, above } => {
+                let value = metric.value(sample);
+                (value > *above).then_some(value)
+            }
+            AlertCondition::Anomaly { metric, z_score } => scores.anomalous(*metric, *z_score).map(|score| score.value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertRule {
+    // organisation is the only one whose collectors the rule is checked
+    // against. It is left empty in a request to mean the caller's own.
+    #[serde(default)]
+    pub organisation: String,
+    pub name: String,
+    pub condition: AlertCondition,
+    // repeat_seconds is how often a still-firing alert is sent again
+    pub repeat_seconds: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FiringAlert {
+    pub rule: String,
+    pub collector_id: String,
+    pub since: u32,
+    pub value: f32,
+}
+
+// Alerts holds the rules in memory, evaluates every ingested sample against
+// them and hands state changes to the dispatcher.
+pub struct Alerts {
+    rules: RwLock<Vec<AlertRule>>,
+    // firing is keyed by the rule's organisation and name, and the collector
+    firing: Mutex<HashMap<(String, String, String), FiringAlert>>,
+    dispatcher: Dispatcher,
+}
+
+impl Alerts {
+    // load reads the rules from the alert_rules table
+    pub async fn load(pool: &Pool<Sqlite>, dispatcher: Dispatcher) -> anyhow::Result<Self> {
+        let rows = sqlx::query("SELECT organisation, name, condition, repeat_seconds FROM alert_rules")
+            .fetch_all(pool)
+            .await?;
+        let mut rules = Vec::with_capacity(rows.len());
+        for row in rows {
+            rules.push(AlertRule {
+                organisation: row.get("organisation"),
+                name: row.get("name"),
+                condition: serde_json::from_str(row.get("condition"))?,
+                repeat_seconds: row.get::<i64, _>("repeat_seconds") as u64,
+            });
+        }
+        Ok(Self {
+            rules: RwLock::new(rules),
+            firing: Mutex::new(HashMap::new()),
+            dispatcher,
+        })
+    }
+
+    // rules returns the rules of an organisation, or every rule for None
+    pub fn rules(&self, organisation: Option<&str>) -> Vec<AlertRule> {
+        let rules = self.rules.read().unwrap();
+        rules
+            .iter()
+            .filter(|rule| organisation.is_none_or(|organisation| rule.organisation == organisation))
+            .cloned()
+            .collect()
+    }
+
+    // save_rule inserts or replaces the rule with the same organisation and
+    // name, both in the database and in memory
+    pub async fn save_rule(&self, pool: &Pool<Sqlite>, rule: AlertRule) -> anyhow::Result<()> {
+        sqlx::query("INSERT OR REPLACE INTO alert_rules (organisation, name, condition, repeat_seconds) VALUES (?, ?, ?, ?)")
+            .bind(&rule.organisation)
+            .bind(&rule.name)
+            .bind(serde_json::to_string(&rule.condition)?)
+            .bind(rule.repeat_seconds as i64)
+            .execute(pool)
+            .await?;
+        let mut rules = self.rules.write().unwrap();
+        rules.retain(|r| (&r.organisation, &r.name) != (&rule.organisation, &rule.name));
+        rules.push(rule);
+        Ok(())
+    }
+
+    // delete_rule returns false if the organisation has no rule with that name
+    pub async fn delete_rule(&self, pool: &Pool<Sqlite>, organisation: &str, name: &str) -> anyhow::Result<bool> {
+        let result = sqlx::query("DELETE FROM alert_rules WHERE organisation = ? AND name = ?")
+            .bind(organisation)
+            .bind(name)
+            .execute(pool)
+            .await?;
+        self.rules.write().unwrap().retain(|r| (r.organisation.as_str(), r.name.as_str()) != (organisation, name));
+        self.firing.lock().unwrap().retain(|(rule_organisation, rule, _), _| (rule_organisation.as_str(), rule.as_str()) != (organisation, name));
+        Ok(result.rows_affected() > 0)
+    }
+
+    // flush waits for notifications that have been dispatched but not delivered yet
+    pub async fn flush(&self) {
+        self.dispatcher.flush().await;
+    }
+
+    pub fn firing(&self) -> Vec<FiringAlert> {
+        self.firing.lock().unwrap().values().cloned().collect()
+    }
+
+    // evaluate checks a freshly ingested sample, and how it scored against
+    // the anomaly baselines, against the rules of the organisation its
+    // collector is in. Alerts from another organisation's rules, left over
+    // from before the collector was moved, are resolved.
+    pub fn evaluate(&self, sample: &Sample, organisation: &str, scores: &Scores) {
+        let rules = self.rules.read().unwrap();
+        let mut firing = self.firing.lock().unwrap();
+        for rule in rules.iter() {
+            let key = (rule.organisation.clone(), rule.name.clone(), sample.collector_id.clone());
+            let repeat = Duration::from_secs(rule.repeat_seconds);
+            let breach = match rule.organisation == organisation {
+                true => rule.condition.check(sample, scores),
+                false => None,
+            };
+            match breach {
+                Some(value) => {
+                    let entry = firing.entry(key).or_insert_with(|| FiringAlert {
+                        rule: rule.name.clone(),
+                        collector_id: sample.collector_id.clone(),
+                        since: sample.received,
+                        value,
+                    });
+                    entry.value = value;
+                    self.dispatcher.dispatch(
+                        Alert {
+                            organisation: rule.organisation.clone(),
+                            rule: rule.name.clone(),
+                            collector_id: sample.collector_id.clone(),
+                            status: AlertStatus::Firing,
+                            value,
+                            message: format!("{} on {}: {:?} (value {value:.2})", rule.name, sample.collector_id, rule.condition),
+                            timestamp: sample.received,
+                        },
+                        repeat,
+                    );
+                }
+                None => {
+                    if let Some(resolved) = firing.remove(&key) {
+                        self.dispatcher.dispatch(
+                            Alert {
+                                organisation: rule.organisation.clone(),
+                                rule: rule.name.clone(),
+                                collector_id: sample.collector_id.clone(),
+                                status: AlertStatus::Resolved,
+                                value: resolved.value,
+                                message: format!("{} on {} resolved", rule.name, sample.collector_id),
+                                timestamp: sample.received,
+                            },
+                            repeat,
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use crate::alerts::Metric;
+use crate::collector::Sample;
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+const METRICS: [Metric; 2] = [Metric::Cpu, Metric::Memory];
+const HOURS: usize = 24;
+// Both metrics are percentages. A metric that hardly moves would otherwise
+// have a near zero deviation and turn every small wobble into an anomaly.
+const MIN_STDDEV: f32 = 0.5;
+// How many anomalies /api/anomalies can look back over
+const RECENT_ANOMALIES: usize = 1000;
+
+// AnomalyConfig tunes how quickly the baselines follow the data and how far
+// a sample has to stray from them to count as an anomaly
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnomalyConfig {
+    pub enabled: bool,
+    // standard deviations from the baseline before a sample is anomalous
+    pub z_score: f32,
+    // how long it takes for the rolling baseline to half forget a sample
+    pub half_life_seconds: u32,
+    // the same for the hour of day baselines, which learn one hour a day
+    pub seasonal_half_life_seconds: u32,
+    // samples a baseline needs before it is trusted
+    pub warmup_samples: u32,
+    // days of stored samples replayed at startup so baselines survive restarts
+    pub warmup_days: u32,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            z_score: 4.0,
+            half_life_seconds: 3600,
+            seasonal_half_life_seconds: 7 * 86400,
+            warmup_samples: 30,
+            warmup_days: 7,
+        }
+    }
+}
+
+// Ewma is an exponentially weighted mean and variance over irregularly spaced samples
+#[derive(Debug, Clone, Copy, Default)]
+struct Ewma {
+    mean: f32,
+    variance: f32,
+    samples: u32,
+    first: u32,
+    last: u32,
+}
+
+impl Ewma {
+    fn update(&mut self, value: f32, timestamp: u32, half_life: u32) {
+        if self.samples == 0 {
+            *self = Ewma {
+                mean: value,
+                variance: 0.0,
+                samples: 1,
+                first: timestamp,
+                last: timestamp,
+            };
+            return;
+        }
+        // The weight of the new sample depends on how long it has been since
+        // the last one. Early on it is at least a plain average, so the first
+        // sample doesn't dominate the baseline for a whole half life.
+        let elapsed = timestamp.saturating_sub(self.last).max(1) as f32;
+        let alpha = 1.0 - (-elapsed * std::f32::consts::LN_2 / half_life.max(1) as f32).exp();
+        let alpha = alpha.max(1.0 / (self.samples + 1) as f32);
+        let delta = value - self.mean;
+        self.mean += alpha * delta;
+        self.variance = (1.0 - alpha) * (self.variance + alpha * delta * delta);
+        self.samples += 1;
+        self.last = self.last.max(timestamp);
+    }
+
+    fn stddev(&self) -> f32 {
+        self.variance.sqrt().max(MIN_STDDEV)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BaselineKind {
+    Rolling,  // the recent past
+    Seasonal, // the same hour on previous days
+}
+
+// Score is how unusual a sample's metric is compared to its baseline
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Score {
+    pub metric: Metric,
+    pub value: f32,
+    pub expected: f32,
+    pub stddev: f32,
+    pub z_score: f32,
+    pub baseline: BaselineKind,
+}
+
+// Scores are the scores of one sample, for the metrics that have a trusted
+// baseline, along with the configured z-score an anomaly has to reach
+#[derive(Debug, Clone, Default)]
+pub struct Scores {
+    scores: Vec<Score>,
+    z_score: f32,
+}
+
+impl Scores {
+    pub fn get(&self, metric: Metric) -> Option<&Score> {
+        self.scores.iter().find(|score| score.metric == metric)
+    }
+
+    // anomalous returns the metric's score if it is at least `z_score`, or the
+    // configured z-score, away from the baseline in either direction
+    pub fn anomalous(&self, metric: Metric, z_score: Option<f32>) -> Option<&Score> {
+        let threshold = z_score.unwrap_or(self.z_score);
+        self.get(metric).filter(|score| score.z_score.abs() >= threshold)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Anomaly {
+    pub collector_id: String,
+    pub timestamp: u32,
+    #[serde(flatten)]
+    pub score: Score,
+}
+
+// Baselines are the statistics kept for one collector and metric
+#[derive(Debug, Clone, Default)]
+struct Baselines {
+    rolling: Ewma,
+    hourly: [Ewma; HOURS],
+}
+
+impl Baselines {
+    // score compares a value to the baselines as they were before it arrived.
+    // The seasonal baseline is preferred once it has seen a previous day.
+    fn score(&self, metric: Metric, value: f32, timestamp: u32, config: &AnomalyConfig) -> Option<Score> {
+        let hourly = &self.hourly[hour_of_day(timestamp)];
+        let (baseline, kind) = if hourly.samples >= config.warmup_samples && timestamp.saturating_sub(hourly.first) >= 86400 {
+            (hourly, BaselineKind::Seasonal)
+        } else if self.rolling.samples >= config.warmup_samples {
+            (&self.rolling, BaselineKind::Rolling)
+        } else {
+            return None;
+        };
+        let stddev = baseline.stddev();
+        Some(Score {
+            metric,
+            value,
+            expected: baseline.mean,
+            stddev,
+            z_score: (value - baseline.mean) / stddev,
+            baseline: kind,
+        })
+    }
+
+    fn update(&mut self, value: f32, timestamp: u32, config: &AnomalyConfig) {
+        self.rolling.update(value, timestamp, config.half_life_seconds);
+        self.hourly[hour_of_day(timestamp)].update(value, timestamp, config.seasonal_half_life_seconds);
+    }
+}
+
+fn hour_of_day(timestamp: u32) -> usize {
+    (timestamp / 3600) as usize % HOURS
+}
+
+// Detector keeps rolling statistics for every collector and metric in memory
+// and remembers the most recent anomalies
+pub struct Detector {
+    config: AnomalyConfig,
+    baselines: Mutex<HashMap<(String, Metric), Baselines>>,
+    recent: Mutex<VecDeque<Anomaly>>,
+}
+
+impl Detector {
+    pub fn new(config: AnomalyConfig) -> Self {
+        Self {
+            config,
+            baselines: Mutex::new(HashMap::new()),
+            recent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // warm_up replays recently stored samples so the baselines don't start from scratch
+    pub async fn warm_up(&self, pool: &Pool<Sqlite>, now: u32) -> sqlx::Result<()> {
+        if !self.config.enabled || self.config.warmup_days == 0 {
+            return Ok(());
+        }
+        let since = now.saturating_sub(self.config.warmup_days * 86400);
+        let mut rows = sqlx::query_as::<_, (String, i64, i64, i64, f32)>(
+            "SELECT collector_id, received, total_memory, used_memory, average_cpu FROM timeseries
+            WHERE received >= ? ORDER BY received",
+        )
+        .bind(since)
+        .fetch(pool);
+        while let Some((collector_id, received, total_memory, used_memory, average_cpu)) = rows.try_next().await? {
+            self.learn(&Sample {
+                collector_id,
+                received: received as u32,
+                total_memory: total_memory as u64,
+                used_memory: used_memory as u64,
+                average_cpu,
+            });
+        }
+        Ok(())
+    }
+
+    fn learn(&self, sample: &Sample) -> Scores {
+        let mut baselines = self.baselines.lock().unwrap();
+        let mut scores = Vec::new();
+        for metric in METRICS {
+            let value = metric.value(sample);
+            let entry = baselines.entry((sample.collector_id.clone(), metric)).or_default();
+            scores.extend(entry.score(metric, value, sample.received, &self.config));
+            entry.update(value, sample.received, &self.config);
+        }
+        Scores {
+            scores,
+            z_score: self.config.z_score,
+        }
+    }
+
+    // observe scores a freshly ingested sample, records any anomalies and
+    // then adds the sample to the baselines
+    pub fn observe(&self, sample: &Sample) -> Scores {
+        if !self.config.enabled {
+            return Scores::default();
+        }
+        let scores = self.learn(sample);
+        let mut recent = self.recent.lock().unwrap();
+        for score in METRICS.iter().filter_map(|metric| scores.anomalous(*metric, None)) {
+            if recent.len() == RECENT_ANOMALIES {
+                recent.pop_front();
+            }
+            recent.push_back(Anomaly {
+                collector_id: sample.collector_id.clone(),
+                timestamp: sample.received,
+                score: *score,
+            });
+        }
+        scores
+    }
+
+    // recent returns the remembered anomalies, newest first
+    pub fn recent(&self) -> Vec<Anomaly> {
+        self.recent.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(received: u32, average_cpu: f32, used_memory: u64) -> Sample {
+        Sample {
+            collector_id: "c1".to_string(),
+            received,
+            total_memory: 100,
+            used_memory,
+            average_cpu,
+        }
+    }
+
+    #[test]
+    fn test_ewma() {
+        let mut ewma = Ewma::default();
+        for t in 0..1000 {
+            ewma.update(if t % 2 == 0 { 10.0 } else { 20.0 }, t, 60);
+        }
+        assert!((ewma.mean - 15.0).abs() < 1.0);
+        assert!((ewma.variance.sqrt() - 5.0).abs() < 1.0);
+
+        // A long gap means the next sample carries more weight
+        let mut short = ewma;
+        short.update(100.0, 1000, 60);
+        let mut long = ewma;
+        long.update(100.0, 2000, 60);
+        assert!(long.mean > short.mean);
+    }
+
+    #[test]
+    fn test_flags_outliers_after_warmup() {
+        let detector = Detector::new(AnomalyConfig::default());
+        // Nothing is flagged while the baseline is still learning
+        assert!(detector.observe(&sample(0, 90.0, 50)).get(Metric::Cpu).is_none());
+        for t in 1..100 {
+            let scores = detector.observe(&sample(t * 10, 10.0 + (t % 3) as f32, 50));
+            assert!(detector.recent().is_empty(), "flagged normal sample {t}: {scores:?}");
+        }
+
+        let scores = detector.observe(&sample(1000, 95.0, 50));
+        let cpu = scores.get(Metric::Cpu).unwrap();
+        assert_eq!(cpu.baseline, BaselineKind::Rolling);
+        assert!(cpu.z_score > 4.0);
+        // Memory didn't change, and the flat line doesn't make it hypersensitive
+        assert!(scores.get(Metric::Memory).unwrap().z_score.abs() < 1.0);
+        let recent = detector.recent();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].score.metric, Metric::Cpu);
+    }
+
+    #[test]
+    fn test_seasonal_baseline() {
+        let detector = Detector::new(AnomalyConfig::default());
+        // Every day CPU is busy from 02:00 to 03:00 and quiet otherwise
+        for day in 0..3 {
+            for minute in 0..24 * 60 {
+                let t = day * 86400 + minute * 60;
+                let busy = hour_of_day(t) == 2;
+                detector.observe(&sample(t, if busy { 80.0 } else { 5.0 } + (minute % 3) as f32, 50));
+            }
+        }
+        // The nightly job is expected by now; the same load at noon is not
+        let t = 3 * 86400 + 2 * 3600 + 60;
+        let night = detector.observe(&sample(t, 81.0, 50));
+        let night = night.get(Metric::Cpu).unwrap();
+        assert_eq!(night.baseline, BaselineKind::Seasonal);
+        assert!(night.z_score.abs() < 4.0);
+        let noon = detector.observe(&sample(3 * 86400 + 12 * 3600, 81.0, 50));
+        assert!(noon.get(Metric::Cpu).unwrap().z_score > 4.0);
+    }
+}
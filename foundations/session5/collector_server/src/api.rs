@@ -1,12 +1,20 @@
-use axum::extract::Path;
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
 use axum::{Extension, Json};
-use sqlx::FromRow;
-use serde::Serialize;
+use sqlx::{FromRow, QueryBuilder, Row, Sqlite};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use crate::alerts::{AlertRule, Alerts, FiringAlert};
+use crate::anomaly::{Anomaly, Detector};
+use crate::auth::{self, Identity, IssuedToken, Scope, Token};
+use crate::error::{collector_id, ApiError};
+use crate::labels::{self, Labels, Selector};
+use crate::registry::{self, Collector, Thresholds};
 
 // DataPoint is a struct that represents a row in the timeseries table
 #[derive(FromRow, Debug, Serialize)]
 pub struct DataPoint {
-    id: i32,
+    id: i64,
     collector_id: String,
     received: i64,
     total_memory: i64,
@@ -14,41 +22,852 @@ pub struct DataPoint {
     average_cpu: f32,
 }
 
-// show_all is a handler that returns all the rows in the timeseries table as JSON
-pub async fn show_all(Extension(pool): Extension<sqlx::SqlitePool>) -> Json<Vec<DataPoint>> {
-    let rows = sqlx::query_as::<_, DataPoint>("SELECT * FROM timeseries")
+// The id column was declared SERIAL, which SQLite doesn't auto-increment, so
+// the rowid is used as the id instead
+const SELECT_DATA_POINTS: &str = "SELECT rowid AS id, collector_id, received, total_memory, used_memory, average_cpu FROM timeseries";
+
+// show_all is a handler that returns all the rows in the timeseries table the caller may see as JSON
+pub async fn show_all(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+) -> Result<Json<Vec<DataPoint>>, ApiError> {
+    let rows = sqlx::query_as::<_, DataPoint>(&format!(
+        "{SELECT_DATA_POINTS} WHERE ? IS NULL OR collector_id IN (SELECT collector_id FROM collectors WHERE organisation = ?)"
+    ))
+        .bind(identity.organisation())
+        .bind(identity.organisation())
         .fetch_all(&pool)
-        .await
-        .unwrap();
+        .await?;
 
-    Json(rows)
+    Ok(Json(rows))
 }
 
-#[derive(FromRow, Debug, Serialize)]
-pub struct Collector {
-    id: i32,
-    collector_id: String,
-    last_seen: i64,
+// not_found is the error for a collector that doesn't exist or isn't visible
+fn not_found(collector_id: &str) -> ApiError {
+    ApiError::NotFound(format!("collector {collector_id} does not exist"))
 }
 
-pub async fn show_collectors(Extension(pool): Extension<sqlx::SqlitePool>) -> Json<Vec<Collector>> {
-    const SQL: &str = "SELECT 
-    DISTINCT(id) AS id, 
-    collector_id, 
-    (SELECT MAX(received) FROM timeseries WHERE collector_id = ts.collector_id) AS last_seen 
-    FROM timeseries ts";
-    Json(sqlx::query_as::<_, Collector>(SQL)
-        .fetch_all(&pool)
-        .await
-        .unwrap())
+// visible checks the path names a collector in an organisation the caller
+// can see and returns its id. Collectors in other organisations are reported
+// as not found.
+pub async fn visible(pool: &sqlx::SqlitePool, identity: &Identity, uuid: &str) -> Result<String, ApiError> {
+    let collector_id = collector_id(uuid)?;
+    match registry::organisation_of(pool, &collector_id).await? {
+        Some(organisation) if identity.can_see(&organisation) => Ok(collector_id),
+        _ => Err(not_found(&collector_id)),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SelectorQuery {
+    selector: Option<String>,
+}
+
+impl SelectorQuery {
+    fn selector(&self) -> Result<Selector, ApiError> {
+        Selector::parse(self.selector.as_deref().unwrap_or("")).map_err(ApiError::BadRequest)
+    }
+}
+
+// show_collectors returns the collector registry along with each collector's
+// status, only the collectors matching a label selector if one is given
+pub async fn show_collectors(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(thresholds): Extension<Thresholds>,
+    Extension(identity): Extension<Identity>,
+    Query(query): Query<SelectorQuery>,
+) -> Result<Json<Vec<Collector>>, ApiError> {
+    let selector = query.selector()?;
+    let mut collectors = registry::list(&pool, &thresholds, identity.organisation()).await?;
+    collectors.retain(|collector| selector.matches(&collector.labels));
+    Ok(Json(collectors))
+}
+
+pub async fn show_collector(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(thresholds): Extension<Thresholds>,
+    Extension(identity): Extension<Identity>,
+    uuid: Path<String>,
+) -> Result<Json<Collector>, ApiError> {
+    let collector_id = collector_id(&uuid)?;
+    match registry::get(&pool, &thresholds, &collector_id).await? {
+        Some(collector) if identity.can_see(&collector.organisation) => Ok(Json(collector)),
+        _ => Err(not_found(&collector_id)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenameRequest {
+    label: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagRequest {
+    tag: String,
+}
+
+// changed maps the result of a mutation to a response, `missing` being the
+// error for when there was nothing to change
+fn changed(result: sqlx::Result<bool>, missing: impl FnOnce() -> ApiError) -> Result<StatusCode, ApiError> {
+    match result? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(missing()),
+    }
+}
+
+pub async fn rename_collector(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    uuid: Path<String>,
+    Json(request): Json<RenameRequest>,
+) -> Result<StatusCode, ApiError> {
+    let collector_id = visible(&pool, &identity, &uuid).await?;
+    changed(registry::rename(&pool, &collector_id, request.label.as_deref()).await, || not_found(&collector_id))
+}
+
+pub async fn decommission_collector(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    uuid: Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let collector_id = visible(&pool, &identity, &uuid).await?;
+    changed(registry::decommission(&pool, &collector_id).await, || not_found(&collector_id))
+}
+
+pub async fn tag_collector(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    uuid: Path<String>,
+    Json(request): Json<TagRequest>,
+) -> Result<StatusCode, ApiError> {
+    let collector_id = visible(&pool, &identity, &uuid).await?;
+    if !registry::valid_tag(&request.tag) {
+        return Err(ApiError::BadRequest(format!("{:?} is not a valid tag", request.tag)));
+    }
+    changed(registry::add_tag(&pool, &collector_id, &request.tag).await, || not_found(&collector_id))
+}
+
+pub async fn untag_collector(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    Path((uuid, tag)): Path<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    let collector_id = visible(&pool, &identity, &uuid).await?;
+    changed(registry::remove_tag(&pool, &collector_id, &tag).await, || {
+        ApiError::NotFound(format!("collector {collector_id} has no tag {tag:?}"))
+    })
+}
+
+// label_collector sets labels on a collector, e.g. {"role": "db"}
+pub async fn label_collector(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    uuid: Path<String>,
+    Json(labels): Json<Labels>,
+) -> Result<StatusCode, ApiError> {
+    let collector_id = visible(&pool, &identity, &uuid).await?;
+    labels::validate(&labels).map_err(ApiError::BadRequest)?;
+    let mut cnn = pool.acquire().await?;
+    changed(registry::set_labels(&mut cnn, &collector_id, &labels).await, || not_found(&collector_id))
+}
+
+pub async fn unlabel_collector(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    Path((uuid, name)): Path<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    let collector_id = visible(&pool, &identity, &uuid).await?;
+    changed(registry::remove_label(&pool, &collector_id, &name).await, || {
+        ApiError::NotFound(format!("collector {collector_id} has no label {name:?}"))
+    })
+}
+
+// delete_collector removes the collector and every sample it submitted
+pub async fn delete_collector(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    uuid: Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let collector_id = visible(&pool, &identity, &uuid).await?;
+    changed(registry::delete(&pool, &collector_id).await, || not_found(&collector_id))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OrganisationRequest {
+    organisation: String,
 }
 
-pub async fn collector_data(Extension(pool): Extension<sqlx::SqlitePool>, uuid: Path<String>) -> Json<Vec<DataPoint>> {
-    let rows = sqlx::query_as::<_, DataPoint>("SELECT * FROM timeseries WHERE collector_id = ? ORDER BY received")
-        .bind(uuid.as_str())
+// move_collector hands a collector over to another organisation. Only an
+// unrestricted identity may move collectors between organisations.
+pub async fn move_collector(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    uuid: Path<String>,
+    Json(request): Json<OrganisationRequest>,
+) -> Result<StatusCode, ApiError> {
+    let collector_id = visible(&pool, &identity, &uuid).await?;
+    if !identity.can_see(&request.organisation) {
+        return Err(ApiError::Forbidden(format!(
+            "cannot move collectors to organisation {:?}",
+            request.organisation
+        )));
+    }
+    changed(registry::set_organisation(&pool, &collector_id, &request.organisation).await, || not_found(&collector_id))
+}
+
+// Range limits a query to samples received between `from` and `to`, both in
+// seconds since the Unix epoch
+#[derive(Debug, Default, Deserialize)]
+pub struct Range {
+    from: Option<u32>,
+    to: Option<u32>,
+    step: Option<u32>,
+}
+
+const DEFAULT_RANGE: u32 = 3600;
+// Aggregates are bucketed so a chart gets at most this many points
+const MAX_BUCKETS: u32 = 500;
+
+impl Range {
+    // bounds defaults to the last hour
+    fn bounds(&self) -> (u32, u32) {
+        let to = self.to.unwrap_or_else(registry::unix_now);
+        let from = self.from.unwrap_or(to.saturating_sub(DEFAULT_RANGE));
+        (from, to)
+    }
+
+    // step is the bucket width in seconds, widened if needed to stay under MAX_BUCKETS
+    fn step(&self) -> u32 {
+        let (from, to) = self.bounds();
+        let minimum = (to.saturating_sub(from) / MAX_BUCKETS).max(1);
+        self.step.unwrap_or(minimum).max(minimum)
+    }
+}
+
+// collector_data returns a collector's raw samples, all of them unless a range is given
+pub async fn collector_data(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    uuid: Path<String>,
+    Query(range): Query<Range>,
+) -> Result<Json<Vec<DataPoint>>, ApiError> {
+    let collector_id = visible(&pool, &identity, &uuid).await?;
+    let (from, to) = (range.from.unwrap_or(0), range.to.unwrap_or(u32::MAX));
+    let rows = sqlx::query_as::<_, DataPoint>(&format!(
+        "{SELECT_DATA_POINTS} WHERE collector_id = ? AND received BETWEEN ? AND ? ORDER BY received"
+    ))
+        .bind(&collector_id)
+        .bind(from)
+        .bind(to)
         .fetch_all(&pool)
-        .await
-        .unwrap();
+        .await?;
+
+    Ok(Json(rows))
+}
+
+// Aggregate summarises the samples in one time bucket
+#[derive(FromRow, Debug, Serialize)]
+pub struct Aggregate {
+    bucket: i64,
+    samples: i64,
+    avg_cpu: f64,
+    max_cpu: f64,
+    // percent of total memory, None if no sample in the bucket had a total
+    avg_memory: Option<f64>,
+    max_memory: Option<f64>,
+}
+
+// collector_aggregate returns a collector's samples averaged into buckets of `step` seconds
+pub async fn collector_aggregate(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    uuid: Path<String>,
+    Query(range): Query<Range>,
+) -> Result<Json<Vec<Aggregate>>, ApiError> {
+    let collector_id = visible(&pool, &identity, &uuid).await?;
+    let (from, to) = range.bounds();
+    let step = range.step();
+    let rows = sqlx::query_as::<_, Aggregate>(
+        "SELECT
+            (received / ?) * ? AS bucket,
+            COUNT(*) AS samples,
+            AVG(average_cpu) AS avg_cpu,
+            MAX(average_cpu) AS max_cpu,
+            AVG(used_memory * 100.0 / NULLIF(total_memory, 0)) AS avg_memory,
+            MAX(used_memory * 100.0 / NULLIF(total_memory, 0)) AS max_memory
+        FROM timeseries
+        WHERE collector_id = ? AND received BETWEEN ? AND ?
+        GROUP BY bucket
+        ORDER BY bucket",
+    )
+    .bind(step)
+    .bind(step)
+    .bind(&collector_id)
+    .bind(from)
+    .bind(to)
+    .fetch_all(&pool)
+    .await?;
+    Ok(Json(rows))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct GroupQuery {
+    // comma separated label names
+    group_by: Option<String>,
+}
+
+// GroupSeries is the aggregated samples of every collector sharing the same
+// values for the group_by labels. A collector without one of the labels is
+// grouped under an empty value.
+#[derive(Debug, Serialize)]
+pub struct GroupSeries {
+    group: Labels,
+    points: Vec<Aggregate>,
+}
+
+// fleet_aggregate averages the samples of the collectors matching a label
+// selector into buckets of `step` seconds, with one series per group
+pub async fn fleet_aggregate(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    Query(range): Query<Range>,
+    Query(selector): Query<SelectorQuery>,
+    Query(query): Query<GroupQuery>,
+) -> Result<Json<Vec<GroupSeries>>, ApiError> {
+    let selector = selector.selector()?;
+    let group_by: Vec<&str> = match query.group_by.as_deref().map(str::trim) {
+        Some("") | None => Vec::new(),
+        Some(names) => names.split(',').map(str::trim).collect(),
+    };
+    if let Some(name) = group_by.iter().find(|name| !labels::valid_name(name)) {
+        return Err(ApiError::BadRequest(format!("{name:?} is not a valid label name")));
+    }
+    let (from, to) = range.bounds();
+    let step = range.step();
+
+    let mut sql = QueryBuilder::<Sqlite>::new("SELECT ");
+    for i in 0..group_by.len() {
+        sql.push(format!("COALESCE(g{i}.value, '') AS g{i}, "));
+    }
+    sql.push("(t.received / ")
+        .push_bind(step)
+        .push(") * ")
+        .push_bind(step)
+        .push(
+            " AS bucket,
+            COUNT(*) AS samples,
+            AVG(t.average_cpu) AS avg_cpu,
+            MAX(t.average_cpu) AS max_cpu,
+            AVG(t.used_memory * 100.0 / NULLIF(t.total_memory, 0)) AS avg_memory,
+            MAX(t.used_memory * 100.0 / NULLIF(t.total_memory, 0)) AS max_memory
+            FROM timeseries t JOIN collectors c ON c.collector_id = t.collector_id",
+        );
+    for (i, name) in group_by.iter().enumerate() {
+        sql.push(format!(" LEFT JOIN collector_labels g{i} ON g{i}.collector_id = t.collector_id AND g{i}.name = "))
+            .push_bind(*name);
+    }
+    sql.push(" WHERE t.received BETWEEN ")
+        .push_bind(from)
+        .push(" AND ")
+        .push_bind(to)
+        .push(" AND (")
+        .push_bind(identity.organisation())
+        .push(" IS NULL OR c.organisation = ")
+        .push_bind(identity.organisation())
+        .push(") AND ");
+    selector.push_sql(&mut sql, "t.collector_id");
+    let columns: String = (0..group_by.len()).map(|i| format!("g{i}, ")).collect();
+    sql.push(format!(" GROUP BY {columns}bucket ORDER BY {columns}bucket"));
+
+    // Rows come ordered by group, so each group's buckets are next to each other
+    let mut series: Vec<GroupSeries> = Vec::new();
+    for row in sql.build().fetch_all(&pool).await? {
+        let group: Labels = group_by
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.to_string(), row.get::<String, _>(i)))
+            .collect();
+        let point = Aggregate::from_row(&row)?;
+        match series.last_mut() {
+            Some(last) if last.group == group => last.points.push(point),
+            _ => series.push(GroupSeries { group, points: vec![point] }),
+        }
+    }
+    Ok(Json(series))
+}
+
+// firing_alerts returns every rule/collector pair that is currently alerting,
+// for the collectors the caller can see
+pub async fn firing_alerts(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(thresholds): Extension<Thresholds>,
+    Extension(identity): Extension<Identity>,
+    Extension(alerts): Extension<Arc<Alerts>>,
+) -> Result<Json<Vec<FiringAlert>>, ApiError> {
+    let mut firing = alerts.firing();
+    if identity.organisation().is_some() {
+        let collectors = registry::list(&pool, &thresholds, identity.organisation()).await?;
+        firing.retain(|alert| collectors.iter().any(|c| c.collector_id == alert.collector_id));
+    }
+    Ok(Json(firing))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnomalyQuery {
+    collector: Option<String>,
+    since: Option<u32>,
+    limit: Option<usize>,
+}
+
+const DEFAULT_ANOMALIES: usize = 100;
+
+// anomalies returns the most recent anomalous samples, newest first, for the
+// collectors the caller can see
+pub async fn anomalies(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(thresholds): Extension<Thresholds>,
+    Extension(identity): Extension<Identity>,
+    Extension(detector): Extension<Arc<Detector>>,
+    Query(query): Query<AnomalyQuery>,
+) -> Result<Json<Vec<Anomaly>>, ApiError> {
+    let collector = match &query.collector {
+        Some(uuid) => Some(visible(&pool, &identity, uuid).await?),
+        None => None,
+    };
+    let mut anomalies = detector.recent();
+    anomalies.retain(|anomaly| {
+        collector.as_ref().is_none_or(|id| *id == anomaly.collector_id)
+            && anomaly.timestamp >= query.since.unwrap_or(0)
+    });
+    if collector.is_none() && identity.organisation().is_some() {
+        let collectors = registry::list(&pool, &thresholds, identity.organisation()).await?;
+        anomalies.retain(|anomaly| collectors.iter().any(|c| c.collector_id == anomaly.collector_id));
+    }
+    anomalies.truncate(query.limit.unwrap_or(DEFAULT_ANOMALIES));
+    Ok(Json(anomalies))
+}
+
+// alert_rules returns the rules of the caller's organisation
+pub async fn alert_rules(
+    Extension(identity): Extension<Identity>,
+    Extension(alerts): Extension<Arc<Alerts>>,
+) -> Json<Vec<AlertRule>> {
+    Json(alerts.rules(identity.organisation()))
+}
+
+// save_alert_rule creates a rule, or replaces the rule with the same name in
+// the same organisation. A rule goes in the caller's own organisation unless
+// it names another one the caller can see.
+pub async fn save_alert_rule(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    Extension(alerts): Extension<Arc<Alerts>>,
+    Json(mut rule): Json<AlertRule>,
+) -> Result<Json<AlertRule>, ApiError> {
+    if rule.organisation.is_empty() {
+        rule.organisation = identity.owning_organisation().to_string();
+    }
+    if !identity.can_see(&rule.organisation) {
+        return Err(ApiError::Forbidden(format!("cannot add alert rules to organisation {:?}", rule.organisation)));
+    }
+    alerts.save_rule(&pool, rule.clone()).await?;
+    Ok(Json(rule))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RuleQuery {
+    // defaults to the caller's own organisation
+    organisation: Option<String>,
+}
+
+pub async fn delete_alert_rule(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    Extension(alerts): Extension<Arc<Alerts>>,
+    name: Path<String>,
+    Query(query): Query<RuleQuery>,
+) -> Result<StatusCode, ApiError> {
+    let organisation = query
+        .organisation
+        .unwrap_or_else(|| identity.owning_organisation().to_string());
+    if !identity.can_see(&organisation) {
+        return Err(ApiError::Forbidden(format!("cannot delete alert rules of organisation {organisation:?}")));
+    }
+    match alerts.delete_rule(&pool, &organisation, name.as_str()).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(ApiError::NotFound(format!("alert rule {:?} does not exist", name.as_str()))),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    name: String,
+    scope: Scope,
+    // defaults to the caller's own organisation
+    organisation: Option<String>,
+}
+
+// issue_token creates a token. An admin can only issue tokens for its own
+// organisation, and operator tokens are only issued from the command line.
+// The secret is in the response and can't be retrieved again.
+pub async fn issue_token(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    Json(request): Json<TokenRequest>,
+) -> Result<Json<IssuedToken>, ApiError> {
+    let organisation = request
+        .organisation
+        .unwrap_or_else(|| identity.owning_organisation().to_string());
+    if !identity.can_see(&organisation) {
+        return Err(ApiError::Forbidden(format!("cannot issue tokens for organisation {organisation:?}")));
+    }
+    Ok(Json(auth::issue(&pool, &request.name, Some(&organisation), request.scope).await?))
+}
+
+pub async fn list_tokens(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+) -> Result<Json<Vec<Token>>, ApiError> {
+    Ok(Json(auth::list(&pool, identity.organisation()).await?))
+}
+
+pub async fn revoke_token(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    token_id: Path<i64>,
+) -> Result<StatusCode, ApiError> {
+    changed(auth::revoke(&pool, identity.organisation(), *token_id).await, || {
+        ApiError::NotFound(format!("token {} does not exist", *token_id))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use crate::config::AuthConfig;
+    use axum::routing::{delete, get, post};
+    use axum::{middleware, Router};
+
+    #[tokio::test]
+    async fn test_tokens_and_tenants() {
+        let pool = testing::memory_pool().await;
+        let (ours, theirs) = ("00000000-0000-0000-0000-00000000000a", "00000000-0000-0000-0000-00000000000b");
+        let mut cnn = pool.acquire().await.unwrap();
+        registry::record_sample(&mut cnn, ours, "acme", 1, 100).await.unwrap();
+        registry::record_sample(&mut cnn, theirs, "other", 1, 100).await.unwrap();
+        drop(cnn);
+        let reader = auth::issue(&pool, "reader", Some("acme"), Scope::Read).await.unwrap().token;
+        let writer = auth::issue(&pool, "writer", Some("acme"), Scope::Write).await.unwrap().token;
+        let administrator = auth::issue(&pool, "admin", Some("acme"), Scope::Admin).await.unwrap().token;
+        let operator = auth::issue(&pool, "operator", None, Scope::Admin).await.unwrap().token;
+
+        let read = Router::new()
+            .route("/api/collectors", get(show_collectors))
+            .route("/api/collectors/{uuid}", get(show_collector))
+            .route_layer(middleware::from_fn_with_state(Scope::Read, auth::require));
+        let write = Router::new()
+            .route("/api/collectors/{uuid}", delete(delete_collector))
+            .route_layer(middleware::from_fn_with_state(Scope::Write, auth::require));
+        let admin = Router::new()
+            .route("/api/collectors/{uuid}/organisation", post(move_collector))
+            .route_layer(middleware::from_fn_with_state(Scope::Admin, auth::require));
+        let app = read
+            .merge(write)
+            .merge(admin)
+            .layer(Extension(Thresholds::default()))
+            .layer(Extension(AuthConfig { enabled: true }))
+            .layer(Extension(pool.clone()));
+        let addr = testing::serve(app).await;
+
+        let client = reqwest::Client::new();
+        let url = |path: &str| format!("http://{addr}{path}");
+        let response = client.get(url("/api/collectors")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Only the caller's own organisation is visible
+        let collectors: Vec<serde_json::Value> = client
+            .get(url("/api/collectors"))
+            .bearer_auth(&reader)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(collectors.len(), 1);
+        assert_eq!(collectors[0]["collector_id"], ours);
+        let response = client.get(url(&format!("/api/collectors/{theirs}"))).bearer_auth(&reader).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // An organisation's admin can't give its collectors away, but an operator can
+        let move_to = |token: &str, collector_id: &str, organisation: &str| {
+            client
+                .post(url(&format!("/api/collectors/{collector_id}/organisation")))
+                .bearer_auth(token)
+                .json(&serde_json::json!({ "organisation": organisation }))
+                .send()
+        };
+        let status = move_to(&administrator, ours, "other").await.unwrap().status();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let status = move_to(&administrator, theirs, "acme").await.unwrap().status();
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(registry::organisation_of(&pool, ours).await.unwrap().unwrap(), "acme");
+        let status = move_to(&operator, theirs, "acme").await.unwrap().status();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let response = client.get(url(&format!("/api/collectors/{theirs}"))).bearer_auth(&reader).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let status = move_to(&operator, theirs, "other").await.unwrap().status();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let response = client.delete(url(&format!("/api/collectors/{ours}"))).bearer_auth(&reader).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = client.delete(url(&format!("/api/collectors/{theirs}"))).bearer_auth(&writer).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = client.delete(url(&format!("/api/collectors/{ours}"))).bearer_auth(&writer).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn test_error_responses() {
+        let pool = testing::memory_pool().await;
+        let known = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        let mut cnn = pool.acquire().await.unwrap();
+        registry::record_sample(&mut cnn, known, "default", 1, 100).await.unwrap();
+        sqlx::query("INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES (?, 100, 0, 0, 5)")
+            .bind(known)
+            .execute(&mut *cnn)
+            .await
+            .unwrap();
+        drop(cnn);
+
+        let app = Router::new()
+            .route("/api/collectors", get(show_collectors))
+            .route("/api/collectors/{uuid}", get(show_collector))
+            .route("/api/collector/{uuid}/aggregate", get(collector_aggregate))
+            .route("/api/collectors/{uuid}/tags", post(tag_collector))
+            .layer(Extension(Thresholds::default()))
+            .layer(Extension(Identity::unrestricted()))
+            .layer(Extension(pool.clone()));
+        let addr = testing::serve(app).await;
+
+        let get = |path: String| async move {
+            let response = reqwest::get(format!("http://{addr}{path}")).await.unwrap();
+            let status = response.status();
+            let content_type = response.headers()[reqwest::header::CONTENT_TYPE].to_str().unwrap().to_string();
+            let body: serde_json::Value = response.json().await.unwrap();
+            (status, content_type, body)
+        };
+
+        let (status, content_type, body) = get("/api/collectors/not-a-uuid".to_string()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(content_type, "application/problem+json");
+        assert_eq!(body["status"], 400);
+        assert_eq!(body["title"], "Bad Request");
+
+        // Any UUID spelling finds the collector
+        let (status, _, body) = get(format!("/api/collectors/{}", known.to_uppercase())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["collector_id"], known);
+
+        // A sample without a total memory has no memory percentage
+        let (status, _, body) = get(format!("/api/collector/{known}/aggregate?from=0&to=200")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["avg_cpu"], 5.0);
+        assert!(body[0]["avg_memory"].is_null());
+
+        // A tag with a comma would read back as two tags
+        let tag = |tag: &'static str| async move {
+            let response = reqwest::Client::new()
+                .post(format!("http://{addr}/api/collectors/{known}/tags"))
+                .json(&serde_json::json!({ "tag": tag }))
+                .send()
+                .await
+                .unwrap();
+            response.status()
+        };
+        assert_eq!(tag("a,b").await, StatusCode::BAD_REQUEST);
+        assert_eq!(tag("").await, StatusCode::BAD_REQUEST);
+        assert_eq!(tag("prod").await, StatusCode::NO_CONTENT);
+
+        let unknown = "00000000-0000-0000-0000-000000000001";
+        let (status, _, body) = get(format!("/api/collectors/{unknown}")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body["detail"].as_str().unwrap().contains(unknown));
+        let (status, _, _) = get(format!("/api/collector/{unknown}/aggregate")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        pool.close().await;
+        let (status, _, body) = get("/api/collectors".to_string()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], 503);
+    }
+
+    #[tokio::test]
+    async fn test_anomalies() {
+        use crate::alerts::{AlertCondition, Metric};
+        use crate::collector::Sample;
+
+        let pool = testing::memory_pool().await;
+        let pipeline = testing::pipeline(&pool).await;
+        let (alerts, detector) = (pipeline.alerts.clone(), pipeline.detector.clone());
+        let rule = AlertRule {
+            organisation: "acme".to_string(),
+            name: "cpu anomaly".to_string(),
+            condition: AlertCondition::Anomaly { metric: Metric::Cpu, z_score: None },
+            repeat_seconds: 3600,
+        };
+        alerts.save_rule(&pool, rule).await.unwrap();
+        let (ours, theirs) = ("00000000-0000-0000-0000-00000000000a", "00000000-0000-0000-0000-00000000000b");
+        for (collector_id, organisation) in [(ours, "acme"), (theirs, "other")] {
+            for t in 0..=100 {
+                let sample = Sample {
+                    collector_id: collector_id.to_string(),
+                    received: t * 10,
+                    total_memory: 100,
+                    used_memory: 50,
+                    average_cpu: if t == 100 { 99.0 } else { 10.0 + (t % 2) as f32 },
+                };
+                pipeline.ingest(sample, 1, organisation).await.unwrap();
+            }
+        }
+        // The rule is only checked against collectors in its own organisation
+        let firing = alerts.firing();
+        assert_eq!(firing.len(), 1);
+        assert_eq!(firing[0].collector_id, ours);
+
+        let read = Router::new()
+            .route("/api/anomalies", get(anomalies))
+            .route("/api/alerts/rules", get(alert_rules))
+            .route_layer(middleware::from_fn_with_state(Scope::Read, auth::require));
+        let admin = Router::new()
+            .route("/api/alerts/rules", post(save_alert_rule))
+            .route("/api/alerts/rules/{name}", delete(delete_alert_rule))
+            .route_layer(middleware::from_fn_with_state(Scope::Admin, auth::require));
+        let app = read
+            .merge(admin)
+            .layer(Extension(alerts.clone()))
+            .layer(Extension(detector))
+            .layer(Extension(Thresholds::default()))
+            .layer(Extension(AuthConfig { enabled: true }))
+            .layer(Extension(pool.clone()));
+        let addr = testing::serve(app).await;
+        let reader = auth::issue(&pool, "reader", Some("acme"), Scope::Read).await.unwrap().token;
+
+        // Only anomalies of the caller's own collectors are listed
+        let client = reqwest::Client::new();
+        let get = |query: String| client.get(format!("http://{addr}/api/anomalies{query}")).bearer_auth(&reader).send();
+        let anomalies: Vec<serde_json::Value> = get(String::new()).await.unwrap().json().await.unwrap();
+        assert_eq!(anomalies.len(), 1);
+        assert_eq!(anomalies[0]["collector_id"], ours);
+        assert_eq!(anomalies[0]["metric"], "cpu");
+        assert_eq!(anomalies[0]["baseline"], "rolling");
+        let anomalies: Vec<serde_json::Value> = get("?since=1001".to_string()).await.unwrap().json().await.unwrap();
+        assert!(anomalies.is_empty());
+        let response = get(format!("?collector={theirs}")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Rules are listed, saved and deleted within the caller's organisation
+        let admin = auth::issue(&pool, "admin", Some("acme"), Scope::Admin).await.unwrap().token;
+        let url = format!("http://{addr}/api/alerts/rules");
+        let rule = serde_json::json!({"name": "cpu anomaly", "condition": {"type": "threshold", "metric": "cpu", "above": 90.0}, "repeat_seconds": 60});
+        let response = client.post(&url).bearer_auth(&admin).json(&rule).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut foreign = rule.clone();
+        foreign["organisation"] = "other".into();
+        let response = client.post(&url).bearer_auth(&admin).json(&foreign).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        alerts.save_rule(&pool, serde_json::from_value(foreign).unwrap()).await.unwrap();
+        let rules: Vec<serde_json::Value> = client.get(&url).bearer_auth(&reader).send().await.unwrap().json().await.unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0]["organisation"], "acme");
+        assert_eq!(rules[0]["condition"]["type"], "threshold");
+        let response = client.delete(format!("{url}/cpu%20anomaly?organisation=other")).bearer_auth(&admin).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = client.delete(format!("{url}/cpu%20anomaly")).bearer_auth(&admin).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(alerts.rules(None).len(), 1);
+        assert_eq!(alerts.rules(Some("other")).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_label_selectors_and_groups() {
+        let pool = testing::memory_pool().await;
+        let hosts = [
+            ("00000000-0000-0000-0000-000000000001", "db", "eu", 10.0),
+            ("00000000-0000-0000-0000-000000000002", "db", "us", 30.0),
+            ("00000000-0000-0000-0000-000000000003", "web", "eu", 90.0),
+        ];
+        let mut cnn = pool.acquire().await.unwrap();
+        for (collector_id, role, region, cpu) in hosts {
+            for received in [100, 160] {
+                sqlx::query(
+                    "INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES (?, ?, 100, 50, ?)",
+                )
+                .bind(collector_id)
+                .bind(received)
+                .bind(cpu)
+                .execute(&mut *cnn)
+                .await
+                .unwrap();
+                registry::record_sample(&mut cnn, collector_id, "default", 1, received).await.unwrap();
+            }
+            let labels: Labels = [("role".to_string(), role.to_string()), ("region".to_string(), region.to_string())].into();
+            registry::set_labels(&mut cnn, collector_id, &labels).await.unwrap();
+        }
+        drop(cnn);
+
+        let app = Router::new()
+            .route("/api/collectors", get(show_collectors))
+            .route("/api/aggregate", get(fleet_aggregate))
+            .route("/api/collectors/{uuid}/labels", axum::routing::post(label_collector))
+            .layer(Extension(Thresholds::default()))
+            .layer(Extension(Identity::unrestricted()))
+            .layer(Extension(pool));
+        let addr = testing::serve(app).await;
+        let get = |path: &str| {
+            let url = format!("http://{addr}{path}");
+            async move { reqwest::get(url).await.unwrap().json::<serde_json::Value>().await.unwrap() }
+        };
+
+        let collectors = get("/api/collectors?selector=role%3Ddb,region!%3Dus").await;
+        assert_eq!(collectors.as_array().unwrap().len(), 1);
+        assert_eq!(collectors[0]["collector_id"], hosts[0].0);
+        assert_eq!(collectors[0]["labels"]["region"], "eu");
+
+        // Average CPU of the database hosts, per region
+        let series = get("/api/aggregate?selector=role%3Ddb&group_by=region&from=0&to=200&step=1000").await;
+        assert_eq!(series.as_array().unwrap().len(), 2);
+        assert_eq!(series[0]["group"]["region"], "eu");
+        assert_eq!(series[0]["points"][0]["avg_cpu"], 10.0);
+        assert_eq!(series[1]["group"]["region"], "us");
+        assert_eq!(series[1]["points"][0]["samples"], 2);
+
+        // Without grouping the whole selection is one series
+        let series = get("/api/aggregate?selector=region%3Deu&from=0&to=200&step=1000").await;
+        assert_eq!(series.as_array().unwrap().len(), 1);
+        assert_eq!(series[0]["points"][0]["avg_cpu"], 50.0);
+
+        let response = reqwest::Client::new()
+            .post(format!("http://{addr}/api/collectors/{}/labels", hosts[0].0))
+            .json(&serde_json::json!({"not a name": "x"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = get("/api/aggregate?group_by=1st").await;
+        assert_eq!(body["status"], 400);
+    }
 
-    Json(rows)
+    #[test]
+    fn test_range_step() {
+        let range = Range { from: Some(0), to: Some(86400), step: None };
+        assert_eq!(range.bounds(), (0, 86400));
+        assert_eq!(range.step(), 172);
+        // A step that would produce too many buckets is widened
+        let range = Range { from: Some(0), to: Some(86400), step: Some(1) };
+        assert_eq!(range.step(), 172);
+        let range = Range { from: Some(0), to: Some(60), step: Some(10) };
+        assert_eq!(range.step(), 10);
+    }
 }
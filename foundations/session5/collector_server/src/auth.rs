@@ -0,0 +1,248 @@
+use crate::config::AuthConfig;
+use crate::error::ApiError;
+use axum::extract::{Request, State};
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Extension;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, Pool, Sqlite};
+
+// Collectors that report over the ingest protocol, and everything stored
+// before organisations existed, belong to this organisation until an operator
+// moves them
+pub const DEFAULT_ORGANISATION: &str = "default";
+
+// Scope is what a token may do. Each scope includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,  // look at collectors, samples and alerts
+    Write, // submit samples and change collectors
+    Admin, // manage tokens, alert rules and which organisation a collector is in
+}
+
+impl Scope {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Admin => "admin",
+        }
+    }
+
+    fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "read" => Some(Scope::Read),
+            "write" => Some(Scope::Write),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+// Identity is who made a request, as established by the middleware
+#[derive(Debug, Clone)]
+pub struct Identity {
+    // None for an operator token, or when authentication is disabled, and
+    // every organisation is visible
+    organisation: Option<String>,
+    scope: Scope,
+}
+
+impl Identity {
+    pub fn unrestricted() -> Self {
+        Self {
+            organisation: None,
+            scope: Scope::Admin,
+        }
+    }
+
+    pub fn organisation(&self) -> Option<&str> {
+        self.organisation.as_deref()
+    }
+
+    // owning_organisation is where collectors created by this identity go
+    pub fn owning_organisation(&self) -> &str {
+        self.organisation().unwrap_or(DEFAULT_ORGANISATION)
+    }
+
+    // can_see says whether a collector in `organisation` is visible
+    pub fn can_see(&self, organisation: &str) -> bool {
+        self.organisation().is_none_or(|own| own == organisation)
+    }
+}
+
+// Token is an issued token as listed by the admin API, without its secret
+#[derive(FromRow, Debug, Serialize)]
+pub struct Token {
+    pub token_id: i64,
+    pub name: String,
+    // None for an operator token
+    pub organisation: Option<String>,
+    pub scope: String,
+    pub created: i64,
+    pub revoked: bool,
+}
+
+// IssuedToken is returned once, when the token is created
+#[derive(Debug, Serialize)]
+pub struct IssuedToken {
+    pub token_id: i64,
+    pub token: String,
+}
+
+// Tokens are random, so a plain hash is enough to keep them out of the database
+fn hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// issue creates a token for an organisation, or an operator token for None
+pub async fn issue(pool: &Pool<Sqlite>, name: &str, organisation: Option<&str>, scope: Scope) -> sqlx::Result<IssuedToken> {
+    let token = format!(
+        "cs_{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    );
+    let result = sqlx::query(
+        "INSERT INTO api_tokens (token_hash, name, organisation, scope, created) VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(hash(&token))
+    .bind(name)
+    .bind(organisation)
+    .bind(scope.as_str())
+    .bind(crate::registry::unix_now())
+    .execute(pool)
+    .await?;
+    Ok(IssuedToken {
+        token_id: result.last_insert_rowid(),
+        token,
+    })
+}
+
+// list returns the tokens of an organisation, or every token for None
+pub async fn list(pool: &Pool<Sqlite>, organisation: Option<&str>) -> sqlx::Result<Vec<Token>> {
+    sqlx::query_as::<_, Token>(
+        "SELECT token_id, name, organisation, scope, created, revoked FROM api_tokens
+        WHERE ? IS NULL OR organisation = ?
+        ORDER BY token_id",
+    )
+    .bind(organisation)
+    .bind(organisation)
+    .fetch_all(pool)
+    .await
+}
+
+// revoke returns false if the token doesn't exist in the organisation
+pub async fn revoke(pool: &Pool<Sqlite>, organisation: Option<&str>, token_id: i64) -> sqlx::Result<bool> {
+    let result = sqlx::query("UPDATE api_tokens SET revoked = TRUE WHERE token_id = ? AND (? IS NULL OR organisation = ?)")
+        .bind(token_id)
+        .bind(organisation)
+        .bind(organisation)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// authenticate looks up a token that hasn't been revoked
+pub async fn authenticate(pool: &Pool<Sqlite>, token: &str) -> sqlx::Result<Option<Identity>> {
+    let row: Option<(Option<String>, String)> =
+        sqlx::query_as("SELECT organisation, scope FROM api_tokens WHERE token_hash = ? AND NOT revoked")
+            .bind(hash(token))
+            .fetch_optional(pool)
+            .await?;
+    Ok(row.and_then(|(organisation, scope)| {
+        Some(Identity {
+            organisation,
+            scope: Scope::parse(&scope)?,
+        })
+    }))
+}
+
+// bearer_token finds the token in the Authorization header, or in the
+// access_token query parameter for clients such as EventSource that can't set headers
+fn bearer_token(request: &Request) -> Option<&str> {
+    let header = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    header.or_else(|| {
+        request
+            .uri()
+            .query()?
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("access_token="))
+    })
+}
+
+// require is the middleware in front of every API route. It checks the token
+// has at least `scope` and makes the caller's Identity available to the handler.
+pub async fn require(
+    State(scope): State<Scope>,
+    Extension(config): Extension<AuthConfig>,
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if !config.enabled {
+        request.extensions_mut().insert(Identity::unrestricted());
+        return Ok(next.run(request).await);
+    }
+
+    let identity = match bearer_token(&request) {
+        Some(token) => authenticate(&pool, token).await?,
+        None => None,
+    };
+    match identity {
+        None => Err(ApiError::Unauthorized),
+        Some(identity) if identity.scope < scope => Err(ApiError::Forbidden(format!(
+            "this requires a token with the {} scope",
+            scope.as_str()
+        ))),
+        Some(identity) => {
+            request.extensions_mut().insert(identity);
+            Ok(next.run(request).await)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn test_issue_and_revoke() {
+        let pool = testing::memory_pool().await;
+
+        let issued = issue(&pool, "grafana", Some("acme"), Scope::Read).await.unwrap();
+        let identity = authenticate(&pool, &issued.token).await.unwrap().unwrap();
+        assert_eq!(identity.organisation(), Some("acme"));
+        assert_eq!(identity.scope, Scope::Read);
+        assert!(identity.can_see("acme") && !identity.can_see("default"));
+        assert!(authenticate(&pool, "cs_wrong").await.unwrap().is_none());
+
+        // Another organisation can neither see nor revoke the token
+        assert!(list(&pool, Some("other")).await.unwrap().is_empty());
+        assert!(!revoke(&pool, Some("other"), issued.token_id).await.unwrap());
+        assert!(revoke(&pool, Some("acme"), issued.token_id).await.unwrap());
+        assert!(authenticate(&pool, &issued.token).await.unwrap().is_none());
+        assert!(list(&pool, None).await.unwrap()[0].revoked);
+
+        // An operator token sees every organisation, and organisations don't see it
+        let operator = issue(&pool, "operator", None, Scope::Admin).await.unwrap();
+        let identity = authenticate(&pool, &operator.token).await.unwrap().unwrap();
+        assert_eq!(identity.organisation(), None);
+        assert!(identity.can_see("acme") && identity.can_see("default"));
+        assert!(list(&pool, Some("acme")).await.unwrap().iter().all(|token| token.token_id != operator.token_id));
+        assert!(!revoke(&pool, Some("acme"), operator.token_id).await.unwrap());
+    }
+
+    #[test]
+    fn test_scopes_include_lower_ones() {
+        assert!(Scope::Admin > Scope::Write && Scope::Write > Scope::Read);
+        assert_eq!(Scope::parse(Scope::Write.as_str()), Some(Scope::Write));
+    }
+}
@@ -1,63 +1,606 @@
 use sqlx::{Pool, Sqlite};
 use tokio::net::{TcpListener, TcpStream};
-use shared_data::{decode_v1, encode_response_v1, CollectorCommandV1, CollectorResponseV1, DATA_COLLECTOR_ADDRESS};
+use shared_data::{
+    encode_response_for, frame_size, frame_version, try_decode_v1, CollectorCommandV1, CollectorResponseV1, DecodeError,
+    ErrorCode, HEADER_SIZE, MIN_VERSION_NUMBER,
+};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use std::net::SocketAddr;
+use tokio::time::timeout;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::alerts::Alerts;
+use crate::anomaly::Detector;
+use crate::auth::DEFAULT_ORGANISATION;
+use crate::labels::{self, Labels};
+use crate::registry;
+use crate::stream::SampleSender;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 
+// Sample is a single decoded submission, as it was stored in the timeseries table
+#[derive(Debug, Clone, Serialize)]
+pub struct Sample {
+    pub collector_id: String,
+    pub received: u32,
+    pub total_memory: u64,
+    pub used_memory: u64,
+    pub average_cpu: f32,
+}
+
+// Pipeline is everything that happens to a sample once it has been decoded,
+// whichever protocol it arrived through
+#[derive(Clone)]
+pub struct Pipeline {
+    pub cnn: Pool<Sqlite>,
+    pub alerts: Arc<Alerts>,
+    pub detector: Arc<Detector>,
+    pub samples: SampleSender,
+}
+
+impl Pipeline {
+    // ingest stores the sample, scores it against the anomaly baselines,
+    // checks it against the alert rules and publishes it to live subscribers.
+    // `version` is the protocol version the sample was submitted with, and a
+    // collector seen for the first time is registered in `organisation`.
+    pub async fn ingest(&self, sample: Sample, version: u16, organisation: &str) -> sqlx::Result<()> {
+        let organisation = store_sample(&self.cnn, &sample, version, organisation).await?;
+        let scores = self.detector.observe(&sample);
+        self.alerts.evaluate(&sample, &organisation, &scores);
+        let _ = self.samples.send(sample); // Only fails when nobody is listening
+        Ok(())
+    }
+}
+
+// Limits protect the ingest listener from too many, or misbehaving, collectors
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    // connections open at once, from all collectors together
+    pub max_connections: usize,
+    // connections open at once from a single IP address
+    pub max_connections_per_ip: usize,
+    // largest frame accepted, header and CRC included
+    pub max_frame_bytes: usize,
+    // how long the rest of a frame may take to arrive once it has started,
+    // and how long a response may take to send
+    pub read_timeout_seconds: u64,
+    // how long a connection may sit idle between frames
+    pub idle_timeout_seconds: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            max_connections_per_ip: 32,
+            max_frame_bytes: 1024,
+            read_timeout_seconds: 10,
+            idle_timeout_seconds: 120,
+        }
+    }
+}
+
+impl Limits {
+    fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout_seconds)
+    }
+
+    fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_seconds)
+    }
+}
+
+// OpenConnections counts the connections being served, in total and per address
+#[derive(Default)]
+struct OpenConnections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+// ConnectionSlot is held for as long as a connection is open and gives its
+// place back when dropped
+struct ConnectionSlot {
+    open: Arc<Mutex<OpenConnections>>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        open.total -= 1;
+        if let Some(count) = open.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+// admit takes a connection slot for the address, or says which limit it would break
+fn admit(open: &Arc<Mutex<OpenConnections>>, ip: IpAddr, limits: &Limits) -> Result<ConnectionSlot, ErrorCode> {
+    let mut connections = open.lock().unwrap();
+    if connections.total >= limits.max_connections {
+        return Err(ErrorCode::TooManyConnections);
+    }
+    let from_ip = connections.per_ip.entry(ip).or_default();
+    if *from_ip >= limits.max_connections_per_ip {
+        return Err(ErrorCode::TooManyConnectionsFromAddress);
+    }
+    *from_ip += 1;
+    connections.total += 1;
+    Ok(ConnectionSlot { open: open.clone(), ip })
+}
 
+// How long to wait before accepting again when the process is out of file
+// descriptors or memory, to give open connections a chance to close
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+// AcceptFailure is how bad an error from accept is
+#[derive(Debug, PartialEq)]
+enum AcceptFailure {
+    // the connection was lost before it was accepted; accept the next one
+    Connection,
+    // the process or system is short of something, such as file descriptors
+    // (EMFILE, ENFILE) or buffers; wait a little and try again
+    Resources,
+    // the listening socket itself is broken
+    Fatal,
+}
+
+fn accept_failure(e: &std::io::Error) -> AcceptFailure {
+    match e.kind() {
+        ErrorKind::ConnectionAborted
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionRefused
+        | ErrorKind::Interrupted
+        | ErrorKind::WouldBlock
+        | ErrorKind::TimedOut => AcceptFailure::Connection,
+        ErrorKind::InvalidInput | ErrorKind::NotConnected | ErrorKind::Unsupported => AcceptFailure::Fatal,
+        _ => AcceptFailure::Resources,
+    }
+}
 
-pub async fn data_collector(cnn: Pool<Sqlite>) -> anyhow::Result<()> {
+pub async fn data_collector(address: String, pipeline: Pipeline, limits: Limits, shutdown: CancellationToken) -> anyhow::Result<()> {
     // Listen for TCP connections on the data collector address
-    let listener = TcpListener::bind(DATA_COLLECTOR_ADDRESS).await?;
+    let listener = TcpListener::bind(&address).await?;
+    serve(listener, pipeline, limits, shutdown).await
+}
+
+async fn serve(listener: TcpListener, pipeline: Pipeline, limits: Limits, shutdown: CancellationToken) -> anyhow::Result<()> {
+    let connections = TaskTracker::new();
+    let open = Arc::new(Mutex::new(OpenConnections::default()));
 
-    // Loop forever, accepting connections
+    // Loop until shutdown, accepting connections
     loop {
         // Wait for a new connection
-        let cnn = cnn.clone();
-        let (socket, address) = listener.accept().await?;
-        tokio::spawn(new_connection(socket, address, cnn));
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.cancelled() => break,
+        };
+        let (mut socket, address) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => match accept_failure(&e) {
+                AcceptFailure::Connection => continue,
+                AcceptFailure::Resources => {
+                    eprintln!("Failed to accept an ingest connection, retrying in {ACCEPT_BACKOFF:?}: {e}");
+                    tokio::select! {
+                        _ = tokio::time::sleep(ACCEPT_BACKOFF) => continue,
+                        _ = shutdown.cancelled() => break,
+                    }
+                }
+                AcceptFailure::Fatal => return Err(e.into()),
+            },
+        };
+        match admit(&open, address.ip(), &limits) {
+            Ok(slot) => {
+                let pipeline = pipeline.clone();
+                connections.spawn(new_connection(socket, address, pipeline, limits, slot, shutdown.clone()));
+            }
+            Err(code) => {
+                eprintln!("Refusing connection from {address:?}: {code:?}");
+                // Tell the collector why without holding up the accept loop, if
+                // its first frame shows it is new enough to understand
+                connections.spawn(async move {
+                    if let Some(version) = peer_version(&mut socket, &limits).await {
+                        respond(&mut socket, CollectorResponseV1::Error(code), version, &limits).await;
+                    }
+                });
+            }
+        }
+    }
+
+    // Stop accepting, then let open connections finish the sample they are on
+    drop(listener);
+    connections.close();
+    println!("Draining {} ingest connections", connections.len());
+    connections.wait().await;
+    Ok(())
+}
+
+// FrameError is why a frame could not be read off a connection
+#[derive(Debug)]
+enum FrameError {
+    Io(std::io::Error),
+    TooLarge(usize),
+    Invalid(DecodeError),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::Io(e) => write!(f, "{e}"),
+            FrameError::TooLarge(size) => write!(f, "frame of {size} bytes is over the limit"),
+            FrameError::Invalid(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl FrameError {
+    fn code(&self) -> Option<ErrorCode> {
+        match self {
+            FrameError::Io(_) => None, // The connection is gone, there is nobody to tell
+            FrameError::TooLarge(_) => Some(ErrorCode::FrameTooLarge),
+            FrameError::Invalid(_) => Some(ErrorCode::InvalidFrame),
+        }
     }
 }
 
-async fn new_connection(mut socket: TcpStream, address: SocketAddr, cnn: Pool<Sqlite>) {
+// peer_version reads the header of the first frame on a connection to find
+// out which protocol version the collector speaks
+async fn peer_version(socket: &mut TcpStream, limits: &Limits) -> Option<u16> {
+    let mut header = [0u8; HEADER_SIZE];
+    timeout(limits.read_timeout(), socket.read_exact(&mut header)).await.ok()?.ok()?;
+    frame_size(&header).ok()?;
+    Some(frame_version(&header))
+}
+
+// read_frame reads the rest of a frame whose first byte has arrived. The size
+// in the header is checked against the limit before the frame is allocated.
+// Once the header is known good, `version` is set to the version it gives.
+async fn read_frame(socket: &mut TcpStream, first: u8, max_frame_bytes: usize, version: &mut u16) -> Result<Vec<u8>, FrameError> {
+    let mut header = [0u8; HEADER_SIZE];
+    header[0] = first;
+    socket.read_exact(&mut header[1..]).await.map_err(FrameError::Io)?;
+    let size = frame_size(&header).map_err(FrameError::Invalid)?;
+    *version = frame_version(&header);
+    if size > max_frame_bytes {
+        return Err(FrameError::TooLarge(size));
+    }
+    let mut frame = vec![0u8; size];
+    frame[..HEADER_SIZE].copy_from_slice(&header);
+    socket.read_exact(&mut frame[HEADER_SIZE..]).await.map_err(FrameError::Io)?;
+    Ok(frame)
+}
+
+// respond sends a response, giving up on a collector that doesn't read it.
+// A response that is too new for the collector's protocol `version` isn't
+// sent at all. It returns false if the connection should be closed.
+async fn respond(socket: &mut TcpStream, response: CollectorResponseV1, version: u16, limits: &Limits) -> bool {
+    let Some(bytes) = encode_response_for(response, version) else {
+        return false;
+    };
+    matches!(timeout(limits.read_timeout(), socket.write_all(&bytes)).await, Ok(Ok(())))
+}
+
+async fn new_connection(
+    mut socket: TcpStream,
+    address: SocketAddr,
+    pipeline: Pipeline,
+    limits: Limits,
+    _slot: ConnectionSlot,
+    shutdown: CancellationToken,
+) {
     println!("New connection from {address:?}");
-    let mut buf = vec![0u8; 1024];
+    // Until a frame says otherwise, the collector may only speak the oldest version
+    let mut version = MIN_VERSION_NUMBER;
     loop {
-        let n = socket
-            .read(&mut buf)
-            .await
-            .expect("failed to read data from socket");
-
-        if n == 0 {
-            println!("No data received - connection closed");
-            return;
-        }
+        // Only stop between samples, so one that is being read is still stored and acknowledged
+        let first = tokio::select! {
+            first = timeout(limits.idle_timeout(), socket.read_u8()) => first,
+            _ = shutdown.cancelled() => {
+                println!("Closing connection from {address:?} for shutdown");
+                return;
+            }
+        };
+        let first = match first {
+            Ok(Ok(byte)) => byte,
+            Ok(Err(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                println!("No data received - connection closed");
+                return;
+            }
+            Ok(Err(e)) => {
+                eprintln!("Failed to read from {address:?}: {e}");
+                return;
+            }
+            Err(_) => {
+                eprintln!("Closing idle connection from {address:?}");
+                respond(&mut socket, CollectorResponseV1::Error(ErrorCode::IdleTimeout), version, &limits).await;
+                return;
+            }
+        };
 
-        println!("Received {n} bytes");
-        let received_data = decode_v1(&buf[0..n]);
+        let frame = match timeout(limits.read_timeout(), read_frame(&mut socket, first, limits.max_frame_bytes, &mut version)).await {
+            Ok(Ok(frame)) => frame,
+            Ok(Err(e)) => {
+                eprintln!("Bad frame from {address:?}: {e}");
+                if let Some(code) = e.code() {
+                    respond(&mut socket, CollectorResponseV1::Error(code), version, &limits).await;
+                }
+                // We can't tell where the next frame starts, so give up on the connection
+                return;
+            }
+            Err(_) => {
+                eprintln!("Timed out reading a frame from {address:?}");
+                respond(&mut socket, CollectorResponseV1::Error(ErrorCode::ReadTimeout), version, &limits).await;
+                return;
+            }
+        };
+
+        println!("Received {} bytes", frame.len());
+        let received_data = match try_decode_v1(&frame) {
+            Ok(received_data) => received_data,
+            Err(e) => {
+                eprintln!("Bad frame from {address:?}: {e}");
+                respond(&mut socket, CollectorResponseV1::Error(ErrorCode::InvalidFrame), version, &limits).await;
+                return;
+            }
+        };
         match received_data {
             (timestampt, CollectorCommandV1::SubmitData { collector_id, total_memory, used_memory, average_cpu_usage }) => {
                 let collector_id = uuid::Uuid::from_u128(collector_id);
-                let collector_id = collector_id.to_string();
+                let sample = Sample {
+                    collector_id: collector_id.to_string(),
+                    received: timestampt,
+                    total_memory,
+                    used_memory,
+                    average_cpu: average_cpu_usage,
+                };
 
                 // Insert the data into the database
-                let result = sqlx::query("INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES (?, ?, ?, ?, ?)")
-                    .bind(&collector_id)
-                    .bind(timestampt)
-                    .bind(total_memory as i64)
-                    .bind(used_memory as i64)
-                    .bind(average_cpu_usage)
-                    .execute(&cnn)
-                    .await;
-
-                if result.is_err() {
+                let result = pipeline.ingest(sample, version, DEFAULT_ORGANISATION).await;
+
+                let response = if result.is_err() {
                     eprintln!("Failed to insert data: {result:?}");
+                    CollectorResponseV1::Error(ErrorCode::StorageFailed)
                 } else { // Send an ACK
-                    let ack = CollectorResponseV1::Ack(0);
-                    let bytes = encode_response_v1(ack);
-                    socket.write_all(&bytes).await.unwrap();
+                    CollectorResponseV1::Ack(0)
+                };
+                if !respond(&mut socket, response, version, &limits).await {
+                    eprintln!("Failed to respond to {address:?}");
+                    return;
+                }
+            }
+            (timestamp, CollectorCommandV1::SetLabels { collector_id, labels }) => {
+                let collector_id = uuid::Uuid::from_u128(collector_id).to_string();
+                let labels: Labels = labels.into_iter().collect();
+                let response = if let Err(e) = labels::validate(&labels) {
+                    eprintln!("Rejected labels from {collector_id}: {e}");
+                    CollectorResponseV1::Error(ErrorCode::InvalidFrame)
+                } else if let Err(e) = store_labels(&pipeline.cnn, &collector_id, &labels, version, timestamp).await {
+                    eprintln!("Failed to store labels: {e}");
+                    CollectorResponseV1::Error(ErrorCode::StorageFailed)
+                } else {
+                    CollectorResponseV1::Ack(0)
+                };
+                if !respond(&mut socket, response, version, &limits).await {
+                    eprintln!("Failed to respond to {address:?}");
+                    return;
                 }
             }
         }
     }
 }
+
+// store_labels registers the collector, if this is the first it has been
+// heard of, and sets its labels. `version` is the protocol version the labels
+// were sent with.
+async fn store_labels(
+    cnn: &Pool<Sqlite>,
+    collector_id: &str,
+    labels: &Labels,
+    version: u16,
+    received: u32,
+) -> sqlx::Result<()> {
+    let mut tx = cnn.begin().await?;
+    registry::record_sample(&mut tx, collector_id, DEFAULT_ORGANISATION, version, received).await?;
+    registry::set_labels(&mut tx, collector_id, labels).await?;
+    tx.commit().await
+}
+
+// store_sample writes the sample and updates the collector registry in one
+// transaction, returning the organisation the collector is in
+async fn store_sample(cnn: &Pool<Sqlite>, sample: &Sample, version: u16, organisation: &str) -> sqlx::Result<String> {
+    let mut tx = cnn.begin().await?;
+    sqlx::query("INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES (?, ?, ?, ?, ?)")
+        .bind(&sample.collector_id)
+        .bind(sample.received)
+        .bind(sample.total_memory as i64)
+        .bind(sample.used_memory as i64)
+        .bind(sample.average_cpu)
+        .execute(&mut *tx)
+        .await?;
+    let organisation = registry::record_sample(&mut tx, &sample.collector_id, organisation, version, sample.received).await?;
+    tx.commit().await?;
+    Ok(organisation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use shared_data::{decode_response_v1, encode_v1};
+
+    async fn start(limits: Limits) -> (SocketAddr, Pool<Sqlite>) {
+        let pool = testing::memory_pool().await;
+        let pipeline = testing::pipeline(&pool).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, pipeline, limits, CancellationToken::new()));
+        (addr, pool)
+    }
+
+    async fn response(socket: &mut TcpStream) -> CollectorResponseV1 {
+        let mut buf = vec![0u8; 64];
+        let n = socket.read(&mut buf).await.unwrap();
+        decode_response_v1(&buf[..n])
+    }
+
+    // closed says whether the server closed the connection without a response
+    async fn closed(socket: &mut TcpStream) -> bool {
+        matches!(socket.read(&mut [0u8; 64]).await, Ok(0) | Err(_))
+    }
+
+    fn submit() -> Vec<u8> {
+        encode_v1(&CollectorCommandV1::SubmitData {
+            collector_id: 1,
+            total_memory: 100,
+            used_memory: 50,
+            average_cpu_usage: 0.5,
+        })
+    }
+
+    #[test]
+    fn test_admit() {
+        let open = Arc::new(Mutex::new(OpenConnections::default()));
+        let limits = Limits {
+            max_connections: 2,
+            max_connections_per_ip: 1,
+            ..Default::default()
+        };
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let c: IpAddr = "10.0.0.3".parse().unwrap();
+
+        let first = admit(&open, a, &limits).unwrap();
+        assert_eq!(admit(&open, a, &limits).err(), Some(ErrorCode::TooManyConnectionsFromAddress));
+        let _second = admit(&open, b, &limits).unwrap();
+        assert_eq!(admit(&open, c, &limits).err(), Some(ErrorCode::TooManyConnections));
+
+        // Closing a connection frees its place
+        drop(first);
+        assert!(admit(&open, a, &limits).is_ok());
+    }
+
+    #[test]
+    fn test_accept_failure() {
+        let error = |kind| std::io::Error::from(kind);
+        assert_eq!(accept_failure(&error(ErrorKind::ConnectionAborted)), AcceptFailure::Connection);
+        assert_eq!(accept_failure(&error(ErrorKind::ConnectionReset)), AcceptFailure::Connection);
+        // EMFILE and ENFILE
+        assert_eq!(accept_failure(&std::io::Error::from_raw_os_error(24)), AcceptFailure::Resources);
+        assert_eq!(accept_failure(&std::io::Error::from_raw_os_error(23)), AcceptFailure::Resources);
+        assert_eq!(accept_failure(&error(ErrorKind::OutOfMemory)), AcceptFailure::Resources);
+        assert_eq!(accept_failure(&error(ErrorKind::InvalidInput)), AcceptFailure::Fatal);
+    }
+
+    #[tokio::test]
+    async fn test_ack_and_per_ip_limit() {
+        let (addr, _) = start(Limits {
+            max_connections_per_ip: 1,
+            ..Default::default()
+        })
+        .await;
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        first.write_all(&submit()).await.unwrap();
+        assert_eq!(response(&mut first).await, CollectorResponseV1::Ack(0));
+
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        second.write_all(&submit()).await.unwrap();
+        assert_eq!(
+            response(&mut second).await,
+            CollectorResponseV1::Error(ErrorCode::TooManyConnectionsFromAddress)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_labels() {
+        let (addr, pool) = start(Limits::default()).await;
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let set_labels = |labels: &[(&str, &str)]| {
+            encode_v1(&CollectorCommandV1::SetLabels {
+                collector_id: 1,
+                labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            })
+        };
+        // Labels can arrive before the first sample
+        socket.write_all(&set_labels(&[("role", "db")])).await.unwrap();
+        assert_eq!(response(&mut socket).await, CollectorResponseV1::Ack(0));
+        socket.write_all(&set_labels(&[("not a name", "db")])).await.unwrap();
+        assert_eq!(response(&mut socket).await, CollectorResponseV1::Error(ErrorCode::InvalidFrame));
+
+        let collector_id = uuid::Uuid::from_u128(1).to_string();
+        let collector = registry::get(&pool, &Default::default(), &collector_id).await.unwrap().unwrap();
+        assert_eq!(collector.labels.get("role").map(String::as_str), Some("db"));
+        assert_eq!(collector.labels.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_frame_limits() {
+        let (addr, _) = start(Limits {
+            read_timeout_seconds: 1,
+            idle_timeout_seconds: 1,
+            ..Default::default()
+        })
+        .await;
+
+        // The header claims a payload far over the limit, which is refused without reading it
+        let mut header = submit()[..HEADER_SIZE].to_vec();
+        header[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(&header).await.unwrap();
+        assert_eq!(response(&mut socket).await, CollectorResponseV1::Error(ErrorCode::FrameTooLarge));
+
+        let mut corrupt = submit();
+        corrupt[HEADER_SIZE] ^= 0xff;
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(&corrupt).await.unwrap();
+        assert_eq!(response(&mut socket).await, CollectorResponseV1::Error(ErrorCode::InvalidFrame));
+
+        // Half a frame, then nothing
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(&submit()[..HEADER_SIZE + 2]).await.unwrap();
+        assert_eq!(response(&mut socket).await, CollectorResponseV1::Error(ErrorCode::ReadTimeout));
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(&submit()).await.unwrap();
+        assert_eq!(response(&mut socket).await, CollectorResponseV1::Ack(0));
+        assert_eq!(response(&mut socket).await, CollectorResponseV1::Error(ErrorCode::IdleTimeout));
+
+        // Without a frame there is no telling whether the collector understands errors
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        assert!(closed(&mut socket).await);
+    }
+
+    #[tokio::test]
+    async fn test_version_1_peer() {
+        let (addr, _) = start(Limits {
+            max_connections_per_ip: 1,
+            ..Default::default()
+        })
+        .await;
+        let as_v1 = |mut frame: Vec<u8>| {
+            frame[2..4].copy_from_slice(&1u16.to_be_bytes());
+            frame
+        };
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        first.write_all(&as_v1(submit())).await.unwrap();
+        assert_eq!(response(&mut first).await, CollectorResponseV1::Ack(0));
+
+        // A version 1 collector can't decode an error, so it is only disconnected
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        second.write_all(&as_v1(submit())).await.unwrap();
+        assert!(closed(&mut second).await);
+
+        let mut corrupt = as_v1(submit());
+        corrupt[HEADER_SIZE] ^= 0xff;
+        first.write_all(&corrupt).await.unwrap();
+        assert!(closed(&mut first).await);
+    }
+}
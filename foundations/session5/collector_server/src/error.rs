@@ -0,0 +1,134 @@
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+
+// ApiError is what a handler fails with. It is sent to the client as an
+// RFC 9457 problem details document.
+#[derive(Debug)]
+pub enum ApiError {
+    InvalidUuid(String),
+    BadRequest(String),
+    Unauthorized,
+    Forbidden(String),
+    NotFound(String),
+    // The database can't be reached or is locked, the request may succeed if retried
+    Unavailable(sqlx::Error),
+    Internal(anyhow::Error),
+}
+
+#[derive(Debug, Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidUuid(_) | ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // detail is shown to the client, so server side errors are only described
+    fn detail(&self) -> String {
+        match self {
+            ApiError::InvalidUuid(value) => format!("{value:?} is not a valid collector UUID"),
+            ApiError::BadRequest(detail) | ApiError::Forbidden(detail) | ApiError::NotFound(detail) => detail.clone(),
+            ApiError::Unauthorized => "a valid API token is required".to_string(),
+            ApiError::Unavailable(_) => "the database is unavailable, try again later".to_string(),
+            ApiError::Internal(_) => "the server failed to handle the request".to_string(),
+        }
+    }
+}
+
+// unavailable says whether a database error is worth retrying: the pool or
+// its connections failed, or it timed out waiting for a lock. Anything else,
+// such as a bad query or a value that doesn't decode, is a bug.
+fn unavailable(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::PoolTimedOut
+        | sqlx::Error::PoolClosed
+        | sqlx::Error::WorkerCrashed
+        | sqlx::Error::Io(_)
+        | sqlx::Error::Tls(_) => true,
+        // SQLITE_BUSY and SQLITE_LOCKED, with or without an extended code
+        sqlx::Error::Database(e) => e
+            .code()
+            .and_then(|code| code.parse::<i32>().ok())
+            .is_some_and(|code| matches!(code & 0xff, 5 | 6)),
+        _ => false,
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        match unavailable(&e) {
+            true => ApiError::Unavailable(e),
+            false => ApiError::Internal(e.into()),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<sqlx::Error>() {
+            Ok(e) => e.into(),
+            Err(e) => ApiError::Internal(e),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match &self {
+            ApiError::Unavailable(e) => eprintln!("Database error: {e}"),
+            ApiError::Internal(e) => eprintln!("Internal error: {e:#}"),
+            _ => {}
+        }
+        let status = self.status();
+        let problem = Problem {
+            kind: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            detail: self.detail(),
+        };
+        let mut response = (status, [(header::CONTENT_TYPE, "application/problem+json")], Json(problem)).into_response();
+        if matches!(self, ApiError::Unauthorized) {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+        }
+        response
+    }
+}
+
+// collector_id checks a collector UUID from a request path and returns it in
+// the hyphenated form the ingest listener stores
+pub fn collector_id(value: &str) -> Result<String, ApiError> {
+    uuid::Uuid::parse_str(value)
+        .map(|uuid| uuid.to_string())
+        .map_err(|_| ApiError::InvalidUuid(value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_database_errors() {
+        assert_eq!(ApiError::from(sqlx::Error::PoolTimedOut).status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(ApiError::from(sqlx::Error::PoolClosed).status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(ApiError::from(sqlx::Error::RowNotFound).status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let decode = sqlx::Error::ColumnNotFound("avg_memory".to_string());
+        assert_eq!(ApiError::from(anyhow::Error::from(decode)).status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
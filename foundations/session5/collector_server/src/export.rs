@@ -0,0 +1,440 @@
+use crate::api::visible;
+use crate::auth::Identity;
+use crate::error::ApiError;
+use axum::body::Body;
+use axum::extract::Query;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use futures::TryStreamExt;
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, FloatType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+// Output is sent to the client in chunks of about this size
+const CHUNK_SIZE: usize = 64 * 1024;
+// Rows per Parquet row group. A row group is the most that is held in memory.
+const ROW_GROUP_SIZE: usize = 64 * 1024;
+// Chunks waiting for a slow client. Reading from the database pauses when this is full.
+const CHUNKS_IN_FLIGHT: usize = 4;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Csv,
+    Ndjson,
+    Parquet,
+}
+
+impl Format {
+    fn content_type(&self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv",
+            Format::Ndjson => "application/x-ndjson",
+            Format::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Ndjson => "ndjson",
+            Format::Parquet => "parquet",
+        }
+    }
+}
+
+// ExportQuery selects what to export. Without a collector every collector the
+// caller can see is exported; without a range every sample is.
+#[derive(Debug, Default, Deserialize)]
+pub struct ExportQuery {
+    collector: Option<String>,
+    from: Option<u32>,
+    to: Option<u32>,
+    #[serde(default)]
+    format: Format,
+}
+
+#[derive(FromRow, Debug, Serialize)]
+struct ExportRow {
+    collector_id: String,
+    received: i64,
+    total_memory: i64,
+    used_memory: i64,
+    average_cpu: f32,
+}
+
+// Encoder turns rows into one of the export formats, a piece at a time
+trait Encoder: Send {
+    fn push(&mut self, row: &ExportRow) -> anyhow::Result<()>;
+    // ready returns output once enough of it has built up to be worth sending
+    fn ready(&mut self) -> anyhow::Result<Option<Vec<u8>>>;
+    fn finish(self: Box<Self>) -> anyhow::Result<Vec<u8>>;
+}
+
+// take_if_full hands over a text buffer once it reaches CHUNK_SIZE
+fn take_if_full(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    (buffer.len() >= CHUNK_SIZE).then(|| std::mem::replace(buffer, Vec::with_capacity(CHUNK_SIZE)))
+}
+
+// Collector ids are UUIDs and every other column is a number, so no field needs quoting
+struct CsvEncoder(Vec<u8>);
+
+impl CsvEncoder {
+    fn new() -> Self {
+        let mut buffer = Vec::with_capacity(CHUNK_SIZE);
+        buffer.extend_from_slice(b"collector_id,received,total_memory,used_memory,average_cpu\n");
+        Self(buffer)
+    }
+}
+
+impl Encoder for CsvEncoder {
+    fn push(&mut self, row: &ExportRow) -> anyhow::Result<()> {
+        writeln!(
+            self.0,
+            "{},{},{},{},{}",
+            row.collector_id, row.received, row.total_memory, row.used_memory, row.average_cpu
+        )?;
+        Ok(())
+    }
+
+    fn ready(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(take_if_full(&mut self.0))
+    }
+
+    fn finish(self: Box<Self>) -> anyhow::Result<Vec<u8>> {
+        Ok(self.0)
+    }
+}
+
+struct NdjsonEncoder(Vec<u8>);
+
+impl Encoder for NdjsonEncoder {
+    fn push(&mut self, row: &ExportRow) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.0, row)?;
+        self.0.push(b'\n');
+        Ok(())
+    }
+
+    fn ready(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(take_if_full(&mut self.0))
+    }
+
+    fn finish(self: Box<Self>) -> anyhow::Result<Vec<u8>> {
+        Ok(self.0)
+    }
+}
+
+const PARQUET_SCHEMA: &str = "message sample {
+    REQUIRED BYTE_ARRAY collector_id (UTF8);
+    REQUIRED INT64 received;
+    REQUIRED INT64 total_memory;
+    REQUIRED INT64 used_memory;
+    REQUIRED FLOAT average_cpu;
+}";
+
+// SharedBuffer is where the Parquet writer puts its output. It is emptied
+// after every row group; the writer keeps track of file offsets itself.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// ParquetEncoder collects a row group's worth of columns and writes them out together
+struct ParquetEncoder {
+    writer: SerializedFileWriter<SharedBuffer>,
+    output: SharedBuffer,
+    collector_id: Vec<ByteArray>,
+    received: Vec<i64>,
+    total_memory: Vec<i64>,
+    used_memory: Vec<i64>,
+    average_cpu: Vec<f32>,
+}
+
+impl ParquetEncoder {
+    fn new() -> anyhow::Result<Self> {
+        let schema = Arc::new(parse_message_type(PARQUET_SCHEMA)?);
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let output = SharedBuffer::default();
+        Ok(Self {
+            writer: SerializedFileWriter::new(output.clone(), schema, Arc::new(properties))?,
+            output,
+            collector_id: Vec::with_capacity(ROW_GROUP_SIZE),
+            received: Vec::with_capacity(ROW_GROUP_SIZE),
+            total_memory: Vec::with_capacity(ROW_GROUP_SIZE),
+            used_memory: Vec::with_capacity(ROW_GROUP_SIZE),
+            average_cpu: Vec::with_capacity(ROW_GROUP_SIZE),
+        })
+    }
+
+    fn write_row_group(&mut self) -> anyhow::Result<()> {
+        let mut row_group = self.writer.next_row_group()?;
+        let mut index = 0;
+        while let Some(mut column) = row_group.next_column()? {
+            match index {
+                0 => column.typed::<ByteArrayType>().write_batch(&self.collector_id, None, None)?,
+                1 => column.typed::<Int64Type>().write_batch(&self.received, None, None)?,
+                2 => column.typed::<Int64Type>().write_batch(&self.total_memory, None, None)?,
+                3 => column.typed::<Int64Type>().write_batch(&self.used_memory, None, None)?,
+                _ => column.typed::<FloatType>().write_batch(&self.average_cpu, None, None)?,
+            };
+            column.close()?;
+            index += 1;
+        }
+        row_group.close()?;
+        self.collector_id.clear();
+        self.received.clear();
+        self.total_memory.clear();
+        self.used_memory.clear();
+        self.average_cpu.clear();
+        Ok(())
+    }
+}
+
+impl Encoder for ParquetEncoder {
+    fn push(&mut self, row: &ExportRow) -> anyhow::Result<()> {
+        self.collector_id.push(row.collector_id.as_str().into());
+        self.received.push(row.received);
+        self.total_memory.push(row.total_memory);
+        self.used_memory.push(row.used_memory);
+        self.average_cpu.push(row.average_cpu);
+        Ok(())
+    }
+
+    fn ready(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        if self.received.len() < ROW_GROUP_SIZE {
+            return Ok(None);
+        }
+        self.write_row_group()?;
+        Ok(Some(self.output.take()))
+    }
+
+    fn finish(mut self: Box<Self>) -> anyhow::Result<Vec<u8>> {
+        if !self.received.is_empty() {
+            self.write_row_group()?;
+        }
+        self.writer.close()?;
+        Ok(self.output.take())
+    }
+}
+
+fn encoder(format: Format) -> anyhow::Result<Box<dyn Encoder>> {
+    Ok(match format {
+        Format::Csv => Box::new(CsvEncoder::new()),
+        Format::Ndjson => Box::new(NdjsonEncoder(Vec::with_capacity(CHUNK_SIZE))),
+        Format::Parquet => Box::new(ParquetEncoder::new()?),
+    })
+}
+
+type Chunk = Result<Vec<u8>, std::io::Error>;
+
+// write_export reads the rows from a database cursor and sends them on
+// encoded. It stops early, without an error, if the client goes away.
+async fn write_export(
+    pool: sqlx::SqlitePool,
+    identity: Identity,
+    collector_id: Option<String>,
+    query: ExportQuery,
+    chunks: &mpsc::Sender<Chunk>,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let mut encoder = encoder(query.format)?;
+    let mut rows = sqlx::query_as::<_, ExportRow>(
+        "SELECT t.collector_id, t.received, t.total_memory, t.used_memory, t.average_cpu
+        FROM timeseries t JOIN collectors c ON c.collector_id = t.collector_id
+        WHERE (? IS NULL OR t.collector_id = ?)
+            AND (? IS NULL OR c.organisation = ?)
+            AND t.received BETWEEN ? AND ?
+        ORDER BY t.collector_id, t.received",
+    )
+    .bind(&collector_id)
+    .bind(&collector_id)
+    .bind(identity.organisation())
+    .bind(identity.organisation())
+    .bind(query.from.unwrap_or(0))
+    .bind(query.to.unwrap_or(u32::MAX))
+    .fetch(&pool);
+
+    loop {
+        let row = tokio::select! {
+            row = rows.try_next() => row?,
+            _ = shutdown.cancelled() => anyhow::bail!("the server is shutting down"),
+        };
+        let Some(row) = row else { break };
+        encoder.push(&row)?;
+        if let Some(chunk) = encoder.ready()? {
+            if chunks.send(Ok(chunk)).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+    let _ = chunks.send(Ok(encoder.finish()?)).await;
+    Ok(())
+}
+
+// export streams samples as CSV, newline-delimited JSON or Parquet. Rows are
+// encoded as they come off the database cursor, so memory use doesn't grow
+// with the size of the export.
+pub async fn export(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    Extension(shutdown): Extension<CancellationToken>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, ApiError> {
+    let collector_id = match &query.collector {
+        Some(uuid) => Some(visible(&pool, &identity, uuid).await?),
+        None => None,
+    };
+    let filename = format!(
+        "{}.{}",
+        collector_id.as_deref().unwrap_or("export"),
+        query.format.extension()
+    );
+    let content_type = query.format.content_type();
+
+    let (tx, rx) = mpsc::channel::<Chunk>(CHUNKS_IN_FLIGHT);
+    tokio::spawn(async move {
+        if let Err(e) = write_export(pool, identity, collector_id, query, &tx, shutdown).await {
+            // The response has already started, so all we can do is cut it short
+            eprintln!("Export failed: {e:#}");
+            let _ = tx.send(Err(std::io::Error::other(e.to_string()))).await;
+        }
+    });
+    let body = Body::from_stream(futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }));
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{filename}\"")),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use axum::routing::get;
+    use axum::Router;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::RowAccessor;
+
+    const C1: &str = "00000000-0000-0000-0000-000000000001";
+    const C2: &str = "00000000-0000-0000-0000-000000000002";
+
+    async fn start() -> std::net::SocketAddr {
+        let pool = testing::memory_pool().await;
+        let mut cnn = pool.acquire().await.unwrap();
+        for (collector_id, received) in [(C1, 100), (C1, 200), (C2, 150)] {
+            sqlx::query("INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES (?, ?, 1000, 500, 12.5)")
+                .bind(collector_id)
+                .bind(received)
+                .execute(&mut *cnn)
+                .await
+                .unwrap();
+            crate::registry::record_sample(&mut cnn, collector_id, "default", 1, received).await.unwrap();
+        }
+        drop(cnn);
+
+        let app = Router::new()
+            .route("/api/export", get(export))
+            .layer(Extension(Identity::unrestricted()))
+            .layer(Extension(CancellationToken::new()))
+            .layer(Extension(pool));
+        testing::serve(app).await
+    }
+
+    #[tokio::test]
+    async fn test_export_formats() {
+        let addr = start().await;
+
+        let response = reqwest::get(format!("http://{addr}/api/export?collector={C1}&from=150")).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/csv");
+        let csv = response.text().await.unwrap();
+        assert_eq!(
+            csv,
+            format!("collector_id,received,total_memory,used_memory,average_cpu\n{C1},200,1000,500,12.5\n")
+        );
+
+        let ndjson = reqwest::get(format!("http://{addr}/api/export?format=ndjson"))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let rows: Vec<serde_json::Value> = ndjson.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[2]["collector_id"], C2);
+
+        let parquet = reqwest::get(format!("http://{addr}/api/export?format=parquet"))
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        let reader = SerializedFileReader::new(parquet).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 3);
+        let first = reader.get_row_iter(None).unwrap().next().unwrap().unwrap();
+        assert_eq!(first.get_string(0).unwrap(), C1);
+        assert_eq!(first.get_long(1).unwrap(), 100);
+
+        let response = reqwest::get(format!("http://{addr}/api/export?collector=nope")).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_parquet_row_groups() {
+        // Output comes out a row group at a time, and the pieces make a valid file
+        let mut encoder = ParquetEncoder::new().unwrap();
+        let mut file = Vec::new();
+        for received in 0..ROW_GROUP_SIZE as i64 + 10 {
+            let row = ExportRow {
+                collector_id: C1.to_string(),
+                received,
+                total_memory: 1000,
+                used_memory: 500,
+                average_cpu: 1.0,
+            };
+            encoder.push(&row).unwrap();
+            if let Some(chunk) = encoder.ready().unwrap() {
+                file.extend(chunk);
+            }
+        }
+        assert!(!file.is_empty());
+        file.extend(Box::new(encoder).finish().unwrap());
+
+        let reader = SerializedFileReader::new(axum::body::Bytes::from(file)).unwrap();
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+        assert_eq!(reader.metadata().file_metadata().num_rows(), ROW_GROUP_SIZE as i64 + 10);
+    }
+}
@@ -0,0 +1,450 @@
+use crate::api::visible;
+use crate::auth::Identity;
+use crate::error::ApiError;
+use crate::registry::{self, Thresholds};
+use axum::extract::Query;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+
+const DEFAULT_WINDOW_DAYS: u32 = 7;
+const DEFAULT_HORIZON_DAYS: u32 = 365;
+// Longer windows and horizons are refused, which also keeps them in range
+// once they are converted to seconds
+const MAX_DAYS: u32 = 3650;
+// Holt-Winters needs two whole days of hourly buckets to learn the daily cycle
+const SEASON: usize = 24;
+const SEASONAL_STEP: u32 = 3600;
+// Shorter windows are bucketed into this many points
+const LINEAR_POINTS: u32 = 120;
+const MIN_POINTS: usize = 3;
+// Two-sided 95% confidence
+const Z_95: f64 = 1.96;
+
+#[derive(Debug, Deserialize)]
+pub struct ForecastQuery {
+    collector: Option<String>,
+    // days of history the model is fitted to
+    window_days: Option<u32>,
+    // how far ahead to look for exhaustion
+    horizon_days: Option<u32>,
+    // only return collectors expected to run out within this many days
+    within_days: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Method {
+    Linear,
+    HoltWinters,
+}
+
+// Forecast is when a collector is expected to run out of memory. Times are
+// seconds since the Unix epoch and None means not within the horizon.
+#[derive(Debug, Clone, Serialize)]
+pub struct Forecast {
+    pub collector_id: String,
+    pub method: Method,
+    pub points: usize,
+    pub total_memory: u64,
+    pub used_memory: u64,
+    // trend of used memory, in bytes per day
+    pub growth_per_day: f64,
+    pub exhaustion: Option<u32>,
+    // the 95% confidence bounds of the exhaustion time
+    pub exhaustion_earliest: Option<u32>,
+    pub exhaustion_latest: Option<u32>,
+}
+
+// Model predicts used memory `ahead` seconds after the last point, as a mean
+// and the standard deviation of that prediction
+trait Model {
+    fn predict(&self, ahead: f64) -> (f64, f64);
+    fn growth_per_second(&self) -> f64;
+}
+
+// Linear is an ordinary least squares fit of used memory against time
+#[derive(Debug)]
+struct Linear {
+    slope: f64,
+    intercept: f64,
+    residual: f64, // standard deviation of the residuals
+    n: f64,
+    mean_t: f64,
+    sxx: f64,
+    last_t: f64,
+}
+
+impl Linear {
+    fn fit(points: &[(f64, f64)]) -> Option<Self> {
+        if points.len() < MIN_POINTS {
+            return None;
+        }
+        let n = points.len() as f64;
+        let mean_t = points.iter().map(|(t, _)| t).sum::<f64>() / n;
+        let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+        let sxx: f64 = points.iter().map(|(t, _)| (t - mean_t).powi(2)).sum();
+        if sxx == 0.0 {
+            return None;
+        }
+        let sxy: f64 = points.iter().map(|(t, y)| (t - mean_t) * (y - mean_y)).sum();
+        let slope = sxy / sxx;
+        let intercept = mean_y - slope * mean_t;
+        let sse: f64 = points.iter().map(|(t, y)| (y - intercept - slope * t).powi(2)).sum();
+        Some(Self {
+            slope,
+            intercept,
+            residual: (sse / (n - 2.0)).sqrt(),
+            n,
+            mean_t,
+            sxx,
+            last_t: points[points.len() - 1].0,
+        })
+    }
+}
+
+impl Model for Linear {
+    // The prediction interval widens the further the time is from the data
+    fn predict(&self, ahead: f64) -> (f64, f64) {
+        let t = self.last_t + ahead;
+        let stddev = self.residual * (1.0 + 1.0 / self.n + (t - self.mean_t).powi(2) / self.sxx).sqrt();
+        (self.intercept + self.slope * t, stddev)
+    }
+
+    fn growth_per_second(&self) -> f64 {
+        self.slope
+    }
+}
+
+// HoltWinters is additive triple exponential smoothing over hourly buckets,
+// so a daily cycle isn't mistaken for growth
+#[derive(Debug)]
+struct HoltWinters {
+    level: f64,
+    trend: f64,       // per step
+    season: Vec<f64>, // indexed by step modulo SEASON
+    residual: f64,    // standard deviation of the one step ahead errors
+    steps: usize,     // number of points fitted
+}
+
+impl HoltWinters {
+    // fit tries a small grid of smoothing factors and keeps the one with the
+    // smallest one step ahead error
+    fn fit(values: &[f64]) -> Option<Self> {
+        if values.len() < 2 * SEASON {
+            return None;
+        }
+        let mut best: Option<(f64, Self)> = None;
+        for alpha in [0.1, 0.3, 0.5, 0.7, 0.9] {
+            for beta in [0.01, 0.05, 0.1, 0.2] {
+                for gamma in [0.05, 0.1, 0.3] {
+                    let (sse, model) = Self::smooth(values, alpha, beta, gamma);
+                    if best.as_ref().is_none_or(|(best_sse, _)| sse < *best_sse) {
+                        best = Some((sse, model));
+                    }
+                }
+            }
+        }
+        best.map(|(_, model)| model)
+    }
+
+    fn smooth(values: &[f64], alpha: f64, beta: f64, gamma: f64) -> (f64, Self) {
+        // Start from the first two seasons: their means give the level and trend
+        let first = values[..SEASON].iter().sum::<f64>() / SEASON as f64;
+        let second = values[SEASON..2 * SEASON].iter().sum::<f64>() / SEASON as f64;
+        let mut level = first;
+        let mut trend = (second - first) / SEASON as f64;
+        let mut season: Vec<f64> = values[..SEASON].iter().map(|value| value - first).collect();
+
+        let mut sse = 0.0;
+        for (i, value) in values.iter().enumerate().skip(SEASON) {
+            let s = season[i % SEASON];
+            let error = value - (level + trend + s);
+            sse += error * error;
+            let previous = level;
+            level = alpha * (value - s) + (1.0 - alpha) * (level + trend);
+            trend = beta * (level - previous) + (1.0 - beta) * trend;
+            season[i % SEASON] = gamma * (value - level) + (1.0 - gamma) * s;
+        }
+        let errors = (values.len() - SEASON) as f64;
+        let model = Self {
+            level,
+            trend,
+            season,
+            residual: (sse / errors).sqrt(),
+            steps: values.len(),
+        };
+        (sse, model)
+    }
+}
+
+impl Model for HoltWinters {
+    // The error of each step ahead adds up, so the deviation grows with the
+    // square root of the number of steps
+    fn predict(&self, ahead: f64) -> (f64, f64) {
+        let h = (ahead / SEASONAL_STEP as f64).ceil().max(1.0);
+        let s = self.season[(self.steps - 1 + h as usize) % SEASON];
+        (self.level + h * self.trend + s, self.residual * h.sqrt())
+    }
+
+    fn growth_per_second(&self) -> f64 {
+        self.trend / SEASONAL_STEP as f64
+    }
+}
+
+// Exhaustion is when the prediction, and its bounds, first reach total memory
+struct Exhaustion {
+    expected: Option<f64>,
+    earliest: Option<f64>,
+    latest: Option<f64>,
+}
+
+// exhaustion steps through the horizon looking for the first time each of
+// the mean, upper and lower bound reach `total`
+fn exhaustion(model: &dyn Model, total: f64, step: u32, horizon: u32) -> Exhaustion {
+    let mut found = Exhaustion {
+        expected: None,
+        earliest: None,
+        latest: None,
+    };
+    let mut ahead = step;
+    while ahead <= horizon && found.latest.is_none() {
+        let (mean, stddev) = model.predict(ahead as f64);
+        let at = Some(ahead as f64);
+        if found.earliest.is_none() && mean + Z_95 * stddev >= total {
+            found.earliest = at;
+        }
+        if found.expected.is_none() && mean >= total {
+            found.expected = at;
+        }
+        if mean - Z_95 * stddev >= total {
+            found.latest = at;
+        }
+        let Some(next) = ahead.checked_add(step) else { break };
+        ahead = next;
+    }
+    found
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct Bucket {
+    bucket: i64,
+    used_memory: f64,
+    total_memory: f64,
+}
+
+// forecast fits a model to a collector's memory use over `window` seconds
+// before `now`. It uses Holt-Winters when there are two days of data to see
+// the daily cycle in, and a straight line otherwise.
+async fn forecast(
+    pool: &Pool<Sqlite>,
+    collector_id: &str,
+    now: u32,
+    window: u32,
+    horizon: u32,
+) -> sqlx::Result<Option<Forecast>> {
+    let seasonal = window >= 2 * SEASON as u32 * SEASONAL_STEP;
+    let step = if seasonal { SEASONAL_STEP } else { (window / LINEAR_POINTS).max(60) };
+    let buckets = sqlx::query_as::<_, Bucket>(
+        "SELECT (received / ?) * ? AS bucket, AVG(used_memory) AS used_memory, AVG(total_memory) AS total_memory
+        FROM timeseries
+        WHERE collector_id = ? AND received > ? AND received <= ?
+        GROUP BY bucket
+        ORDER BY bucket",
+    )
+    .bind(step)
+    .bind(step)
+    .bind(collector_id)
+    .bind(now.saturating_sub(window))
+    .bind(now)
+    .fetch_all(pool)
+    .await?;
+    let Some(last) = buckets.last() else {
+        return Ok(None);
+    };
+    let total = last.total_memory;
+
+    let holt_winters = seasonal.then(|| HoltWinters::fit(&fill_gaps(&buckets, step))).flatten();
+    let linear = || {
+        let points: Vec<(f64, f64)> = buckets.iter().map(|b| (b.bucket as f64, b.used_memory)).collect();
+        Linear::fit(&points)
+    };
+    let (method, model): (Method, Box<dyn Model>) = match holt_winters {
+        Some(model) => (Method::HoltWinters, Box::new(model)),
+        None => match linear() {
+            Some(model) => (Method::Linear, Box::new(model)),
+            None => return Ok(None),
+        },
+    };
+
+    // Predictions start from the last bucket, which may be a little before now
+    let last_bucket = last.bucket as u32;
+    let found = exhaustion(model.as_ref(), total, step, now.saturating_sub(last_bucket).saturating_add(horizon));
+    let at = |ahead: Option<f64>| ahead.map(|ahead| last_bucket.saturating_add(ahead as u32));
+    Ok(Some(Forecast {
+        collector_id: collector_id.to_string(),
+        method,
+        points: buckets.len(),
+        total_memory: total as u64,
+        used_memory: last.used_memory as u64,
+        growth_per_day: model.growth_per_second() * 86400.0,
+        exhaustion: at(found.expected),
+        exhaustion_earliest: at(found.earliest),
+        exhaustion_latest: at(found.latest),
+    }))
+}
+
+// fill_gaps turns the buckets into one value per step, repeating the last
+// value over any hour the collector didn't report in
+fn fill_gaps(buckets: &[Bucket], step: u32) -> Vec<f64> {
+    let mut values = Vec::new();
+    let mut previous: Option<&Bucket> = None;
+    for bucket in buckets {
+        if let Some(previous) = previous {
+            let missing = (bucket.bucket - previous.bucket) / step as i64 - 1;
+            values.extend((0..missing).map(|_| previous.used_memory));
+        }
+        values.push(bucket.used_memory);
+        previous = Some(bucket);
+    }
+    values
+}
+
+// forecasts returns the memory forecast of every collector the caller can
+// see, or of one collector, soonest exhaustion first. Collectors without
+// enough recent samples to fit a model are left out.
+pub async fn forecasts(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(thresholds): Extension<Thresholds>,
+    Extension(identity): Extension<Identity>,
+    Query(query): Query<ForecastQuery>,
+) -> Result<Json<Vec<Forecast>>, ApiError> {
+    let window = query.window_days.unwrap_or(DEFAULT_WINDOW_DAYS);
+    let horizon = query.horizon_days.unwrap_or(DEFAULT_HORIZON_DAYS);
+    if window == 0 || horizon == 0 {
+        return Err(ApiError::BadRequest("window_days and horizon_days must be positive".to_string()));
+    }
+    if window > MAX_DAYS || horizon > MAX_DAYS {
+        return Err(ApiError::BadRequest(format!("window_days and horizon_days can't be more than {MAX_DAYS}")));
+    }
+    let collectors = match &query.collector {
+        Some(uuid) => vec![visible(&pool, &identity, uuid).await?],
+        None => registry::list(&pool, &thresholds, identity.organisation())
+            .await?
+            .into_iter()
+            .filter(|collector| !collector.decommissioned)
+            .map(|collector| collector.collector_id)
+            .collect(),
+    };
+
+    let now = registry::unix_now();
+    let mut forecasts = Vec::new();
+    for collector_id in collectors {
+        if let Some(forecast) = forecast(&pool, &collector_id, now, window * 86400, horizon * 86400).await? {
+            forecasts.push(forecast);
+        }
+    }
+    if let Some(days) = query.within_days {
+        let deadline = now.saturating_add(days.saturating_mul(86400));
+        forecasts.retain(|forecast| forecast.exhaustion.is_some_and(|at| at <= deadline));
+    }
+    forecasts.sort_by_key(|forecast| forecast.exhaustion.unwrap_or(u32::MAX));
+    Ok(Json(forecasts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    const GB: f64 = 1e9;
+
+    async fn pool_with(collector_id: &str, samples: impl Iterator<Item = (u32, f64)>) -> Pool<Sqlite> {
+        let pool = testing::memory_pool().await;
+        for (received, used) in samples {
+            sqlx::query(
+                "INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES (?, ?, ?, ?, 0)",
+            )
+            .bind(collector_id)
+            .bind(received)
+            .bind((16.0 * GB) as i64)
+            .bind(used as i64)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+        pool
+    }
+
+    #[test]
+    fn test_linear_fit() {
+        // 1 GB a day, plus some noise
+        let points: Vec<(f64, f64)> = (0..100)
+            .map(|i| (i as f64 * 3600.0, 4.0 * GB + i as f64 * GB / 24.0 + if i % 2 == 0 { 1e7 } else { -1e7 }))
+            .collect();
+        let model = Linear::fit(&points).unwrap();
+        assert!((model.growth_per_second() * 86400.0 - GB).abs() < 1e6);
+        let (mean, stddev) = model.predict(86400.0);
+        assert!((mean - (4.0 * GB + 123.0 * GB / 24.0)).abs() < 1e8);
+        // Predicting further ahead is less certain
+        assert!(model.predict(10.0 * 86400.0).1 > stddev);
+        assert!(Linear::fit(&points[..2]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_linear_exhaustion() {
+        let now = 10 * 86400;
+        // Used memory grows by 1 GB a day and is at 14 GB now, so 16 GB is reached in two days
+        let pool = pool_with("c1", (0..600).map(|i| (now - 86400 + i * 144, (13.0 + i as f64 / 600.0) * GB))).await;
+        let forecast = forecast(&pool, "c1", now, 86400, 30 * 86400).await.unwrap().unwrap();
+        assert_eq!(forecast.method, Method::Linear);
+        let expected = now + 2 * 86400;
+        let exhaustion = forecast.exhaustion.unwrap();
+        assert!(exhaustion.abs_diff(expected) < 3600, "{forecast:?}");
+        assert!(forecast.exhaustion_earliest.unwrap() <= exhaustion);
+        assert!(forecast.exhaustion_latest.unwrap() >= exhaustion);
+
+        // Not within a shorter horizon
+        let forecast = super::forecast(&pool, "c1", now, 86400, 86400).await.unwrap().unwrap();
+        assert!(forecast.exhaustion.is_none());
+        assert!(super::forecast(&pool, "other", now, 86400, 86400).await.unwrap().is_none());
+        // A horizon at the end of time doesn't overflow
+        let forecast = super::forecast(&pool, "c1", now, 86400, u32::MAX).await.unwrap().unwrap();
+        assert!(forecast.exhaustion.unwrap().abs_diff(expected) < 3600);
+
+        let query = |horizon_days| ForecastQuery {
+            collector: None,
+            window_days: None,
+            horizon_days: Some(horizon_days),
+            within_days: None,
+        };
+        let request = |query| {
+            forecasts(Extension(pool.clone()), Extension(Thresholds::default()), Extension(Identity::unrestricted()), Query(query))
+        };
+        assert!(request(query(MAX_DAYS)).await.is_ok());
+        assert!(matches!(request(query(MAX_DAYS + 1)).await, Err(ApiError::BadRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn test_holt_winters_ignores_daily_cycle() {
+        let now = 20 * 86400;
+        // Flat at 8 GB with a 6 GB swing every day: a line through the last
+        // few hours would hit 16 GB soon, but there is no growth
+        let daily = |t: u32| 8.0 * GB + 6.0 * GB * ((t % 86400) as f64 / 86400.0 * std::f64::consts::TAU).sin();
+        let pool = pool_with("c1", (0..7 * 288).map(|i| now - 7 * 86400 + i * 300).map(|t| (t, daily(t)))).await;
+        let forecast = forecast(&pool, "c1", now, 7 * 86400, 30 * 86400).await.unwrap().unwrap();
+        assert_eq!(forecast.method, Method::HoltWinters);
+        assert!(forecast.exhaustion.is_none(), "{forecast:?}");
+        assert!(forecast.growth_per_day.abs() < 0.1 * GB);
+
+        // With growth on top of a smaller cycle it does run out: the daily
+        // peak is at 15 GB now and grows by 1 GB a day
+        let start = now - 7 * 86400;
+        let growing = |t: u32| 6.0 * GB + (daily(t) - 8.0 * GB) / 3.0 + (t - start) as f64 / 86400.0 * GB;
+        let pool = pool_with("c1", (0..7 * 288).map(|i| start + i * 300).map(|t| (t, growing(t)))).await;
+        let forecast = super::forecast(&pool, "c1", now, 7 * 86400, 30 * 86400).await.unwrap().unwrap();
+        let days = (forecast.exhaustion.unwrap() - now) as f64 / 86400.0;
+        assert!((0.0..3.0).contains(&days), "{forecast:?}");
+        assert!((forecast.growth_per_day - GB).abs() < 0.2 * GB);
+    }
+}
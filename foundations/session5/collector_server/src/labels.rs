@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use sqlx::{QueryBuilder, Sqlite};
+
+// Labels are the key/value pairs describing a collector, such as role=db
+pub type Labels = BTreeMap<String, String>;
+
+const MAX_NAME: usize = 64;
+const MAX_VALUE: usize = 255;
+
+// valid_name accepts names made of letters, digits and underscores that
+// don't start with a digit, so they can be written in selectors unquoted
+pub fn valid_name(name: &str) -> bool {
+    name.len() <= MAX_NAME
+        && name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// validate checks the labels a collector or API client wants to set
+pub fn validate(labels: &Labels) -> Result<(), String> {
+    for (name, value) in labels {
+        if !valid_name(name) {
+            return Err(format!("{name:?} is not a valid label name"));
+        }
+        if value.len() > MAX_VALUE {
+            return Err(format!("the value of label {name} is longer than {MAX_VALUE} bytes"));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Matcher {
+    pub name: String,
+    pub value: String,
+    pub negated: bool,
+}
+
+// Selector picks collectors by their labels. It is written as comma separated
+// matchers, `role=db,region!="eu west"`, all of which have to match. A
+// collector without the label counts as having an empty value.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Selector(pub Vec<Matcher>);
+
+impl Selector {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut matchers = Vec::new();
+        let mut rest = text.trim();
+        while !rest.is_empty() {
+            let (matcher, remainder) = Self::parse_matcher(rest)?;
+            matchers.push(matcher);
+            rest = remainder.trim_start();
+            if let Some(remainder) = rest.strip_prefix(',') {
+                rest = remainder.trim_start();
+            } else if !rest.is_empty() {
+                return Err(format!("expected ',' before {rest:?}"));
+            }
+        }
+        Ok(Self(matchers))
+    }
+
+    // parse_matcher reads one `name=value` or `name!=value` off the front of `text`
+    fn parse_matcher(text: &str) -> Result<(Matcher, &str), String> {
+        let end = text
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(text.len());
+        let name = &text[..end];
+        if !valid_name(name) {
+            return Err(format!("expected a label name at {text:?}"));
+        }
+        let rest = text[end..].trim_start();
+        let (negated, rest) = match (rest.strip_prefix("!="), rest.strip_prefix('=')) {
+            (Some(rest), _) => (true, rest),
+            (None, Some(rest)) => (false, rest),
+            (None, None) => return Err(format!("expected '=' or '!=' after {name}")),
+        };
+        let rest = rest.trim_start();
+        let (value, rest) = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let close = quoted.find('"').ok_or_else(|| format!("unterminated value for {name}"))?;
+                (&quoted[..close], &quoted[close + 1..])
+            }
+            None => {
+                let end = rest.find([',', '}']).unwrap_or(rest.len());
+                (rest[..end].trim_end(), &rest[end..])
+            }
+        };
+        let matcher = Matcher {
+            name: name.to_string(),
+            value: value.to_string(),
+            negated,
+        };
+        Ok((matcher, rest))
+    }
+
+    pub fn matches(&self, labels: &Labels) -> bool {
+        self.0.iter().all(|matcher| {
+            let value = labels.get(&matcher.name).map(String::as_str).unwrap_or("");
+            (value == matcher.value) != matcher.negated
+        })
+    }
+
+    // push_sql adds a condition matching the selector to a query, `collector`
+    // being the column holding the collector id
+    pub fn push_sql(&self, query: &mut QueryBuilder<Sqlite>, collector: &str) {
+        query.push("(TRUE");
+        for matcher in &self.0 {
+            query.push(format!(
+                " AND COALESCE((SELECT value FROM collector_labels l WHERE l.collector_id = {collector} AND l.name = "
+            ));
+            query.push_bind(matcher.name.clone());
+            query.push("), '')");
+            query.push(if matcher.negated { " != " } else { " = " });
+            query.push_bind(matcher.value.clone());
+        }
+        query.push(")");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_parse_selector() {
+        let selector = Selector::parse(r#"role=db, region != "eu west""#).unwrap();
+        assert_eq!(
+            selector.0,
+            vec![
+                Matcher { name: "role".into(), value: "db".into(), negated: false },
+                Matcher { name: "region".into(), value: "eu west".into(), negated: true },
+            ]
+        );
+        assert_eq!(Selector::parse("").unwrap(), Selector::default());
+        assert!(Selector::parse("role").is_err());
+        assert!(Selector::parse("1role=db").is_err());
+        assert!(Selector::parse(r#"role="db"x"#).is_err());
+        assert!(Selector::parse(r#"role="db"#).is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let selector = Selector::parse("role=db,region!=eu").unwrap();
+        assert!(selector.matches(&labels(&[("role", "db"), ("region", "us")])));
+        assert!(selector.matches(&labels(&[("role", "db")])));
+        assert!(!selector.matches(&labels(&[("role", "db"), ("region", "eu")])));
+        assert!(!selector.matches(&labels(&[("role", "web")])));
+        // An empty value matches collectors without the label
+        assert!(Selector::parse("role=").unwrap().matches(&Labels::new()));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&labels(&[("role", "db"), ("_zone2", "")])).is_ok());
+        assert!(validate(&labels(&[("role-name", "db")])).is_err());
+        assert!(validate(&labels(&[("role", &"x".repeat(256))])).is_err());
+    }
+}
@@ -1,38 +1,195 @@
 //use axum::response::Redirect;
 use tokio::net::TcpListener;
-use axum::Extension;
-use axum::{Router, routing::get};
+use axum::{middleware, Extension};
+use axum::{Router, routing::{delete, get, post}};
+use clap::Parser;
+use std::sync::{Arc, Mutex};
+use tokio_util::sync::CancellationToken;
 
 mod collector;
 mod api;
+mod auth;
+mod error;
+mod export;
+mod labels;
+mod forecast;
 mod web;
+mod alerts;
+mod anomaly;
+mod notify;
+mod query;
+mod registry;
+mod stream;
+mod metrics;
+mod config;
+mod tls;
+mod retention;
+mod supervisor;
+#[cfg(test)]
+mod testing;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Read the .env file and obtain the database URL
-    dotenv::dotenv()?;
-    let db_url = std::env::var("DATABASE_URL")?;
+    // The .env file is optional, its variables are one layer of the configuration
+    let args = config::Args::parse();
+    dotenv::dotenv().ok();
+    let config = config::Config::load(&args)?;
+    if args.print_config {
+        print!("{}", config.to_toml());
+        return Ok(());
+    }
+
+    if !config.auth.enabled {
+        eprintln!(
+            "WARNING: authentication is disabled, anyone who can reach {} can read and change everything",
+            config.http.address
+        );
+    }
 
     // Get a database connection pool
-    let pool = sqlx::SqlitePool::connect(&db_url).await?;
+    let pool = sqlx::SqlitePool::connect(&config.database.url).await?;
+    sqlx::migrate!().run(&pool).await?;
+
+    if let Some(organisation) = &args.issue_token {
+        let issued = auth::issue(&pool, "command line", organisation.as_deref(), auth::Scope::Admin).await?;
+        match organisation {
+            Some(organisation) => println!("Issued admin token {} for {organisation}:", issued.token_id),
+            None => println!("Issued operator token {} for every organisation:", issued.token_id),
+        }
+        println!("{}", issued.token);
+        return Ok(());
+    }
+
+    // Load the alert rules and the notifiers they report to
+    let dispatcher = notify::Dispatcher::new(notify::notifiers(&config.notify)?);
+    let alerts = Arc::new(alerts::Alerts::load(&pool, dispatcher).await?);
+    let thresholds = config.collectors;
+
+    // Learn the anomaly baselines from recent samples so they survive a restart
+    let detector = Arc::new(anomaly::Detector::new(config.anomaly));
+    detector.warm_up(&pool, registry::unix_now()).await?;
+
+    let mut supervisor = supervisor::Supervisor::new();
+    let shutdown = supervisor.shutdown_token();
 
-    let handle = tokio::spawn(collector::data_collector(pool.clone()));
+    let samples = stream::channel();
 
+    let pipeline = collector::Pipeline {
+        cnn: pool.clone(),
+        alerts: alerts.clone(),
+        detector: detector.clone(),
+        samples: samples.clone(),
+    };
 
+    // The ingest listener is restarted if it fails, e.g. because its port is briefly taken
+    let ingest_address = config.ingest.address.clone();
+    let ingest_limits = config.ingest.limits;
+    let ingest_pipeline = pipeline.clone();
+    supervisor.spawn("ingest listener", supervisor::Restart::OnError { max: 5 }, move |shutdown| {
+        collector::data_collector(ingest_address.clone(), ingest_pipeline.clone(), ingest_limits, shutdown)
+    });
+
+    if config.retention.days > 0 {
+        let (pool, retention) = (pool.clone(), config.retention.clone());
+        supervisor.spawn("retention", supervisor::Restart::OnError { max: 5 }, move |shutdown| {
+            let (pool, retention) = (pool.clone(), retention.clone());
+            async move {
+                retention::enforce(pool, retention, shutdown).await;
+                Ok(())
+            }
+        });
+    }
+
+    // Start the web server. API routes are grouped by the token scope they need.
+    let read = Router::new()
+        .route("/api/all", get(api::show_all))
+        .route("/api/collectors", get(api::show_collectors))
+        .route("/api/collectors/{uuid}", get(api::show_collector))
+        .route("/api/collector/{uuid}", get(api::collector_data))
+        .route("/api/collector/{uuid}/aggregate", get(api::collector_aggregate))
+        .route("/api/aggregate", get(api::fleet_aggregate))
+        .route("/api/query", get(query::query))
+        .route("/api/export", get(export::export))
+        .route("/api/forecast", get(forecast::forecasts))
+        .route("/api/alerts", get(api::firing_alerts))
+        .route("/api/alerts/rules", get(api::alert_rules))
+        .route("/api/anomalies", get(api::anomalies))
+        .route("/metrics", get(metrics::metrics))
+        .route("/ws/collector/{uuid}", get(stream::ws))
+        .route("/sse/collector/{uuid}", get(stream::sse))
+        .route_layer(middleware::from_fn_with_state(auth::Scope::Read, auth::require));
+    let write = Router::new()
+        .route("/api/collectors/{uuid}", delete(api::delete_collector))
+        .route("/api/collectors/{uuid}/rename", post(api::rename_collector))
+        .route("/api/collectors/{uuid}/decommission", post(api::decommission_collector))
+        .route("/api/collectors/{uuid}/tags", post(api::tag_collector))
+        .route("/api/collectors/{uuid}/tags/{tag}", delete(api::untag_collector))
+        .route("/api/collectors/{uuid}/labels", post(api::label_collector))
+        .route("/api/collectors/{uuid}/labels/{name}", delete(api::unlabel_collector))
+        .route("/api/v1/write", post(metrics::remote_write))
+        .route_layer(middleware::from_fn_with_state(auth::Scope::Write, auth::require));
+    let admin = Router::new()
+        .route("/api/collectors/{uuid}/organisation", post(api::move_collector))
+        .route("/api/alerts/rules", post(api::save_alert_rule))
+        .route("/api/alerts/rules/{name}", delete(api::delete_alert_rule))
+        .route("/api/admin/tokens", get(api::list_tokens).post(api::issue_token))
+        .route("/api/admin/tokens/{id}", delete(api::revoke_token))
+        .route_layer(middleware::from_fn_with_state(auth::Scope::Admin, auth::require));
 
-    // Start the web server
     let app = Router::new()
         //.route("/", get(|| async {Redirect::to("/api/all")}))
         .route("/", get(web::index))
         .route("/collector.html", get(web::collector))
-        .route("/api/all", get(api::show_all))
-        .route("/api/collectors", get(api::show_collectors))
-        .route("/api/collector/{uuid}", get(api::collector_data))
-        .layer(Extension(pool)); // This is the database connection pool
-    let addr = TcpListener::bind("localhost:3000").await?;
-    axum::serve(addr, app).await?;
+        .merge(read)
+        .merge(write)
+        .merge(admin)
+        .layer(Extension(alerts.clone()))
+        .layer(Extension(detector))
+        .layer(Extension(samples))
+        .layer(Extension(pipeline))
+        .layer(Extension(thresholds))
+        .layer(Extension(config.auth.clone()))
+        .layer(Extension(shutdown))
+        .layer(Extension(pool.clone())); // This is the database connection pool
+
+    // Bind before handing over to the supervisor so a bad address fails at startup.
+    // If the web server stops, the whole server goes down with it.
+    let http = config.http.clone();
+    match (&config.tls.cert, &config.tls.key) {
+        (Some(cert), Some(key)) => {
+            let addr = Mutex::new(Some(tls::TlsListener::bind(&http.address, cert, key).await?));
+            supervisor.spawn("web server", supervisor::Restart::Never, move |shutdown| {
+                serve(addr.lock().unwrap().take(), app.clone(), shutdown)
+            });
+        }
+        _ => {
+            let addr = Mutex::new(Some(TcpListener::bind(&http.address).await?));
+            supervisor.spawn("web server", supervisor::Restart::Never, move |shutdown| {
+                serve(addr.lock().unwrap().take(), app.clone(), shutdown)
+            });
+        }
+    }
+
+    // Run until a shutdown signal or a fatal error, then drain everything
+    let result = supervisor.run().await;
+
+    // Deliver alerts that are still on their way, then close the database
+    if tokio::time::timeout(supervisor::DRAIN_TIMEOUT, alerts.flush()).await.is_err() {
+        eprintln!("Gave up waiting for alert notifications");
+    }
+    pool.close().await;
+    println!("Shutdown complete");
+    result
+}
 
-    // Wait for the data collector to finish
-    handle.await??; // Two question marks - we're unwrapping the task result, and the result from running the collector.
+// serve runs the web server until shutdown, letting in-flight requests finish
+async fn serve<L: axum::serve::Listener>(listener: Option<L>, app: Router, shutdown: CancellationToken) -> anyhow::Result<()>
+where
+    L::Addr: std::fmt::Debug,
+{
+    let listener = listener.ok_or_else(|| anyhow::anyhow!("the web server cannot be restarted"))?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await?;
     Ok(())
 }
@@ -0,0 +1,392 @@
+use crate::auth::Identity;
+use crate::collector::{Pipeline, Sample};
+use crate::error::ApiError;
+use crate::registry::{CollectorStatus, Thresholds};
+use axum::body::Bytes;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::Extension;
+use prost::Message;
+use sqlx::{FromRow, Pool, Sqlite};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+// The metric names are shared by /metrics and remote write, so data scraped
+// from one server can be pushed into another unchanged
+const CPU: &str = "collector_cpu_usage_percent";
+const MEMORY_USED: &str = "collector_memory_used_bytes";
+const MEMORY_TOTAL: &str = "collector_memory_total_bytes";
+const LAST_SEEN: &str = "collector_last_seen_timestamp_seconds";
+const UP: &str = "collector_up";
+
+// Samples that arrive through remote write are registered with this protocol version
+const REMOTE_WRITE_VERSION: u16 = 0;
+
+#[derive(FromRow, Debug)]
+struct Latest {
+    collector_id: String,
+    label: Option<String>,
+    received: i64,
+    total_memory: i64,
+    used_memory: i64,
+    average_cpu: f32,
+}
+
+// latest returns the most recent sample of every active collector in the
+// organisation, or in all of them for None
+async fn latest(pool: &Pool<Sqlite>, organisation: Option<&str>) -> sqlx::Result<Vec<Latest>> {
+    sqlx::query_as::<_, Latest>(
+        "SELECT c.collector_id, c.label, c.last_seen AS received, t.total_memory, t.used_memory, t.average_cpu
+        FROM collectors c
+        JOIN timeseries t ON t.collector_id = c.collector_id AND t.received = c.last_seen
+        WHERE NOT c.decommissioned AND (? IS NULL OR c.organisation = ?)
+        GROUP BY c.collector_id
+        ORDER BY c.collector_id",
+    )
+    .bind(organisation)
+    .bind(organisation)
+    .fetch_all(pool)
+    .await
+}
+
+// escape_label escapes a label value as the text exposition format requires
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// A metric family is its name, type, help text and how to read it from a row
+type Family = (&'static str, &'static str, &'static str, fn(&Latest, bool) -> f64);
+
+fn render(rows: &[Latest], thresholds: &Thresholds, now: u32) -> String {
+    let families: [Family; 5] = [
+        (CPU, "gauge", "Average CPU usage reported by the collector.", |r, _| r.average_cpu as f64),
+        (MEMORY_USED, "gauge", "Memory in use on the collector host.", |r, _| r.used_memory as f64),
+        (MEMORY_TOTAL, "gauge", "Total memory on the collector host.", |r, _| r.total_memory as f64),
+        (LAST_SEEN, "gauge", "Time of the collector's latest sample.", |r, _| r.received as f64),
+        (UP, "gauge", "Whether the collector is online.", |_, up| if up { 1.0 } else { 0.0 }),
+    ];
+
+    let mut out = String::new();
+    for (name, kind, help, value) in families {
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} {kind}").unwrap();
+        for row in rows {
+            let up = thresholds.status(row.received as u32, now) == CollectorStatus::Online;
+            let mut labels = format!("collector_id=\"{}\"", escape_label(&row.collector_id));
+            if let Some(label) = &row.label {
+                write!(labels, ",label=\"{}\"", escape_label(label)).unwrap();
+            }
+            writeln!(out, "{name}{{{labels}}} {}", value(row, up)).unwrap();
+        }
+    }
+    out
+}
+
+// metrics exposes the latest value per collector in Prometheus text format
+pub async fn metrics(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(thresholds): Extension<Thresholds>,
+    Extension(identity): Extension<Identity>,
+) -> Result<impl IntoResponse, ApiError> {
+    let rows = latest(&pool, identity.organisation()).await?;
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render(&rows, &thresholds, crate::registry::unix_now()),
+    ))
+}
+
+// The remote write protocol messages, from prometheus/prompb/types.proto
+
+#[derive(Clone, PartialEq, Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<PromSample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PromSample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64, // milliseconds since the Unix epoch
+}
+
+// Partial collects the metrics pushed for one collector at one timestamp
+#[derive(Default)]
+struct Partial {
+    cpu: Option<f32>,
+    used_memory: Option<u64>,
+    total_memory: Option<u64>,
+}
+
+// collector_uuid keeps an id that is already a UUID. Anything else, such as
+// an instance address, becomes a name-based UUID so it can be used with the
+// rest of the API like any other collector.
+fn collector_uuid(id: &str) -> String {
+    uuid::Uuid::parse_str(id)
+        .unwrap_or_else(|_| uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_URL, id.as_bytes()))
+        .to_string()
+}
+
+// memory_bytes is a pushed memory value as bytes, if it is one the timeseries
+// table can hold
+fn memory_bytes(value: f64) -> Option<u64> {
+    (value.is_finite() && value >= 0.0 && value < i64::MAX as f64).then_some(value as u64)
+}
+
+// group_samples sorts the series we understand by collector and timestamp.
+// The collector is taken from the `collector_id` label, or `instance`.
+// Samples with a timestamp or value we can't store are skipped and counted.
+fn group_samples(request: WriteRequest) -> (BTreeMap<(String, u32), Partial>, usize) {
+    let mut groups: BTreeMap<(String, u32), Partial> = BTreeMap::new();
+    let mut invalid = 0;
+    for series in request.timeseries {
+        let label = |name: &str| series.labels.iter().find(|l| l.name == name).map(|l| l.value.clone());
+        let (Some(metric), Some(collector_id)) = (label("__name__"), label("collector_id").or_else(|| label("instance"))) else {
+            continue;
+        };
+        if ![CPU, MEMORY_USED, MEMORY_TOTAL].contains(&metric.as_str()) {
+            continue;
+        }
+        let collector_id = collector_uuid(&collector_id);
+        for sample in &series.samples {
+            let Ok(received) = u32::try_from(sample.timestamp.div_euclid(1000)) else {
+                invalid += 1;
+                continue;
+            };
+            let key = (collector_id.clone(), received);
+            match (metric.as_str(), memory_bytes(sample.value)) {
+                (CPU, _) if sample.value.is_finite() => groups.entry(key).or_default().cpu = Some(sample.value as f32),
+                (MEMORY_USED, Some(bytes)) => groups.entry(key).or_default().used_memory = Some(bytes),
+                (MEMORY_TOTAL, Some(bytes)) => groups.entry(key).or_default().total_memory = Some(bytes),
+                _ => invalid += 1,
+            }
+        }
+    }
+    (groups, invalid)
+}
+
+async fn latest_sample(pool: &Pool<Sqlite>, collector_id: &str) -> sqlx::Result<Option<Sample>> {
+    let row: Option<(i64, i64, i64, f32)> = sqlx::query_as(
+        "SELECT received, total_memory, used_memory, average_cpu FROM timeseries
+        WHERE collector_id = ? ORDER BY received DESC LIMIT 1",
+    )
+    .bind(collector_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|(received, total_memory, used_memory, average_cpu)| Sample {
+        collector_id: collector_id.to_string(),
+        received: received as u32,
+        total_memory: total_memory as u64,
+        used_memory: used_memory as u64,
+        average_cpu,
+    }))
+}
+
+// remote_write accepts a snappy-compressed Prometheus WriteRequest. A metric
+// missing at some timestamp is carried forward from the collector's previous
+// sample; points for a collector we know nothing about yet are dropped until
+// all three metrics have been seen together. Samples with a timestamp or
+// value that can't be stored are skipped. New collectors join the
+// caller's organisation, and collectors in other organisations are refused.
+pub async fn remote_write(
+    Extension(pipeline): Extension<Pipeline>,
+    Extension(identity): Extension<Identity>,
+    body: Bytes,
+) -> Result<StatusCode, ApiError> {
+    let body = snap::raw::Decoder::new()
+        .decompress_vec(&body)
+        .map_err(|e| ApiError::BadRequest(format!("invalid snappy payload: {e}")))?;
+    let request = WriteRequest::decode(body.as_slice())
+        .map_err(|e| ApiError::BadRequest(format!("invalid protobuf: {e}")))?;
+
+    // Every collector is checked before anything is ingested, so a refused
+    // request doesn't leave part of itself behind
+    let (groups, invalid) = group_samples(request);
+    let mut previous: HashMap<String, Option<Sample>> = HashMap::new();
+    for (collector_id, _) in groups.keys() {
+        if previous.contains_key(collector_id) {
+            continue;
+        }
+        let organisation = crate::registry::organisation_of(&pipeline.cnn, collector_id).await?;
+        if organisation.is_some_and(|organisation| !identity.can_see(&organisation)) {
+            return Err(ApiError::Forbidden(format!("{collector_id} belongs to another organisation")));
+        }
+        let sample = latest_sample(&pipeline.cnn, collector_id).await?;
+        previous.insert(collector_id.clone(), sample);
+    }
+
+    let mut dropped = 0;
+    for ((collector_id, received), partial) in groups {
+        let base = previous[&collector_id].as_ref();
+        let (Some(average_cpu), Some(used_memory), Some(total_memory)) = (
+            partial.cpu.or(base.map(|s| s.average_cpu)),
+            partial.used_memory.or(base.map(|s| s.used_memory)),
+            partial.total_memory.or(base.map(|s| s.total_memory)),
+        ) else {
+            dropped += 1;
+            continue;
+        };
+        let sample = Sample {
+            collector_id: collector_id.clone(),
+            received,
+            total_memory,
+            used_memory,
+            average_cpu,
+        };
+        pipeline
+            .ingest(sample.clone(), REMOTE_WRITE_VERSION, identity.owning_organisation())
+            .await?;
+        previous.insert(collector_id, Some(sample));
+    }
+    if dropped > 0 {
+        eprintln!("Remote write: dropped {dropped} incomplete points");
+    }
+    if invalid > 0 {
+        eprintln!("Remote write: skipped {invalid} samples with an invalid timestamp or value");
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use axum::routing::{get, post};
+    use axum::Router;
+
+    fn series(name: &str, collector_id: &str, value: f64, timestamp: i64) -> TimeSeries {
+        TimeSeries {
+            labels: vec![
+                Label { name: "__name__".to_string(), value: name.to_string() },
+                Label { name: "collector_id".to_string(), value: collector_id.to_string() },
+            ],
+            samples: vec![PromSample { value, timestamp }],
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let rows = vec![Latest {
+            collector_id: "c1".to_string(),
+            label: Some("db \"primary\"".to_string()),
+            received: 1000,
+            total_memory: 200,
+            used_memory: 100,
+            average_cpu: 12.5,
+        }];
+        let text = render(&rows, &Thresholds::default(), 1010);
+        assert!(text.contains("# TYPE collector_cpu_usage_percent gauge\n"));
+        assert!(text.contains("collector_cpu_usage_percent{collector_id=\"c1\",label=\"db \\\"primary\\\"\"} 12.5\n"));
+        assert!(text.contains("collector_up{collector_id=\"c1\",label=\"db \\\"primary\\\"\"} 1\n"));
+    }
+
+    #[test]
+    fn test_group_samples_skips_invalid() {
+        let request = WriteRequest {
+            timeseries: vec![
+                series(CPU, "c1", 10.0, 1_000_000),
+                series(CPU, "c1", f64::NAN, 2_000_000),
+                series(MEMORY_USED, "c1", -1.0, 1_000_000),
+                series(MEMORY_TOTAL, "c1", f64::INFINITY, 1_000_000),
+                // Before 1970 and after 2106
+                series(CPU, "c1", 10.0, -5_000),
+                series(CPU, "c1", 10.0, (u32::MAX as i64 + 1) * 1000),
+                series("some_other_metric", "c1", f64::NAN, -5_000),
+            ],
+        };
+        let (groups, invalid) = group_samples(request);
+        assert_eq!(invalid, 5);
+        assert_eq!(groups.len(), 1);
+        let partial = &groups[&(collector_uuid("c1"), 1000)];
+        assert_eq!((partial.cpu, partial.used_memory, partial.total_memory), (Some(10.0), None, None));
+    }
+
+    #[tokio::test]
+    async fn test_remote_write_then_scrape() {
+        let pool = testing::memory_pool().await;
+        let pipeline = testing::pipeline(&pool).await;
+        let app = Router::new()
+            .route("/metrics", get(metrics))
+            .route("/api/v1/write", post(remote_write))
+            .layer(Extension(pipeline))
+            .layer(Extension(Thresholds::default()))
+            .layer(Extension(Identity::unrestricted()))
+            .layer(Extension(pool));
+        let addr = testing::serve(app).await;
+
+        // The second timestamp only carries CPU, memory is carried forward
+        let request = WriteRequest {
+            timeseries: vec![
+                series(CPU, "c1", 10.0, 1_000_000),
+                series(MEMORY_USED, "c1", 100.0, 1_000_000),
+                series(MEMORY_TOTAL, "c1", 200.0, 1_000_000),
+                series(CPU, "c1", 20.0, 1_001_000),
+                series(CPU, "unknown", 20.0, 1_001_000),
+            ],
+        };
+        let body = snap::raw::Encoder::new().compress_vec(&request.encode_to_vec()).unwrap();
+        let client = reqwest::Client::new();
+        let response = client.post(format!("http://{addr}/api/v1/write")).body(body).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = client.post(format!("http://{addr}/api/v1/write")).body("junk").send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let text = reqwest::get(format!("http://{addr}/metrics")).await.unwrap().text().await.unwrap();
+        let c1 = collector_uuid("c1");
+        assert!(text.contains(&format!("collector_cpu_usage_percent{{collector_id=\"{c1}\"}} 20\n")));
+        assert!(text.contains(&format!("collector_memory_used_bytes{{collector_id=\"{c1}\"}} 100\n")));
+        assert!(text.contains(&format!("collector_last_seen_timestamp_seconds{{collector_id=\"{c1}\"}} 1001\n")));
+        assert!(!text.contains(&collector_uuid("unknown")));
+        // A collector that already has a UUID keeps it
+        assert_eq!(collector_uuid(&c1), c1);
+    }
+
+    #[tokio::test]
+    async fn test_remote_write_checks_every_collector_first() {
+        let pool = testing::memory_pool().await;
+        let pipeline = testing::pipeline(&pool).await;
+        let theirs = "ffffffff-ffff-ffff-ffff-ffffffffffff";
+        let mut cnn = pool.acquire().await.unwrap();
+        crate::registry::record_sample(&mut cnn, theirs, "other", 1, 100).await.unwrap();
+        drop(cnn);
+        let token = crate::auth::issue(&pool, "writer", Some("acme"), crate::auth::Scope::Write).await.unwrap().token;
+        let identity = crate::auth::authenticate(&pool, &token).await.unwrap().unwrap();
+
+        // The new collector sorts first but is refused along with the rest
+        let request = WriteRequest {
+            timeseries: [("mine", 1.0), (theirs, 2.0)]
+                .into_iter()
+                .flat_map(|(collector_id, cpu)| {
+                    [
+                        series(CPU, collector_id, cpu, 1_000_000),
+                        series(MEMORY_USED, collector_id, 100.0, 1_000_000),
+                        series(MEMORY_TOTAL, collector_id, 200.0, 1_000_000),
+                    ]
+                })
+                .collect(),
+        };
+        let body = snap::raw::Encoder::new().compress_vec(&request.encode_to_vec()).unwrap();
+        let result = remote_write(Extension(pipeline), Extension(identity), Bytes::from(body)).await;
+        assert!(matches!(result, Err(ApiError::Forbidden(_))));
+        let mine = collector_uuid("mine");
+        assert!(crate::registry::organisation_of(&pool, &mine).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,484 @@
+use crate::config::NotifyConfig;
+use futures::future::BoxFuture;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio_util::task::TaskTracker;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+// Alert is what every notifier receives when a rule changes state for a collector
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub organisation: String,
+    pub rule: String,
+    pub collector_id: String,
+    pub status: AlertStatus,
+    pub value: f32,
+    pub message: String,
+    pub timestamp: u32,
+}
+
+// Notifier is a sink that delivers alerts somewhere people will see them.
+// The future is boxed so notifiers can be stored as trait objects.
+pub trait Notifier: Send + Sync {
+    fn name(&self) -> &str;
+    fn notify<'a>(&'a self, alert: &'a Alert) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+// How long a webhook request or an alert command may take before it is given up on
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// WebhookNotifier POSTs the alert as JSON, retrying with exponential backoff
+pub struct WebhookNotifier {
+    client: reqwest::Client,
+    url: String,
+    attempts: u32,
+    backoff: Duration,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: &str) -> Self {
+        Self {
+            client: webhook_client(NOTIFY_TIMEOUT),
+            url: url.to_string(),
+            attempts: 3,
+            backoff: Duration::from_millis(500),
+        }
+    }
+
+    // with_timeout limits how long each attempt may take, so a webhook that
+    // never answers doesn't hold a notification open forever
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.client = webhook_client(timeout);
+        self
+    }
+
+    pub fn with_retry(mut self, attempts: u32, backoff: Duration) -> Self {
+        self.attempts = attempts.max(1);
+        self.backoff = backoff;
+        self
+    }
+}
+
+fn webhook_client(timeout: Duration) -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .expect("failed to build the webhook client")
+}
+
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    fn notify<'a>(&'a self, alert: &'a Alert) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let mut delay = self.backoff;
+            let mut attempt = 1;
+            loop {
+                let result = self
+                    .client
+                    .post(&self.url)
+                    .json(alert)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+                match result {
+                    Ok(_) => return Ok(()),
+                    Err(e) if attempt >= self.attempts => return Err(e.into()),
+                    Err(e) => {
+                        eprintln!("Webhook attempt {attempt} failed: {e}");
+                        tokio::time::sleep(delay).await;
+                        delay *= 2;
+                        attempt += 1;
+                    }
+                }
+            }
+        })
+    }
+}
+
+// SmtpNotifier sends a plain text email for each alert
+pub struct SmtpNotifier {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: String,
+    to: Vec<String>,
+}
+
+impl SmtpNotifier {
+    // new builds the transport. With `starttls` false the connection is
+    // unencrypted, which is only meant for local relays and tests.
+    pub fn new(
+        host: &str,
+        port: u16,
+        starttls: bool,
+        credentials: Option<(String, String)>,
+        from: &str,
+        to: Vec<String>,
+    ) -> anyhow::Result<Self> {
+        let mut builder = if starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+        };
+        builder = builder.port(port);
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+        Ok(Self {
+            transport: builder.build(),
+            from: from.to_string(),
+            to,
+        })
+    }
+}
+
+impl Notifier for SmtpNotifier {
+    fn name(&self) -> &str {
+        "smtp"
+    }
+
+    fn notify<'a>(&'a self, alert: &'a Alert) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let mut builder = Message::builder().from(self.from.parse()?).subject(format!(
+                "[{:?}] {} on {}",
+                alert.status, alert.rule, alert.collector_id
+            ));
+            for to in &self.to {
+                builder = builder.to(to.parse()?);
+            }
+            let email = builder.body(alert.message.clone())?;
+            self.transport.send(email).await?;
+            Ok(())
+        })
+    }
+}
+
+// CommandNotifier runs a local program. The alert is passed as JSON on stdin
+// and as ALERT_* environment variables.
+pub struct CommandNotifier {
+    program: String,
+    args: Vec<String>,
+    timeout: Duration,
+}
+
+impl CommandNotifier {
+    pub fn new(program: &str, args: Vec<String>) -> Self {
+        Self {
+            program: program.to_string(),
+            args,
+            timeout: NOTIFY_TIMEOUT,
+        }
+    }
+
+    // with_timeout sets how long the command may run before it is killed
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl Notifier for CommandNotifier {
+    fn name(&self) -> &str {
+        "command"
+    }
+
+    fn notify<'a>(&'a self, alert: &'a Alert) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let mut child = tokio::process::Command::new(&self.program)
+                .args(&self.args)
+                .env("ALERT_ORGANISATION", &alert.organisation)
+                .env("ALERT_RULE", &alert.rule)
+                .env("ALERT_COLLECTOR", &alert.collector_id)
+                .env("ALERT_STATUS", format!("{:?}", alert.status).to_lowercase())
+                .env("ALERT_VALUE", alert.value.to_string())
+                .env("ALERT_MESSAGE", &alert.message)
+                .stdin(std::process::Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()?;
+            if let Some(mut stdin) = child.stdin.take() {
+                // A command that ignores stdin may exit before we finish writing
+                match stdin.write_all(&serde_json::to_vec(alert)?).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(e.into()),
+                    _ => {}
+                }
+            } // stdin is dropped here so the child sees EOF
+            let status = match tokio::time::timeout(self.timeout, child.wait()).await {
+                Ok(status) => status?,
+                Err(_) => {
+                    child.kill().await?;
+                    anyhow::bail!("{} did not finish within {:?} and was killed", self.program, self.timeout);
+                }
+            };
+            anyhow::ensure!(status.success(), "{} exited with {status}", self.program);
+            Ok(())
+        })
+    }
+}
+
+// notifiers builds the notifiers that are configured
+pub fn notifiers(config: &NotifyConfig) -> anyhow::Result<Vec<Arc<dyn Notifier>>> {
+    let mut notifiers: Vec<Arc<dyn Notifier>> = Vec::new();
+    let timeout = config.timeout_seconds.map_or(NOTIFY_TIMEOUT, Duration::from_secs);
+
+    if let Some(url) = &config.webhook_url {
+        let mut webhook = WebhookNotifier::new(url).with_timeout(timeout);
+        if let Some(attempts) = config.webhook_attempts {
+            webhook = webhook.with_retry(attempts, Duration::from_millis(500));
+        }
+        notifiers.push(Arc::new(webhook));
+    }
+
+    if let Some(smtp) = &config.smtp {
+        let credentials = smtp.username.clone().zip(smtp.password.clone());
+        notifiers.push(Arc::new(SmtpNotifier::new(
+            &smtp.host,
+            smtp.port,
+            smtp.starttls,
+            credentials,
+            &smtp.from,
+            smtp.to.clone(),
+        )?));
+    }
+
+    if let Some(command) = &config.command {
+        let mut parts = command.split_whitespace().map(str::to_string);
+        if let Some(program) = parts.next() {
+            notifiers.push(Arc::new(CommandNotifier::new(&program, parts.collect()).with_timeout(timeout)));
+        }
+    }
+
+    Ok(notifiers)
+}
+
+// Dispatcher fans alerts out to every notifier. A firing alert for the same
+// organisation, rule and collector is only sent again once the rule's repeat interval has
+// passed; a resolved alert is sent once and clears that state.
+pub struct Dispatcher {
+    notifiers: Vec<Arc<dyn Notifier>>,
+    last_sent: Mutex<HashMap<(String, String, String), Instant>>,
+    in_flight: TaskTracker,
+}
+
+impl Dispatcher {
+    pub fn new(notifiers: Vec<Arc<dyn Notifier>>) -> Self {
+        Self {
+            notifiers,
+            last_sent: Mutex::new(HashMap::new()),
+            in_flight: TaskTracker::new(),
+        }
+    }
+
+    // should_send records the alert and reports whether it needs to go out
+    fn should_send(&self, alert: &Alert, repeat_interval: Duration) -> bool {
+        let key = (alert.organisation.clone(), alert.rule.clone(), alert.collector_id.clone());
+        let mut last_sent = self.last_sent.lock().unwrap();
+        match alert.status {
+            AlertStatus::Resolved => last_sent.remove(&key).is_some(),
+            AlertStatus::Firing => match last_sent.get(&key) {
+                Some(sent) if sent.elapsed() < repeat_interval => false,
+                _ => {
+                    last_sent.insert(key, Instant::now());
+                    true
+                }
+            },
+        }
+    }
+
+    // dispatch sends the alert in the background so ingest is never held up
+    // by a slow notifier
+    pub fn dispatch(&self, alert: Alert, repeat_interval: Duration) {
+        if !self.should_send(&alert, repeat_interval) {
+            return;
+        }
+        let alert = Arc::new(alert);
+        for notifier in &self.notifiers {
+            let notifier = notifier.clone();
+            let alert = alert.clone();
+            self.in_flight.spawn(async move {
+                if let Err(e) = notifier.notify(&alert).await {
+                    eprintln!("{} notifier failed: {e:?}", notifier.name());
+                }
+            });
+        }
+    }
+
+    // flush waits for notifications that are still being sent
+    pub async fn flush(&self) {
+        self.in_flight.close();
+        self.in_flight.wait().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use axum::{http::StatusCode, routing::post, Json, Router};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::TcpListener;
+
+    fn alert(status: AlertStatus) -> Alert {
+        Alert {
+            organisation: "default".to_string(),
+            rule: "high_cpu".to_string(),
+            collector_id: "c1".to_string(),
+            status,
+            value: 95.0,
+            message: "CPU is 95%".to_string(),
+            timestamp: 1,
+        }
+    }
+
+    #[test]
+    fn test_dispatcher_dedup() {
+        let dispatcher = Dispatcher::new(Vec::new());
+        let hour = Duration::from_secs(3600);
+        assert!(dispatcher.should_send(&alert(AlertStatus::Firing), hour));
+        assert!(!dispatcher.should_send(&alert(AlertStatus::Firing), hour));
+        assert!(dispatcher.should_send(&alert(AlertStatus::Firing), Duration::ZERO));
+        assert!(dispatcher.should_send(&alert(AlertStatus::Resolved), hour));
+        assert!(!dispatcher.should_send(&alert(AlertStatus::Resolved), hour));
+        assert!(dispatcher.should_send(&alert(AlertStatus::Firing), hour));
+
+        // Another organisation's rule with the same name is tracked apart
+        let other = Alert {
+            organisation: "acme".to_string(),
+            ..alert(AlertStatus::Firing)
+        };
+        assert!(dispatcher.should_send(&other, hour));
+        assert!(!dispatcher.should_send(&other, hour));
+    }
+
+    #[tokio::test]
+    async fn test_webhook_retries() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let app = Router::new().route(
+            "/hook",
+            post(move |Json(body): Json<serde_json::Value>| async move {
+                assert_eq!(body["rule"], "high_cpu");
+                // Fail the first request so the notifier has to retry
+                match counter.fetch_add(1, Ordering::SeqCst) {
+                    0 => StatusCode::INTERNAL_SERVER_ERROR,
+                    _ => StatusCode::OK,
+                }
+            }),
+        );
+        let addr = testing::serve(app).await;
+
+        let notifier = WebhookNotifier::new(&format!("http://{addr}/hook"))
+            .with_retry(3, Duration::from_millis(10));
+        notifier.notify(&alert(AlertStatus::Firing)).await.unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_timeout() {
+        let app = Router::new().route(
+            "/hook",
+            post(|| async {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                StatusCode::OK
+            }),
+        );
+        let addr = testing::serve(app).await;
+
+        let notifier = WebhookNotifier::new(&format!("http://{addr}/hook"))
+            .with_retry(1, Duration::ZERO)
+            .with_timeout(Duration::from_millis(100));
+        let started = Instant::now();
+        assert!(notifier.notify(&alert(AlertStatus::Firing)).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    // smtp_stub accepts a single session and returns the DATA it received
+    async fn smtp_stub(listener: TcpListener) -> String {
+        let (socket, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = socket.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer.write_all(b"220 stub ESMTP\r\n").await.unwrap();
+        let mut data = String::new();
+        let mut in_data = false;
+        while let Ok(Some(line)) = lines.next_line().await {
+            if in_data {
+                if line == "." {
+                    in_data = false;
+                    writer.write_all(b"250 queued\r\n").await.unwrap();
+                } else {
+                    data.push_str(&line);
+                    data.push('\n');
+                }
+                continue;
+            }
+            let reply: &[u8] = match line.get(..4).unwrap_or_default().to_uppercase().as_str() {
+                "EHLO" | "HELO" => b"250 stub\r\n",
+                "DATA" => {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                }
+                "QUIT" => {
+                    writer.write_all(b"221 bye\r\n").await.unwrap();
+                    break;
+                }
+                _ => b"250 ok\r\n",
+            };
+            writer.write_all(reply).await.unwrap();
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn test_smtp_notifier() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let stub = tokio::spawn(smtp_stub(listener));
+
+        let notifier = SmtpNotifier::new(
+            "127.0.0.1",
+            port,
+            false,
+            None,
+            "alerts@example.com",
+            vec!["ops@example.com".to_string()],
+        )
+        .unwrap();
+        notifier.notify(&alert(AlertStatus::Firing)).await.unwrap();
+
+        let data = stub.await.unwrap();
+        assert!(data.contains("To: ops@example.com"));
+        assert!(data.contains("high_cpu on c1"));
+        assert!(data.contains("CPU is 95%"));
+    }
+
+    #[tokio::test]
+    async fn test_command_notifier() {
+        let notifier = CommandNotifier::new(
+            "sh",
+            vec!["-c".to_string(), "test \"$ALERT_RULE\" = high_cpu".to_string()],
+        );
+        notifier.notify(&alert(AlertStatus::Firing)).await.unwrap();
+
+        let failing = CommandNotifier::new("sh", vec!["-c".to_string(), "exit 1".to_string()]);
+        assert!(failing.notify(&alert(AlertStatus::Firing)).await.is_err());
+
+        // A command that hangs is killed
+        let hanging = CommandNotifier::new("sh", vec!["-c".to_string(), "sleep 10".to_string()])
+            .with_timeout(Duration::from_millis(100));
+        let started = Instant::now();
+        assert!(hanging.notify(&alert(AlertStatus::Firing)).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,904 @@
+use crate::auth::Identity;
+use crate::error::ApiError;
+use crate::labels::{self, Labels, Matcher, Selector};
+use crate::registry;
+use axum::extract::Query;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, QueryBuilder, Row, Sqlite};
+use std::fmt;
+
+// A query may not ask for more points per series than this
+const MAX_POINTS: u32 = 1000;
+const MAX_RANGE: u32 = 366 * 86400;
+// Queries are parsed and evaluated recursively, so their size and nesting are
+// limited to keep them from overflowing the stack. Each call is a scan of up
+// to MAX_RANGE, so there can only be a few of them.
+const MAX_QUERY_LENGTH: usize = 4096;
+const MAX_DEPTH: usize = 64;
+const MAX_CALLS: usize = 10;
+// Group by labels that are properties of the collector rather than labels set on it
+const COLLECTOR: &str = "collector"; // the collector UUID
+const HOST: &str = "host"; // the collector's name, or its UUID if it hasn't been named
+
+// Field is a value of a sample that can be queried
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Cpu,         // average CPU usage in percent
+    Memory,      // used memory as a percentage of total memory
+    UsedMemory,  // bytes
+    TotalMemory, // bytes
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "cpu" => Some(Field::Cpu),
+            "memory" => Some(Field::Memory),
+            "used_memory" => Some(Field::UsedMemory),
+            "total_memory" => Some(Field::TotalMemory),
+            _ => None,
+        }
+    }
+
+    // sql is the expression for the field over the timeseries table `t`, as a REAL
+    fn sql(&self) -> &'static str {
+        match self {
+            Field::Cpu => "t.average_cpu",
+            Field::Memory => "t.used_memory * 100.0 / NULLIF(t.total_memory, 0)",
+            Field::UsedMemory => "t.used_memory * 1.0",
+            Field::TotalMemory => "t.total_memory * 1.0",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    Avg,
+    Min,
+    Max,
+    // per second change of each collector over the range, summed over the group
+    Rate,
+    // nearest rank percentile, 0 < p <= 100
+    Percentile(f64),
+}
+
+impl Function {
+    fn name(&self) -> &'static str {
+        match self {
+            Function::Avg => "avg",
+            Function::Min => "min",
+            Function::Max => "max",
+            Function::Rate => "rate",
+            Function::Percentile(_) => "percentile",
+        }
+    }
+}
+
+// Call applies a range function to the samples of the selected collectors,
+// e.g. avg(cpu{role="db"})[1h] by (host)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub function: Function,
+    pub field: Field,
+    pub selector: Selector,
+    pub range: u32, // seconds
+    pub by: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl Op {
+    fn apply(&self, a: f64, b: f64) -> f64 {
+        match self {
+            Op::Add => a + b,
+            Op::Sub => a - b,
+            Op::Mul => a * b,
+            Op::Div => a / b,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Call(Call),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    // depth is how many levels of operators the expression has. The parser
+    // never builds one deeper than MAX_DEPTH.
+    fn depth(&self) -> usize {
+        match self {
+            Expr::Number(_) | Expr::Call(_) => 0,
+            Expr::Binary(_, left, right) => 1 + left.depth().max(right.depth()),
+        }
+    }
+}
+
+// ParseError says what was wrong with a query and where, counting columns from 1
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.column)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(f64),
+    Duration(u32),
+    Str(String),
+    Symbol(&'static str),
+    End,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Ident(name) => write!(f, "{name:?}"),
+            Token::Number(n) => write!(f, "{n}"),
+            Token::Duration(seconds) => write!(f, "a {seconds}s duration"),
+            Token::Str(s) => write!(f, "the string {s:?}"),
+            Token::Symbol(s) => write!(f, "'{s}'"),
+            Token::End => write!(f, "the end of the query"),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 13] = ["!=", "(", ")", "{", "}", "[", "]", ",", "+", "-", "*", "/", "="];
+
+fn error<T>(column: usize, message: impl Into<String>) -> Result<T, ParseError> {
+    Err(ParseError {
+        column,
+        message: message.into(),
+    })
+}
+
+// tokenize splits a query into tokens, each with the column it starts at
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push((column, Token::Ident(chars[start..i].iter().collect())));
+        } else if c.is_ascii_digit() || c == '.' {
+            let (token, end) = number_or_duration(&chars, i)?;
+            tokens.push((column, token));
+            i = end;
+        } else if c == '"' {
+            let start = i + 1;
+            i = start;
+            while i < chars.len() && chars[i] != '"' {
+                i += 1;
+            }
+            if i == chars.len() {
+                return error(column, "unterminated string");
+            }
+            tokens.push((column, Token::Str(chars[start..i].iter().collect())));
+            i += 1;
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            match SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+                Some(symbol) => {
+                    tokens.push((column, Token::Symbol(symbol)));
+                    i += symbol.len();
+                }
+                None => return error(column, format!("unexpected character '{c}'")),
+            }
+        }
+    }
+    tokens.push((chars.len() + 1, Token::End));
+    Ok(tokens)
+}
+
+// number_or_duration reads a number, or a duration such as 1h30m when the
+// digits are followed by a unit
+fn number_or_duration(chars: &[char], start: usize) -> Result<(Token, usize), ParseError> {
+    let digits = |mut i: usize| {
+        while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+            i += 1;
+        }
+        i
+    };
+    let mut i = digits(start);
+    let text: String = chars[start..i].iter().collect();
+    let number: f64 = text
+        .parse()
+        .or_else(|_| error(start + 1, format!("{text:?} is not a number")))?;
+    if i == chars.len() || !chars[i].is_ascii_alphabetic() {
+        return Ok((Token::Number(number), i));
+    }
+
+    let mut seconds: u64 = 0;
+    let mut amount = text;
+    loop {
+        let unit_start = i;
+        while i < chars.len() && chars[i].is_ascii_alphabetic() {
+            i += 1;
+        }
+        let unit: String = chars[unit_start..i].iter().collect();
+        let scale = match unit.as_str() {
+            "s" => 1,
+            "m" => 60,
+            "h" => 3600,
+            "d" => 86400,
+            "w" => 7 * 86400,
+            _ => return error(unit_start + 1, format!("unknown duration unit {unit:?}, expected s, m, h, d or w")),
+        };
+        let whole: u64 = amount
+            .parse()
+            .or_else(|_| error(start + 1, format!("{amount:?} is not a whole number of {unit}")))?;
+        seconds = seconds.saturating_add(whole.saturating_mul(scale));
+        if i == chars.len() || !chars[i].is_ascii_digit() {
+            break;
+        }
+        let amount_start = i;
+        i = digits(i);
+        amount = chars[amount_start..i].iter().collect();
+    }
+    Ok((Token::Duration(seconds.min(u32::MAX as u64) as u32), i))
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+    // parentheses and negations currently open
+    nesting: usize,
+    calls: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position].1
+    }
+
+    fn column(&self) -> usize {
+        self.tokens[self.position].0
+    }
+
+    fn next(&mut self) -> (usize, Token) {
+        let token = self.tokens[self.position].clone();
+        if token.1 != Token::End {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn accept(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Token::Symbol(s) if *s == symbol) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &str, context: &str) -> Result<(), ParseError> {
+        if self.accept(symbol) {
+            return Ok(());
+        }
+        error(self.column(), format!("expected '{symbol}' {context}, found {}", self.peek()))
+    }
+
+    // binary combines two operands, refusing to nest deeper than MAX_DEPTH
+    fn binary(column: usize, op: Op, left: Expr, right: Expr) -> Result<Expr, ParseError> {
+        let expr = Expr::Binary(op, Box::new(left), Box::new(right));
+        if expr.depth() > MAX_DEPTH {
+            return error(column, format!("the query nests more than {MAX_DEPTH} operators deep"));
+        }
+        Ok(expr)
+    }
+
+    // nested parses what follows an opening parenthesis or a negation
+    fn nested<T>(&mut self, column: usize, parse: impl FnOnce(&mut Self) -> Result<T, ParseError>) -> Result<T, ParseError> {
+        if self.nesting == MAX_DEPTH {
+            return error(column, format!("the query nests more than {MAX_DEPTH} levels deep"));
+        }
+        self.nesting += 1;
+        let result = parse(self);
+        self.nesting -= 1;
+        result
+    }
+
+    // expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.term()?;
+        loop {
+            let column = self.column();
+            let op = if self.accept("+") {
+                Op::Add
+            } else if self.accept("-") {
+                Op::Sub
+            } else {
+                return Ok(left);
+            };
+            left = Self::binary(column, op, left, self.term()?)?;
+        }
+    }
+
+    // term := unary (('*' | '/') unary)*
+    fn term(&mut self) -> Result<Expr, ParseError> {
+        let mut left = self.unary()?;
+        loop {
+            let column = self.column();
+            let op = if self.accept("*") {
+                Op::Mul
+            } else if self.accept("/") {
+                Op::Div
+            } else {
+                return Ok(left);
+            };
+            left = Self::binary(column, op, left, self.unary()?)?;
+        }
+    }
+
+    // unary := '-' unary | number | '(' expr ')' | call
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        let column = self.column();
+        if self.accept("-") {
+            let operand = self.nested(column, Self::unary)?;
+            return Self::binary(column, Op::Sub, Expr::Number(0.0), operand);
+        }
+        if self.accept("(") {
+            return self.nested(column, |parser| {
+                let expr = parser.expr()?;
+                parser.expect(")", "to close the parenthesis")?;
+                Ok(expr)
+            });
+        }
+        match self.next() {
+            (_, Token::Number(n)) => Ok(Expr::Number(n)),
+            (column, Token::Ident(name)) => {
+                self.calls += 1;
+                if self.calls > MAX_CALLS {
+                    return error(column, format!("a query can have at most {MAX_CALLS} functions"));
+                }
+                Ok(Expr::Call(self.call(column, &name)?))
+            }
+            (column, token) => error(column, format!("expected a number or a function such as avg(cpu)[1h], found {token}")),
+        }
+    }
+
+    // call := function '(' [number ','] field [selector] ')' '[' duration ']' ['by' '(' names ')']
+    fn call(&mut self, column: usize, name: &str) -> Result<Call, ParseError> {
+        let function = match name {
+            "avg" => Function::Avg,
+            "min" => Function::Min,
+            "max" => Function::Max,
+            "rate" => Function::Rate,
+            "percentile" => Function::Percentile(0.0),
+            _ => {
+                return error(
+                    column,
+                    format!("unknown function {name:?}, expected avg, min, max, rate or percentile"),
+                )
+            }
+        };
+        self.expect("(", &format!("after {name}"))?;
+        let function = match function {
+            Function::Percentile(_) => match self.next() {
+                (_, Token::Number(p)) if p > 0.0 && p <= 100.0 => {
+                    self.expect(",", "after the percentile")?;
+                    Function::Percentile(p)
+                }
+                (column, token) => return error(column, format!("expected a percentile between 0 and 100, found {token}")),
+            },
+            function => function,
+        };
+        let field = match self.next() {
+            (column, Token::Ident(name)) => Field::parse(&name).ok_or_else(|| ParseError {
+                column,
+                message: format!("unknown metric {name:?}, expected cpu, memory, used_memory or total_memory"),
+            })?,
+            (column, token) => return error(column, format!("expected a metric such as cpu, found {token}")),
+        };
+        let selector = if self.accept("{") { self.selector()? } else { Selector::default() };
+        self.expect(")", &format!("to close {name}("))?;
+
+        self.expect("[", &format!("and a range such as [1h] after {name}(...)"))?;
+        let range = match self.next() {
+            (_, Token::Duration(seconds)) if seconds > 0 && seconds <= MAX_RANGE => seconds,
+            (column, Token::Duration(_)) => return error(column, "the range must be between 1s and 366d"),
+            (column, token) => return error(column, format!("expected a duration such as 5m or 1h, found {token}")),
+        };
+        self.expect("]", "after the range")?;
+
+        let mut by = Vec::new();
+        if matches!(self.peek(), Token::Ident(word) if word == "by") {
+            self.next();
+            self.expect("(", "after by")?;
+            loop {
+                match self.next() {
+                    (_, Token::Ident(name)) if labels::valid_name(&name) => by.push(name),
+                    (column, token) => return error(column, format!("expected a label name, found {token}")),
+                }
+                if !self.accept(",") {
+                    break;
+                }
+            }
+            self.expect(")", "after the group by labels")?;
+        }
+        Ok(Call {
+            function,
+            field,
+            selector,
+            range,
+            by,
+        })
+    }
+
+    // selector := '{' [name ('=' | '!=') value (',' name ('=' | '!=') value)*] '}'
+    fn selector(&mut self) -> Result<Selector, ParseError> {
+        let mut matchers = Vec::new();
+        while !self.accept("}") {
+            let name = match self.next() {
+                (_, Token::Ident(name)) => name,
+                (column, token) => return error(column, format!("expected a label name, found {token}")),
+            };
+            let negated = if self.accept("!=") {
+                true
+            } else {
+                self.expect("=", &format!("or '!=' after {name}"))?;
+                false
+            };
+            let value = match self.next() {
+                (_, Token::Str(value)) | (_, Token::Ident(value)) => value,
+                (column, token) => return error(column, format!("expected a quoted value for {name}, found {token}")),
+            };
+            matchers.push(Matcher { name, value, negated });
+            if !self.accept(",") {
+                self.expect("}", "to close the selector")?;
+                break;
+            }
+        }
+        Ok(Selector(matchers))
+    }
+}
+
+pub fn parse(text: &str) -> Result<Expr, ParseError> {
+    if text.chars().count() > MAX_QUERY_LENGTH {
+        return error(MAX_QUERY_LENGTH + 1, format!("the query is longer than {MAX_QUERY_LENGTH} characters"));
+    }
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        position: 0,
+        nesting: 0,
+        calls: 0,
+    };
+    let expr = parser.expr()?;
+    match parser.peek() {
+        Token::End => Ok(expr),
+        token => error(parser.column(), format!("expected an operator or the end of the query, found {token}")),
+    }
+}
+
+// Series is one group's values, as [timestamp, value] pairs in time order
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Series {
+    pub labels: Labels,
+    pub points: Vec<(u32, f64)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Value {
+    Scalar { value: f64 },
+    Vector { series: Vec<Series> },
+}
+
+// Window is the time the query is evaluated at and the width of its points.
+// Each point covers the `step` seconds up to and including its timestamp.
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    end: u32,
+    step: Option<u32>,
+}
+
+// plan turns a call into SQL returning one row per group and point. Samples
+// are numbered into buckets counting back from the end, so the points of
+// different calls line up.
+fn plan(call: &Call, window: Window, organisation: Option<&str>) -> QueryBuilder<'static, Sqlite> {
+    let step = window.step.unwrap_or(call.range);
+    let organisation = organisation.map(str::to_string);
+    let mut sql = QueryBuilder::new("WITH samples AS (SELECT t.collector_id, t.received, ");
+    sql.push(call.field.sql())
+        .push(" AS v, (")
+        .push_bind(window.end)
+        .push(" - t.received) / ")
+        .push_bind(step)
+        .push(" AS bucket");
+    for (i, name) in call.by.iter().enumerate() {
+        match name.as_str() {
+            COLLECTOR => sql.push(", t.collector_id"),
+            HOST => sql.push(", COALESCE(c.label, t.collector_id)"),
+            _ => sql
+                .push(", COALESCE((SELECT value FROM collector_labels l WHERE l.collector_id = t.collector_id AND l.name = ")
+                .push_bind(name.clone())
+                .push("), '')"),
+        };
+        sql.push(format!(" AS g{i}"));
+    }
+    sql.push(" FROM timeseries t JOIN collectors c ON c.collector_id = t.collector_id WHERE t.received > ")
+        .push_bind(window.end.saturating_sub(call.range))
+        .push(" AND t.received <= ")
+        .push_bind(window.end)
+        .push(" AND (")
+        .push_bind(organisation.clone())
+        .push(" IS NULL OR c.organisation = ")
+        .push_bind(organisation)
+        .push(") AND ");
+    call.selector.push_sql(&mut sql, "t.collector_id");
+    sql.push(") ");
+
+    let groups: String = (0..call.by.len()).map(|i| format!("g{i}, ")).collect();
+    match call.function {
+        Function::Avg | Function::Min | Function::Max => {
+            let aggregate = call.function.name().to_uppercase();
+            sql.push(format!(
+                "SELECT {groups}bucket, {aggregate}(v) AS value FROM samples GROUP BY {groups}bucket"
+            ));
+        }
+        Function::Percentile(p) => {
+            sql.push(format!(
+                "SELECT {groups}bucket, v AS value FROM (
+                    SELECT {groups}bucket, v,
+                        ROW_NUMBER() OVER (PARTITION BY {groups}bucket ORDER BY v) AS rank,
+                        COUNT(*) OVER (PARTITION BY {groups}bucket) AS n
+                    FROM samples
+                ) WHERE rank = MAX(1, -CAST(-("
+            ))
+            .push_bind(p)
+            .push(" * n / 100.0) AS INTEGER))");
+        }
+        Function::Rate => {
+            sql.push(format!(
+                "SELECT {groups}bucket, SUM(rate) AS value FROM (
+                    SELECT DISTINCT collector_id, {groups}bucket,
+                        (LAST_VALUE(v) OVER w - FIRST_VALUE(v) OVER w) / NULLIF(MAX(received) OVER w - MIN(received) OVER w, 0) AS rate
+                    FROM samples
+                    WINDOW w AS (PARTITION BY collector_id, bucket ORDER BY received ROWS BETWEEN UNBOUNDED PRECEDING AND UNBOUNDED FOLLOWING)
+                ) WHERE rate IS NOT NULL GROUP BY {groups}bucket"
+            ));
+        }
+    }
+    sql.push(format!(" ORDER BY {groups}bucket DESC"));
+    sql
+}
+
+async fn fetch(pool: &Pool<Sqlite>, call: &Call, window: Window, organisation: Option<&str>) -> sqlx::Result<Vec<Series>> {
+    let step = window.step.unwrap_or(call.range);
+    let mut series: Vec<Series> = Vec::new();
+    for row in plan(call, window, organisation).build().fetch_all(pool).await? {
+        let labels: Labels = call
+            .by
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.clone(), row.get::<String, _>(i)))
+            .collect();
+        let bucket: i64 = row.get(call.by.len());
+        let Some(value) = row.get::<Option<f64>, _>(call.by.len() + 1) else {
+            continue;
+        };
+        let point = (window.end - bucket as u32 * step, value);
+        match series.last_mut() {
+            Some(last) if last.labels == labels => last.points.push(point),
+            _ => series.push(Series {
+                labels,
+                points: vec![point],
+            }),
+        }
+    }
+    Ok(series)
+}
+
+// calls lists the calls in an expression in the order evaluate visits them
+fn calls<'a>(expr: &'a Expr, found: &mut Vec<&'a Call>) {
+    match expr {
+        Expr::Number(_) => {}
+        Expr::Call(call) => found.push(call),
+        Expr::Binary(_, left, right) => {
+            calls(left, found);
+            calls(right, found);
+        }
+    }
+}
+
+// evaluate combines the results of the calls, taken in order from `results`
+fn evaluate(expr: &Expr, results: &mut impl Iterator<Item = Vec<Series>>) -> Value {
+    match expr {
+        Expr::Number(value) => Value::Scalar { value: *value },
+        Expr::Call(_) => Value::Vector {
+            series: results.next().unwrap_or_default(),
+        },
+        Expr::Binary(op, left, right) => {
+            let left = evaluate(left, results);
+            let right = evaluate(right, results);
+            apply(*op, left, right)
+        }
+    }
+}
+
+// apply does arithmetic between scalars and series. Series are paired up by
+// their labels; a single series without labels, like avg(cpu)[1h], is paired
+// with every series on the other side.
+fn apply(op: Op, left: Value, right: Value) -> Value {
+    let map = |series: Vec<Series>, f: &dyn Fn(f64) -> f64| Value::Vector {
+        series: series
+            .into_iter()
+            .map(|s| Series {
+                points: s.points.into_iter().map(|(t, v)| (t, f(v))).filter(|(_, v)| v.is_finite()).collect(),
+                labels: s.labels,
+            })
+            .collect(),
+    };
+    match (left, right) {
+        (Value::Scalar { value: a }, Value::Scalar { value: b }) => Value::Scalar { value: op.apply(a, b) },
+        (Value::Vector { series }, Value::Scalar { value: b }) => map(series, &|a| op.apply(a, b)),
+        (Value::Scalar { value: a }, Value::Vector { series }) => map(series, &|b| op.apply(a, b)),
+        (Value::Vector { series: left }, Value::Vector { series: right }) => {
+            let single = |side: &[Series]| side.len() == 1 && side[0].labels.is_empty();
+            let pairs: Vec<(&Series, &Series, &Labels)> = if single(&right) {
+                left.iter().map(|l| (l, &right[0], &l.labels)).collect()
+            } else if single(&left) {
+                right.iter().map(|r| (&left[0], r, &r.labels)).collect()
+            } else {
+                left.iter()
+                    .filter_map(|l| right.iter().find(|r| r.labels == l.labels).map(|r| (l, r, &l.labels)))
+                    .collect()
+            };
+            Value::Vector {
+                series: pairs.into_iter().map(|(l, r, labels)| join(op, l, r, labels)).collect(),
+            }
+        }
+    }
+}
+
+// join applies `op` to the points of two series that have the same timestamp
+fn join(op: Op, left: &Series, right: &Series, labels: &Labels) -> Series {
+    let points = left
+        .points
+        .iter()
+        .filter_map(|(t, a)| {
+            let (_, b) = right.points.iter().find(|(u, _)| u == t)?;
+            Some((*t, op.apply(*a, *b)))
+        })
+        .filter(|(_, v)| v.is_finite())
+        .collect();
+    Series {
+        labels: labels.clone(),
+        points,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryRequest {
+    query: String,
+    // when the query is evaluated, seconds since the Unix epoch; defaults to now
+    time: Option<u32>,
+    // seconds per point; without it each range is a single point
+    step: Option<u32>,
+}
+
+// query evaluates an expression in the query language over the collectors the caller can see
+pub async fn query(
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    Query(request): Query<QueryRequest>,
+) -> Result<Json<Value>, ApiError> {
+    let expr = parse(&request.query).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let window = Window {
+        end: request.time.unwrap_or_else(registry::unix_now),
+        step: request.step,
+    };
+    let mut found = Vec::new();
+    calls(&expr, &mut found);
+    let mut results = Vec::with_capacity(found.len());
+    for call in found {
+        if let Some(step) = window.step {
+            if step == 0 || call.range / step > MAX_POINTS {
+                return Err(ApiError::BadRequest(format!(
+                    "step must be positive and give at most {MAX_POINTS} points over each range"
+                )));
+            }
+        }
+        results.push(fetch(&pool, call, window, identity.organisation()).await?);
+    }
+    Ok(Json(evaluate(&expr, &mut results.into_iter())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn call(function: Function, field: Field, range: u32) -> Call {
+        Call {
+            function,
+            field,
+            selector: Selector::default(),
+            range,
+            by: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let expr = parse(r#"avg(cpu{role="db"})[1h] by (host)"#).unwrap();
+        assert_eq!(
+            expr,
+            Expr::Call(Call {
+                selector: Selector::parse("role=db").unwrap(),
+                by: vec!["host".to_string()],
+                ..call(Function::Avg, Field::Cpu, 3600)
+            })
+        );
+
+        // Multiplication binds tighter than subtraction
+        let expr = parse("max(memory)[1d12h] - 2 * percentile(95, memory)[5m]").unwrap();
+        let Expr::Binary(Op::Sub, left, right) = expr else { panic!() };
+        assert_eq!(*left, Expr::Call(call(Function::Max, Field::Memory, 36 * 3600)));
+        assert_eq!(
+            *right,
+            Expr::Binary(
+                Op::Mul,
+                Box::new(Expr::Number(2.0)),
+                Box::new(Expr::Call(call(Function::Percentile(95.0), Field::Memory, 300)))
+            )
+        );
+        assert_eq!(parse("-(1 + 2)").unwrap(), parse("0 - (1 + 2)").unwrap());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let message = |query: &str| parse(query).unwrap_err().to_string();
+        assert_eq!(
+            message("mean(cpu)[1h]"),
+            r#"unknown function "mean", expected avg, min, max, rate or percentile at column 1"#
+        );
+        assert_eq!(message("avg(cpu)"), "expected '[' and a range such as [1h] after avg(...), found the end of the query at column 9");
+        assert_eq!(message("avg(cpu)[1y]"), r#"unknown duration unit "y", expected s, m, h, d or w at column 11"#);
+        assert_eq!(message("avg(disk)[1h]"), r#"unknown metric "disk", expected cpu, memory, used_memory or total_memory at column 5"#);
+        assert_eq!(message(r#"avg(cpu{role="db)[1h]"#), "unterminated string at column 14");
+        assert_eq!(message(r#"avg(cpu{role})[1h]"#), "expected '=' or '!=' after role, found '}' at column 13");
+        assert_eq!(message("percentile(cpu)[1h]"), r#"expected a percentile between 0 and 100, found "cpu" at column 12"#);
+        assert_eq!(message("avg(cpu)[1h] by host"), r#"expected '(' after by, found "host" at column 17"#);
+        assert_eq!(message("avg(cpu)[1h] avg(cpu)[1h]"), r#"expected an operator or the end of the query, found "avg" at column 14"#);
+        assert_eq!(message("1 + $"), "unexpected character '$' at column 5");
+
+        // Queries that would overflow the stack are refused
+        assert_eq!(message(&"1".repeat(5000)), "the query is longer than 4096 characters at column 4097");
+        let negations = format!("{}1", "-".repeat(4000));
+        assert_eq!(message(&negations), "the query nests more than 64 levels deep at column 65");
+        let parentheses = format!("{}1{}", "(".repeat(100), ")".repeat(100));
+        assert_eq!(message(&parentheses), "the query nests more than 64 levels deep at column 65");
+        let sums = format!("1{}", " + 1".repeat(100));
+        assert_eq!(message(&sums), "the query nests more than 64 operators deep at column 259");
+        assert!(parse(&format!("{}1", "-".repeat(MAX_DEPTH))).is_ok());
+        let calls = ["avg(cpu)[1h]"; MAX_CALLS + 1].join(" + ");
+        assert_eq!(message(&calls), "a query can have at most 10 functions at column 151");
+        assert!(parse(&calls[..calls.len() - " + avg(cpu)[1h]".len()]).is_ok());
+    }
+
+    async fn pool() -> Pool<Sqlite> {
+        let pool = testing::memory_pool().await;
+        // Two database hosts and a web server, sampled every 100 seconds
+        let hosts = [
+            ("00000000-0000-0000-0000-000000000001", "db", "db-1", 10.0, 1000),
+            ("00000000-0000-0000-0000-000000000002", "db", "db-2", 30.0, 3000),
+            ("00000000-0000-0000-0000-000000000003", "web", "web-1", 90.0, 0),
+        ];
+        let mut cnn = pool.acquire().await.unwrap();
+        for (collector_id, role, _, cpu, growth) in hosts {
+            for i in 1..=10u32 {
+                sqlx::query(
+                    "INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES (?, ?, 100000, ?, ?)",
+                )
+                .bind(collector_id)
+                .bind(i * 100)
+                .bind(i * growth)
+                .bind(cpu + i as f32)
+                .execute(&mut *cnn)
+                .await
+                .unwrap();
+                registry::record_sample(&mut cnn, collector_id, "default", 1, i * 100).await.unwrap();
+            }
+            let labels: Labels = [("role".to_string(), role.to_string())].into();
+            registry::set_labels(&mut cnn, collector_id, &labels).await.unwrap();
+        }
+        drop(cnn);
+        for (collector_id, _, name, _, _) in hosts {
+            registry::rename(&pool, collector_id, Some(name)).await.unwrap();
+        }
+        pool
+    }
+
+    async fn run(pool: &Pool<Sqlite>, query: &str, step: Option<u32>) -> Value {
+        let expr = parse(query).unwrap();
+        let mut found = Vec::new();
+        calls(&expr, &mut found);
+        let window = Window { end: 1000, step };
+        let mut results = Vec::new();
+        for call in found {
+            results.push(fetch(pool, call, window, None).await.unwrap());
+        }
+        evaluate(&expr, &mut results.into_iter())
+    }
+
+    fn vector(value: Value) -> Vec<Series> {
+        match value {
+            Value::Vector { series } => series,
+            Value::Scalar { .. } => panic!("expected a vector"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_functions() {
+        let pool = pool().await;
+        let series = vector(run(&pool, r#"avg(cpu{role="db"})[1h] by (host)"#, None).await);
+        assert_eq!(series.len(), 2);
+        assert_eq!(series[0].labels["host"], "db-1");
+        assert_eq!(series[0].points, vec![(1000, 15.5)]);
+        assert_eq!(series[1].points, vec![(1000, 35.5)]);
+
+        let series = vector(run(&pool, "max(cpu)[1h] by (role)", None).await);
+        assert_eq!(series[0].labels["role"], "db");
+        assert_eq!(series[0].points[0].1, 40.0);
+        assert_eq!(series[1].points[0].1, 100.0);
+
+        // The 50th percentile of 11..20 and 31..40 is 20
+        let series = vector(run(&pool, r#"percentile(50, cpu{role="db"})[1h]"#, None).await);
+        assert_eq!(series[0].points[0].1, 20.0);
+
+        // db-1 and db-2 together grow by 10 + 30 bytes a second
+        let series = vector(run(&pool, "rate(used_memory)[1h] by (role)", None).await);
+        assert_eq!(series[0].points[0].1, 40.0);
+        assert_eq!(series[1].points[0].1, 0.0);
+
+        // Only the samples in the range count
+        let series = vector(run(&pool, r#"avg(cpu{host!="x"})[5m] by (collector)"#, None).await);
+        assert_eq!(series[0].points, vec![(1000, 19.0)]);
+    }
+
+    #[tokio::test]
+    async fn test_steps_and_arithmetic() {
+        let pool = pool().await;
+        // Points every 500 seconds, each covering the samples up to it
+        let series = vector(run(&pool, r#"avg(cpu{role="web"})[1000s]"#, Some(500)).await);
+        assert_eq!(series[0].points, vec![(500, 93.0), (1000, 98.0)]);
+
+        // Each database host's CPU compared to the average of all of them
+        let series = vector(run(&pool, r#"avg(cpu{role="db"})[1h] by (host) - avg(cpu{role="db"})[1h]"#, None).await);
+        assert_eq!(series[0].points, vec![(1000, -10.0)]);
+        assert_eq!(series[1].points, vec![(1000, 10.0)]);
+
+        // Series are paired by labels, and division by zero leaves a gap
+        let series = vector(run(&pool, "rate(used_memory)[1h] by (role) / rate(used_memory)[1h] by (role) * 100", None).await);
+        assert_eq!(series[0].points, vec![(1000, 100.0)]);
+        assert!(series[1].points.is_empty());
+
+        assert_eq!(run(&pool, "2 * (3 + 4)", None).await, Value::Scalar { value: 14.0 });
+    }
+}
@@ -0,0 +1,358 @@
+use crate::labels::Labels;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Sqlite, SqliteConnection};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CollectorStatus {
+    Online,
+    Stale,
+    Offline,
+}
+
+// Thresholds decide how long a collector may be silent before it counts as
+// stale or offline. Both are in seconds since the last sample.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Thresholds {
+    pub stale_after: u32,
+    pub offline_after: u32,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            stale_after: 30,
+            offline_after: 300,
+        }
+    }
+}
+
+impl Thresholds {
+    pub fn status(&self, last_seen: u32, now: u32) -> CollectorStatus {
+        let silent = now.saturating_sub(last_seen);
+        if silent >= self.offline_after {
+            CollectorStatus::Offline
+        } else if silent >= self.stale_after {
+            CollectorStatus::Stale
+        } else {
+            CollectorStatus::Online
+        }
+    }
+}
+
+#[derive(FromRow, Debug)]
+struct CollectorRow {
+    collector_id: String,
+    label: Option<String>,
+    version: i64,
+    first_seen: i64,
+    last_seen: i64,
+    decommissioned: bool,
+    organisation: String,
+    tags: Option<String>,
+    labels: String,
+}
+
+// Collector is a registry entry as returned by the API
+#[derive(Debug, Clone, Serialize)]
+pub struct Collector {
+    pub collector_id: String,
+    pub label: Option<String>,
+    pub version: u16,
+    pub first_seen: u32,
+    pub last_seen: u32,
+    pub status: CollectorStatus,
+    pub decommissioned: bool,
+    pub organisation: String,
+    pub tags: Vec<String>,
+    pub labels: Labels,
+}
+
+pub fn unix_now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs() as u32
+}
+
+// record_sample registers the collector in `organisation` on its first sample
+// and bumps last_seen afterwards. A decommissioned collector that reports
+// again is brought back. It returns the organisation the collector is in,
+// which is `organisation` only if the collector is new.
+pub async fn record_sample(
+    cnn: &mut SqliteConnection,
+    collector_id: &str,
+    organisation: &str,
+    version: u16,
+    received: u32,
+) -> sqlx::Result<String> {
+    let (organisation,): (String,) = sqlx::query_as(
+        "INSERT INTO collectors (collector_id, organisation, version, first_seen, last_seen) VALUES (?, ?, ?, ?, ?)
+        ON CONFLICT (collector_id) DO UPDATE SET
+            version = excluded.version,
+            last_seen = MAX(last_seen, excluded.last_seen),
+            decommissioned = FALSE
+        RETURNING organisation",
+    )
+    .bind(collector_id)
+    .bind(organisation)
+    .bind(version)
+    .bind(received)
+    .bind(received)
+    .fetch_one(cnn)
+    .await?;
+    Ok(organisation)
+}
+
+const SELECT_COLLECTORS: &str = "SELECT
+    c.collector_id, c.label, c.version, c.first_seen, c.last_seen, c.decommissioned, c.organisation,
+    (SELECT GROUP_CONCAT(tag, ',') FROM collector_tags t WHERE t.collector_id = c.collector_id) AS tags,
+    (SELECT json_group_object(name, value) FROM collector_labels l WHERE l.collector_id = c.collector_id) AS labels
+    FROM collectors c";
+
+fn to_collector(row: CollectorRow, thresholds: &Thresholds, now: u32) -> Collector {
+    Collector {
+        status: thresholds.status(row.last_seen as u32, now),
+        tags: row
+            .tags
+            .map(|tags| tags.split(',').map(str::to_string).collect())
+            .unwrap_or_default(),
+        labels: serde_json::from_str(&row.labels).unwrap_or_default(),
+        collector_id: row.collector_id,
+        label: row.label,
+        version: row.version as u16,
+        first_seen: row.first_seen as u32,
+        last_seen: row.last_seen as u32,
+        decommissioned: row.decommissioned,
+        organisation: row.organisation,
+    }
+}
+
+// list returns the collectors in an organisation, or every collector for
+// None, most recently seen first
+pub async fn list(
+    pool: &Pool<Sqlite>,
+    thresholds: &Thresholds,
+    organisation: Option<&str>,
+) -> sqlx::Result<Vec<Collector>> {
+    let rows = sqlx::query_as::<_, CollectorRow>(&format!(
+        "{SELECT_COLLECTORS} WHERE ? IS NULL OR c.organisation = ? ORDER BY c.last_seen DESC"
+    ))
+        .bind(organisation)
+        .bind(organisation)
+        .fetch_all(pool)
+        .await?;
+    let now = unix_now();
+    Ok(rows.into_iter().map(|row| to_collector(row, thresholds, now)).collect())
+}
+
+pub async fn get(pool: &Pool<Sqlite>, thresholds: &Thresholds, collector_id: &str) -> sqlx::Result<Option<Collector>> {
+    let row = sqlx::query_as::<_, CollectorRow>(&format!("{SELECT_COLLECTORS} WHERE c.collector_id = ?"))
+        .bind(collector_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.map(|row| to_collector(row, thresholds, unix_now())))
+}
+
+// organisation_of returns the organisation a collector belongs to, if it is registered
+pub async fn organisation_of(pool: &Pool<Sqlite>, collector_id: &str) -> sqlx::Result<Option<String>> {
+    let row: Option<(String,)> = sqlx::query_as("SELECT organisation FROM collectors WHERE collector_id = ?")
+        .bind(collector_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.map(|(organisation,)| organisation))
+}
+
+// The mutations below return false when the collector is not registered
+
+pub async fn set_organisation(pool: &Pool<Sqlite>, collector_id: &str, organisation: &str) -> sqlx::Result<bool> {
+    let result = sqlx::query("UPDATE collectors SET organisation = ? WHERE collector_id = ?")
+        .bind(organisation)
+        .bind(collector_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn rename(pool: &Pool<Sqlite>, collector_id: &str, label: Option<&str>) -> sqlx::Result<bool> {
+    let result = sqlx::query("UPDATE collectors SET label = ? WHERE collector_id = ?")
+        .bind(label)
+        .bind(collector_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn decommission(pool: &Pool<Sqlite>, collector_id: &str) -> sqlx::Result<bool> {
+    let result = sqlx::query("UPDATE collectors SET decommissioned = TRUE WHERE collector_id = ?")
+        .bind(collector_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+const MAX_TAG: usize = 64;
+
+// valid_tag rejects empty tags and tags with commas, which would be split in
+// two when the tags are read back
+pub fn valid_tag(tag: &str) -> bool {
+    !tag.trim().is_empty() && tag.len() <= MAX_TAG && !tag.chars().any(|c| c == ',' || c.is_control())
+}
+
+pub async fn add_tag(pool: &Pool<Sqlite>, collector_id: &str, tag: &str) -> sqlx::Result<bool> {
+    let result = sqlx::query(
+        "INSERT OR IGNORE INTO collector_tags (collector_id, tag)
+        SELECT collector_id, ? FROM collectors WHERE collector_id = ?",
+    )
+    .bind(tag)
+    .bind(collector_id)
+    .execute(pool)
+    .await?;
+    // The insert is ignored for a tag that is already there, so check the collector exists
+    Ok(result.rows_affected() > 0 || exists(pool, collector_id).await?)
+}
+
+pub async fn remove_tag(pool: &Pool<Sqlite>, collector_id: &str, tag: &str) -> sqlx::Result<bool> {
+    let result = sqlx::query("DELETE FROM collector_tags WHERE collector_id = ? AND tag = ?")
+        .bind(collector_id)
+        .bind(tag)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// set_labels adds labels to a collector, replacing the values of labels it already has
+pub async fn set_labels(cnn: &mut SqliteConnection, collector_id: &str, labels: &Labels) -> sqlx::Result<bool> {
+    for (name, value) in labels {
+        sqlx::query(
+            "INSERT INTO collector_labels (collector_id, name, value)
+            SELECT collector_id, ?, ? FROM collectors WHERE collector_id = ?
+            ON CONFLICT (collector_id, name) DO UPDATE SET value = excluded.value",
+        )
+        .bind(name)
+        .bind(value)
+        .bind(collector_id)
+        .execute(&mut *cnn)
+        .await?;
+    }
+    let row: Option<(i64,)> = sqlx::query_as("SELECT 1 FROM collectors WHERE collector_id = ?")
+        .bind(collector_id)
+        .fetch_optional(cnn)
+        .await?;
+    Ok(row.is_some())
+}
+
+pub async fn remove_label(pool: &Pool<Sqlite>, collector_id: &str, name: &str) -> sqlx::Result<bool> {
+    let result = sqlx::query("DELETE FROM collector_labels WHERE collector_id = ? AND name = ?")
+        .bind(collector_id)
+        .bind(name)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// delete removes the collector, its tags, its labels and all of its data
+pub async fn delete(pool: &Pool<Sqlite>, collector_id: &str) -> sqlx::Result<bool> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM timeseries WHERE collector_id = ?")
+        .bind(collector_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM collector_tags WHERE collector_id = ?")
+        .bind(collector_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM collector_labels WHERE collector_id = ?")
+        .bind(collector_id)
+        .execute(&mut *tx)
+        .await?;
+    let result = sqlx::query("DELETE FROM collectors WHERE collector_id = ?")
+        .bind(collector_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(result.rows_affected() > 0)
+}
+
+async fn exists(pool: &Pool<Sqlite>, collector_id: &str) -> sqlx::Result<bool> {
+    let row: Option<(i64,)> = sqlx::query_as("SELECT 1 FROM collectors WHERE collector_id = ?")
+        .bind(collector_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn test_status_thresholds() {
+        let thresholds = Thresholds {
+            stale_after: 30,
+            offline_after: 300,
+        };
+        assert_eq!(thresholds.status(1000, 1010), CollectorStatus::Online);
+        assert_eq!(thresholds.status(1000, 1030), CollectorStatus::Stale);
+        assert_eq!(thresholds.status(1000, 1300), CollectorStatus::Offline);
+        // A collector with a clock slightly ahead of ours is still online
+        assert_eq!(thresholds.status(1010, 1000), CollectorStatus::Online);
+    }
+
+    #[test]
+    fn test_valid_tag() {
+        assert!(valid_tag("prod"));
+        assert!(valid_tag("eu west"));
+        assert!(!valid_tag(""));
+        assert!(!valid_tag(" "));
+        assert!(!valid_tag("a,b"));
+        assert!(!valid_tag("a\nb"));
+        assert!(!valid_tag(&"x".repeat(MAX_TAG + 1)));
+    }
+
+    #[tokio::test]
+    async fn test_registry_lifecycle() {
+        let pool = testing::memory_pool().await;
+        let thresholds = Thresholds::default();
+
+        let mut cnn = pool.acquire().await.unwrap();
+        record_sample(&mut cnn, "c1", "acme", 1, 100).await.unwrap();
+        assert_eq!(record_sample(&mut cnn, "c1", "other", 1, 200).await.unwrap(), "acme");
+        drop(cnn);
+
+        let collector = get(&pool, &thresholds, "c1").await.unwrap().unwrap();
+        assert_eq!((collector.first_seen, collector.last_seen), (100, 200));
+        assert_eq!(collector.status, CollectorStatus::Offline);
+        // The organisation is only set when the collector is first seen
+        assert_eq!(collector.organisation, "acme");
+        assert_eq!(list(&pool, &thresholds, Some("acme")).await.unwrap().len(), 1);
+        assert!(list(&pool, &thresholds, Some("other")).await.unwrap().is_empty());
+
+        assert!(rename(&pool, "c1", Some("db-1")).await.unwrap());
+        assert!(add_tag(&pool, "c1", "prod").await.unwrap());
+        assert!(add_tag(&pool, "c1", "prod").await.unwrap());
+        assert!(!add_tag(&pool, "missing", "prod").await.unwrap());
+        let mut cnn = pool.acquire().await.unwrap();
+        let labels: Labels = [("role".to_string(), "db".to_string()), ("region".to_string(), "eu".to_string())].into();
+        assert!(set_labels(&mut cnn, "c1", &labels).await.unwrap());
+        let relabel: Labels = [("region".to_string(), "us".to_string())].into();
+        assert!(set_labels(&mut cnn, "c1", &relabel).await.unwrap());
+        assert!(!set_labels(&mut cnn, "missing", &labels).await.unwrap());
+        drop(cnn);
+        assert!(remove_label(&pool, "c1", "role").await.unwrap());
+        assert!(!remove_label(&pool, "c1", "role").await.unwrap());
+        assert!(decommission(&pool, "c1").await.unwrap());
+        let collector = get(&pool, &thresholds, "c1").await.unwrap().unwrap();
+        assert_eq!(collector.label.as_deref(), Some("db-1"));
+        assert_eq!(collector.tags, vec!["prod".to_string()]);
+        assert_eq!(collector.labels, relabel);
+        assert!(collector.decommissioned);
+
+        assert!(delete(&pool, "c1").await.unwrap());
+        assert!(get(&pool, &thresholds, "c1").await.unwrap().is_none());
+        assert!(!delete(&pool, "c1").await.unwrap());
+    }
+}
@@ -0,0 +1,52 @@
+use crate::config::RetentionConfig;
+use crate::registry::unix_now;
+use sqlx::{Pool, Sqlite};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+// expire deletes samples older than the retention period and returns how many were removed
+pub async fn expire(pool: &Pool<Sqlite>, days: u32, now: u32) -> sqlx::Result<u64> {
+    let cutoff = now.saturating_sub(days.saturating_mul(86400));
+    let result = sqlx::query("DELETE FROM timeseries WHERE received < ?")
+        .bind(cutoff)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+// enforce expires old samples every `interval_seconds` until shutdown
+pub async fn enforce(pool: Pool<Sqlite>, config: RetentionConfig, shutdown: CancellationToken) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_seconds));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+        match expire(&pool, config.days, unix_now()).await {
+            Ok(0) => {}
+            Ok(deleted) => println!("Retention: deleted {deleted} samples older than {} days", config.days),
+            Err(e) => eprintln!("Retention: failed to delete old samples: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[tokio::test]
+    async fn test_expire() {
+        let pool = testing::memory_pool().await;
+        for received in [100_000u32, 200_000, 300_000] {
+            sqlx::query("INSERT INTO timeseries (collector_id, received, total_memory, used_memory, average_cpu) VALUES ('c1', ?, 1, 1, 1)")
+                .bind(received)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        // One day of retention at 300000 keeps everything received since 213600
+        assert_eq!(expire(&pool, 1, 300_000).await.unwrap(), 2);
+        assert_eq!(expire(&pool, 1, 300_000).await.unwrap(), 0);
+    }
+}
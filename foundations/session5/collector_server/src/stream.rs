@@ -0,0 +1,207 @@
+use crate::alerts::Metric;
+use crate::auth::Identity;
+use crate::error::{collector_id, ApiError};
+use crate::collector::Sample;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::Response;
+use axum::Extension;
+use futures::Stream;
+use serde::Deserialize;
+use std::convert::Infallible;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_util::sync::CancellationToken;
+
+// Every ingested sample is published on this channel. A receiver that falls
+// more than SAMPLE_BUFFER samples behind skips ahead instead of holding up
+// ingestion.
+pub const SAMPLE_BUFFER: usize = 1024;
+
+pub type SampleSender = broadcast::Sender<Sample>;
+
+pub fn channel() -> SampleSender {
+    broadcast::channel(SAMPLE_BUFFER).0
+}
+
+// StreamFilter holds the optional query parameters a subscriber can use to
+// only receive interesting samples, e.g. `?cpu_above=80`
+#[derive(Debug, Default, Deserialize)]
+pub struct StreamFilter {
+    cpu_above: Option<f32>,
+    memory_above: Option<f32>,
+}
+
+impl StreamFilter {
+    fn matches(&self, sample: &Sample) -> bool {
+        self.cpu_above.is_none_or(|above| Metric::Cpu.value(sample) > above)
+            && self.memory_above.is_none_or(|above| Metric::Memory.value(sample) > above)
+    }
+}
+
+// subscribable checks the caller may see the collector before it subscribes
+// and returns its id
+async fn subscribable(pool: &sqlx::SqlitePool, identity: &Identity, uuid: &str) -> Result<String, ApiError> {
+    let collector_id = collector_id(uuid)?;
+    match crate::registry::organisation_of(pool, &collector_id).await? {
+        Some(organisation) if identity.can_see(&organisation) => Ok(collector_id),
+        // With authentication off, a collector that hasn't reported yet can still be watched
+        None if identity.organisation().is_none() => Ok(collector_id),
+        _ => Err(ApiError::NotFound(format!("collector {collector_id} does not exist"))),
+    }
+}
+
+// Update is what a subscriber receives: either a sample or a note saying how
+// many samples it missed because it was too slow
+enum Update {
+    Sample(Sample),
+    Lagged(u64),
+}
+
+// next_update waits for the next sample for this collector that passes the
+// filter. It returns None once the channel is closed.
+async fn next_update(rx: &mut broadcast::Receiver<Sample>, collector_id: &str, filter: &StreamFilter) -> Option<Update> {
+    loop {
+        match rx.recv().await {
+            Ok(sample) if sample.collector_id == collector_id && filter.matches(&sample) => {
+                return Some(Update::Sample(sample))
+            }
+            Ok(_) => continue,
+            Err(RecvError::Lagged(skipped)) => return Some(Update::Lagged(skipped)),
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+// sse streams a collector's samples as Server-Sent Events. The stream ends
+// when the server shuts down.
+pub async fn sse(
+    Extension(samples): Extension<SampleSender>,
+    Extension(shutdown): Extension<CancellationToken>,
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    uuid: Path<String>,
+    Query(filter): Query<StreamFilter>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let collector_id = subscribable(&pool, &identity, &uuid).await?;
+    let state = (samples.subscribe(), collector_id, filter, shutdown);
+    let stream = futures::stream::unfold(state, |(mut rx, collector_id, filter, shutdown)| async move {
+        let update = tokio::select! {
+            update = next_update(&mut rx, &collector_id, &filter) => update?,
+            _ = shutdown.cancelled() => return None,
+        };
+        let event = match update {
+            Update::Sample(sample) => Event::default().event("sample").json_data(&sample).unwrap(),
+            Update::Lagged(skipped) => Event::default().event("lagged").data(skipped.to_string()),
+        };
+        Some((Ok(event), (rx, collector_id, filter, shutdown)))
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+// ws streams a collector's samples over a WebSocket as JSON text messages
+pub async fn ws(
+    Extension(samples): Extension<SampleSender>,
+    Extension(shutdown): Extension<CancellationToken>,
+    Extension(pool): Extension<sqlx::SqlitePool>,
+    Extension(identity): Extension<Identity>,
+    uuid: Path<String>,
+    Query(filter): Query<StreamFilter>,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, ApiError> {
+    let collector_id = subscribable(&pool, &identity, &uuid).await?;
+    let rx = samples.subscribe();
+    Ok(upgrade.on_upgrade(move |socket| ws_connection(socket, rx, collector_id, filter, shutdown)))
+}
+
+async fn ws_connection(
+    mut socket: WebSocket,
+    mut rx: broadcast::Receiver<Sample>,
+    collector_id: String,
+    filter: StreamFilter,
+    shutdown: CancellationToken,
+) {
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => {
+                let _ = socket.send(Message::Close(None)).await;
+                return;
+            }
+            update = next_update(&mut rx, &collector_id, &filter) => {
+                let text = match update {
+                    Some(Update::Sample(sample)) => serde_json::to_string(&sample).unwrap(),
+                    Some(Update::Lagged(skipped)) => serde_json::json!({ "lagged": skipped }).to_string(),
+                    None => return,
+                };
+                if socket.send(Message::Text(text.into())).await.is_err() {
+                    return; // The client went away
+                }
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {} // Subscribers have nothing to say to us
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use axum::{routing::get, Router};
+
+    fn sample(collector_id: &str, average_cpu: f32) -> Sample {
+        Sample {
+            collector_id: collector_id.to_string(),
+            received: 1,
+            total_memory: 100,
+            used_memory: 50,
+            average_cpu,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filter_and_lag() {
+        let (tx, mut rx) = broadcast::channel(2);
+        let filter = StreamFilter {
+            cpu_above: Some(50.0),
+            memory_above: None,
+        };
+        tx.send(sample("other", 90.0)).unwrap();
+        tx.send(sample("c1", 10.0)).unwrap();
+        tx.send(sample("c1", 90.0)).unwrap();
+
+        // The first sample was pushed out of the buffer before we read it
+        assert!(matches!(next_update(&mut rx, "c1", &filter).await, Some(Update::Lagged(1))));
+        match next_update(&mut rx, "c1", &filter).await {
+            Some(Update::Sample(sample)) => assert_eq!(sample.average_cpu, 90.0),
+            _ => panic!("expected a sample"),
+        }
+        drop(tx);
+        assert!(next_update(&mut rx, "c1", &filter).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sse_endpoint() {
+        let samples = channel();
+        let pool = testing::memory_pool().await;
+        let app = Router::new()
+            .route("/sse/collector/{uuid}", get(sse))
+            .layer(Extension(samples.clone()))
+            .layer(Extension(CancellationToken::new()))
+            .layer(Extension(Identity::unrestricted()))
+            .layer(Extension(pool));
+        let addr = testing::serve(app).await;
+
+        let id = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        let response = reqwest::get(format!("http://{addr}/sse/collector/not-a-uuid")).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        let mut response = reqwest::get(format!("http://{addr}/sse/collector/{id}")).await.unwrap();
+        samples.send(sample(id, 42.0)).unwrap();
+        let chunk = response.chunk().await.unwrap().unwrap();
+        let chunk = String::from_utf8_lossy(&chunk);
+        assert!(chunk.starts_with("event: sample\n"));
+        assert!(chunk.contains("\"average_cpu\":42.0"));
+    }
+}
@@ -0,0 +1,196 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+// How long to wait for tasks to finish once shutdown has started
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// A task that stays up this long is considered healthy again, however often
+// it failed before
+const HEALTHY_PERIOD: Duration = Duration::from_secs(60);
+
+// Restart says what to do when a supervised task fails
+#[derive(Debug, Clone, Copy)]
+pub enum Restart {
+    // Shut the whole server down
+    Never,
+    // Start the task again after a growing delay, giving up after `max` failures
+    // in a row. A run that returns Ok or stays up for HEALTHY_PERIOD starts the
+    // count, and the delay, over.
+    OnError { max: u32 },
+}
+
+// Supervisor runs the server's long-lived tasks. Every task is handed the
+// shutdown token and is expected to stop cleanly once it is cancelled.
+pub struct Supervisor {
+    shutdown: CancellationToken,
+    tasks: JoinSet<(&'static str, anyhow::Result<()>)>,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self {
+            shutdown: CancellationToken::new(),
+            tasks: JoinSet::new(),
+        }
+    }
+
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    // spawn starts a task. `make` is called again for every restart.
+    pub fn spawn<F, Fut>(&mut self, name: &'static str, restart: Restart, make: F)
+    where
+        F: Fn(CancellationToken) -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send,
+    {
+        let shutdown = self.shutdown.clone();
+        self.tasks.spawn(async move {
+            let mut failures = 0;
+            loop {
+                let started = Instant::now();
+                let result = make(shutdown.clone()).await;
+                if shutdown.is_cancelled() {
+                    return (name, result);
+                }
+                if result.is_ok() || started.elapsed() >= HEALTHY_PERIOD {
+                    failures = 0;
+                }
+                // A task that returns before shutdown has stopped doing its job
+                let error = result.err().unwrap_or_else(|| anyhow::anyhow!("exited unexpectedly"));
+                failures += 1;
+                match restart {
+                    Restart::OnError { max } if failures <= max => {
+                        let delay = Duration::from_millis(250) * 2u32.pow((failures - 1).min(8));
+                        eprintln!("{name} failed ({failures}/{max}), restarting in {delay:?}: {error:#}");
+                        tokio::select! {
+                            _ = tokio::time::sleep(delay) => {}
+                            _ = shutdown.cancelled() => return (name, Ok(())),
+                        }
+                    }
+                    _ => return (name, Err(error)),
+                }
+            }
+        });
+    }
+
+    // run waits for a shutdown signal or for a task to fail for good, then
+    // cancels every task and waits for them to drain. The first error is returned.
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        let mut first_error = None;
+        tokio::select! {
+            _ = shutdown_signal() => println!("Shutting down"),
+            Some(joined) = self.tasks.join_next() => {
+                first_error = Some(task_error(joined));
+                eprintln!("Shutting down: {:?}", first_error.as_ref().unwrap());
+            }
+        }
+        self.shutdown.cancel();
+
+        let drain = async {
+            while let Some(joined) = self.tasks.join_next().await {
+                if let Err(e) = task_error(joined) {
+                    eprintln!("{e:?}");
+                    first_error.get_or_insert(Err(e));
+                }
+            }
+        };
+        if tokio::time::timeout(DRAIN_TIMEOUT, drain).await.is_err() {
+            eprintln!("Tasks did not stop within {DRAIN_TIMEOUT:?}, aborting them");
+            self.tasks.abort_all();
+        }
+        first_error.unwrap_or(Ok(()))
+    }
+}
+
+fn task_error(joined: Result<(&'static str, anyhow::Result<()>), tokio::task::JoinError>) -> anyhow::Result<()> {
+    match joined {
+        Ok((name, result)) => result.map_err(|e| e.context(format!("{name} failed"))),
+        Err(e) => Err(anyhow::anyhow!("task panicked: {e}")),
+    }
+}
+
+// shutdown_signal completes on Ctrl+C, or SIGTERM on Unix
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to listen for Ctrl+C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_restarts_then_fails() {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let counter = attempts.clone();
+        let mut supervisor = Supervisor::new();
+        supervisor.spawn("flaky", Restart::OnError { max: 2 }, move |_| {
+            let counter = counter.clone();
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                anyhow::bail!("boom")
+            }
+        });
+        let error = supervisor.run().await.unwrap_err();
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert!(format!("{error:?}").contains("flaky failed"));
+    }
+
+    #[tokio::test]
+    async fn test_success_resets_failures() {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let counter = attempts.clone();
+        let mut supervisor = Supervisor::new();
+        supervisor.spawn("flaky", Restart::OnError { max: 2 }, move |_| {
+            let counter = counter.clone();
+            async move {
+                // Fails, then succeeds, then fails for good
+                match counter.fetch_add(1, Ordering::SeqCst) {
+                    1 => Ok(()),
+                    _ => anyhow::bail!("boom"),
+                }
+            }
+        });
+        assert!(supervisor.run().await.is_err());
+        // Without the reset the third attempt would have been the last
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_failure_cancels_other_tasks() {
+        let mut supervisor = Supervisor::new();
+        let stopped = Arc::new(AtomicU32::new(0));
+        let counter = stopped.clone();
+        supervisor.spawn("worker", Restart::Never, move |shutdown| {
+            let counter = counter.clone();
+            async move {
+                shutdown.cancelled().await;
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        });
+        supervisor.spawn("broken", Restart::Never, |_| async { anyhow::bail!("boom") });
+        assert!(supervisor.run().await.is_err());
+        assert_eq!(stopped.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,35 @@
+use crate::alerts::Alerts;
+use crate::anomaly::Detector;
+use crate::collector::Pipeline;
+use crate::notify::Dispatcher;
+use axum::Router;
+use sqlx::{Pool, Sqlite};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+// memory_pool is an empty in-memory database with every migration applied
+pub async fn memory_pool() -> Pool<Sqlite> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await.unwrap();
+    sqlx::migrate!().run(&pool).await.unwrap();
+    pool
+}
+
+// pipeline ingests into `pool`, with the rules already saved there, no
+// notifiers and the default anomaly configuration
+pub async fn pipeline(pool: &Pool<Sqlite>) -> Pipeline {
+    Pipeline {
+        cnn: pool.clone(),
+        alerts: Arc::new(Alerts::load(pool, Dispatcher::new(Vec::new())).await.unwrap()),
+        detector: Arc::new(Detector::new(Default::default())),
+        samples: crate::stream::channel(),
+    }
+}
+
+// serve runs `app` on a free local port until the test ends and returns its address
+pub async fn serve(app: Router) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    addr
+}
@@ -0,0 +1,78 @@
+use axum::serve::Listener;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_native_tls::{native_tls, TlsAcceptor, TlsStream};
+
+// A client gets this long to finish the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// TlsListener lets axum::serve accept HTTPS connections. Handshakes run in
+// their own tasks so one slow client can't hold up the others.
+pub struct TlsListener {
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    // bind loads a PEM certificate chain and PKCS#8 key and starts listening
+    pub async fn bind(address: &str, cert: &Path, key: &Path) -> anyhow::Result<Self> {
+        let cert = std::fs::read(cert).map_err(|e| anyhow::anyhow!("{}: {e}", cert.display()))?;
+        let key = std::fs::read(key).map_err(|e| anyhow::anyhow!("{}: {e}", key.display()))?;
+        let identity = native_tls::Identity::from_pkcs8(&cert, &key)?;
+        let acceptor = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?);
+
+        let listener = TcpListener::bind(address).await?;
+        let local_addr = listener.local_addr()?;
+        let (tx, connections) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                let (socket, address) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        eprintln!("Failed to accept connection: {e}");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                if tx.is_closed() {
+                    return; // The server has stopped
+                }
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send((stream, address)).await;
+                        }
+                        Ok(Err(e)) => eprintln!("TLS handshake with {address} failed: {e}"),
+                        Err(_) => eprintln!("TLS handshake with {address} timed out"),
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            connections,
+            local_addr,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // The accept task never stops while we hold the receiver
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
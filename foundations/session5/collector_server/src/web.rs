@@ -1,13 +1,14 @@
 use axum::response::Html;
 
-pub async fn index() -> Html<String> {
-    let path = std::path::Path::new("src/index.html");
-    let content = tokio::fs::read_to_string(path).await.unwrap();
-    Html(content)
+// The pages are compiled into the binary, so the server doesn't depend on the
+// directory it is started from
+const INDEX: &str = include_str!("index.html");
+const COLLECTOR: &str = include_str!("collector.html");
+
+pub async fn index() -> Html<&'static str> {
+    Html(INDEX)
 }
 
-pub async fn collector() -> Html<String> {
-    let path = std::path::Path::new("src/collector.html");
-    let content = tokio::fs::read_to_string(path).await.unwrap();
-    Html(content)
+pub async fn collector() -> Html<&'static str> {
+    Html(COLLECTOR)
 }
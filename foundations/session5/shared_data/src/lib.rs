@@ -1,6 +1,7 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 
 /*
@@ -27,7 +28,14 @@ use serde::{Deserialize, Serialize};
 
 pub const DATA_COLLECTOR_ADDRESS: &str = "127.0.0.1:9004";
 const MAGIC_NUMBER: u16 = 1234;
-const VERSION_NUMBER: u16 = 1;
+// Version 2 added CollectorCommandV1::SetLabels and CollectorResponseV1::Error.
+// Version 1 frames are still accepted, but a version 1 peer must not be sent
+// or send anything it doesn't know about.
+pub const VERSION_NUMBER: u16 = 2;
+pub const MIN_VERSION_NUMBER: u16 = 1;
+// HEADER_SIZE is the number of bytes before the payload, CRC_SIZE the number after it
+pub const HEADER_SIZE: usize = 12;
+pub const CRC_SIZE: usize = 4;
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum CollectorCommandV1 {
@@ -37,11 +45,67 @@ pub enum CollectorCommandV1 {
         used_memory: u64,
         average_cpu_usage: f32,
     },
+    // SetLabels describes the collector with key/value labels such as
+    // role=db. Labels it already has and that aren't listed are kept.
+    SetLabels {
+        collector_id: u128,
+        labels: Vec<(String, String)>,
+    },
+}
+
+impl CollectorCommandV1 {
+    //since_version is the first protocol version that has the command.
+    pub fn since_version(&self) -> u16 {
+        match self {
+            CollectorCommandV1::SubmitData { .. } => 1,
+            CollectorCommandV1::SetLabels { .. } => 2,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum CollectorResponseV1 {
     Ack(u128),
+    Error(ErrorCode),
+}
+
+impl CollectorResponseV1 {
+    //since_version is the first protocol version that has the response.
+    pub fn since_version(&self) -> u16 {
+        match self {
+            CollectorResponseV1::Ack(_) => 1,
+            CollectorResponseV1::Error(_) => 2,
+        }
+    }
+}
+
+// ErrorCode tells a collector why the server refused a submission or is
+// about to close the connection
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    TooManyConnections,
+    TooManyConnectionsFromAddress,
+    FrameTooLarge,
+    ReadTimeout,
+    IdleTimeout,
+    InvalidFrame,
+    StorageFailed,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum DecodeError {
+    #[error("frame is shorter than its header says")]
+    Truncated,
+    #[error("bad magic number {0}")]
+    BadMagicNumber(u16),
+    #[error("unsupported version {0}")]
+    UnsupportedVersion(u16),
+    #[error("command needs protocol version {0}")]
+    CommandNeedsVersion(u16),
+    #[error("CRC mismatch")]
+    BadCrc,
+    #[error("invalid payload: {0}")]
+    InvalidPayload(String),
 }
 
 //unix_now gets the current time in seconds since the Unix epoch.
@@ -73,14 +137,40 @@ pub fn encode_v1(command: &CollectorCommandV1) -> Vec<u8> {
     result
 }
 
-//decode_v1 decodes a Vec<u8> into a CollectorCommandV1 following the protocol spec.
-pub fn decode_v1(bytes: &[u8]) -> (u32, CollectorCommandV1) {
-    let magic_number = u16::from_be_bytes([bytes[0], bytes[1]]);
-    let version_number = u16::from_be_bytes([bytes[2], bytes[3]]);
+//frame_version returns the protocol version a frame header says the frame uses.
+pub fn frame_version(header: &[u8; HEADER_SIZE]) -> u16 {
+    u16::from_be_bytes([header[2], header[3]])
+}
+
+//frame_size reads a frame header and returns the size of the whole frame,
+//so a reader knows how much to read before allocating anything for it.
+pub fn frame_size(header: &[u8; HEADER_SIZE]) -> Result<usize, DecodeError> {
+    let magic_number = u16::from_be_bytes([header[0], header[1]]);
+    if magic_number != MAGIC_NUMBER {
+        return Err(DecodeError::BadMagicNumber(magic_number));
+    }
+    let version_number = frame_version(header);
+    if !(MIN_VERSION_NUMBER..=VERSION_NUMBER).contains(&version_number) {
+        return Err(DecodeError::UnsupportedVersion(version_number));
+    }
+    let payload_size = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+    Ok(HEADER_SIZE + payload_size as usize + CRC_SIZE)
+}
+
+//try_decode_v1 decodes a Vec<u8> into a CollectorCommandV1 following the protocol spec,
+//returning an error instead of panicking on a malformed frame.
+pub fn try_decode_v1(bytes: &[u8]) -> Result<(u32, CollectorCommandV1), DecodeError> {
+    let header: &[u8; HEADER_SIZE] = bytes
+        .get(..HEADER_SIZE)
+        .and_then(|header| header.try_into().ok())
+        .ok_or(DecodeError::Truncated)?;
+    if bytes.len() < frame_size(header)? {
+        return Err(DecodeError::Truncated);
+    }
     let timestamp = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
     let payload_size = u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
     let payload = &bytes[12..12 + payload_size as usize]; // 12 is the start of the payload
-    //crc is the last 4 bytes. We can get it by taking the last 4 bytes of the slice.
+    //crc is the 4 bytes after the payload.
     let crc = u32::from_be_bytes([
         bytes[12 + payload_size as usize],
         bytes[13 + payload_size as usize],
@@ -88,24 +178,36 @@ pub fn decode_v1(bytes: &[u8]) -> (u32, CollectorCommandV1) {
         bytes[15 + payload_size as usize],
     ]);
 
-    // Verify the magic number
-    assert_eq!(magic_number, MAGIC_NUMBER);
-
-    // Verify the version number
-    assert_eq!(version_number, VERSION_NUMBER);
-
     // Verify the CRC
     let computed_crc = crc32fast::hash(payload);
-    assert_eq!(crc, computed_crc);
+    if crc != computed_crc {
+        return Err(DecodeError::BadCrc);
+    }
 
     // Decode the payload
-    (timestamp, bincode::deserialize(payload).unwrap())
+    let command: CollectorCommandV1 = bincode::deserialize(payload).map_err(|e| DecodeError::InvalidPayload(e.to_string()))?;
+    if command.since_version() > frame_version(header) {
+        return Err(DecodeError::CommandNeedsVersion(command.since_version()));
+    }
+    Ok((timestamp, command))
+}
+
+//decode_v1 decodes a Vec<u8> into a CollectorCommandV1 following the protocol spec.
+//It panics on a malformed frame.
+pub fn decode_v1(bytes: &[u8]) -> (u32, CollectorCommandV1) {
+    try_decode_v1(bytes).expect("invalid frame")
 }
 
 pub fn encode_response_v1(command: CollectorResponseV1) -> Vec<u8> {
     bincode::serialize(&command).unwrap()
 }
 
+//encode_response_for encodes a response for a peer that speaks the given protocol
+//version, or returns None if the peer is too old to understand it.
+pub fn encode_response_for(command: CollectorResponseV1, version: u16) -> Option<Vec<u8>> {
+    (command.since_version() <= version).then(|| encode_response_v1(command))
+}
+
 pub fn decode_response_v1(bytes: &[u8]) -> CollectorResponseV1 {
     bincode::deserialize(bytes).unwrap()
 }
@@ -126,6 +228,17 @@ mod tests {
         let (timestamp, decoded) = decode_v1(&encoded);
         assert_eq!(decoded, command);
         assert!(timestamp > 0);
+        assert_eq!(frame_version(encoded[..HEADER_SIZE].try_into().unwrap()), VERSION_NUMBER);
+    }
+
+    #[test]
+    fn test_encode_decode_labels() {
+        let command = CollectorCommandV1::SetLabels {
+            collector_id: 123,
+            labels: vec![("role".to_string(), "db".to_string())],
+        };
+        let (_, decoded) = decode_v1(&encode_v1(&command));
+        assert_eq!(decoded, command);
     }
 
     #[test]
@@ -134,5 +247,79 @@ mod tests {
         let encoded = encode_response_v1(response.clone());
         let decoded = decode_response_v1(&encoded);
         assert_eq!(decoded, response);
+
+        let response = CollectorResponseV1::Error(ErrorCode::FrameTooLarge);
+        let decoded = decode_response_v1(&encode_response_v1(response.clone()));
+        assert_eq!(decoded, response);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let command = CollectorCommandV1::SubmitData {
+            collector_id: 123,
+            total_memory: 100,
+            used_memory: 50,
+            average_cpu_usage: 0.5,
+        };
+        let encoded = encode_v1(&command);
+        let header: &[u8; HEADER_SIZE] = encoded[..HEADER_SIZE].try_into().unwrap();
+        assert_eq!(frame_size(header), Ok(encoded.len()));
+
+        assert_eq!(try_decode_v1(&encoded[..encoded.len() - 1]), Err(DecodeError::Truncated));
+        let mut corrupt = encoded.clone();
+        corrupt[HEADER_SIZE] ^= 0xff;
+        assert_eq!(try_decode_v1(&corrupt), Err(DecodeError::BadCrc));
+        let mut corrupt = encoded.clone();
+        corrupt[0] = 0;
+        assert!(matches!(try_decode_v1(&corrupt), Err(DecodeError::BadMagicNumber(_))));
+    }
+
+    // v1_frame builds a frame the way a version 1 peer does, with the payload
+    // laid out by hand so a change to the wire format shows up here
+    fn v1_frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&MAGIC_NUMBER.to_be_bytes());
+        frame.extend_from_slice(&1u16.to_be_bytes());
+        frame.extend_from_slice(&1_700_000_000u32.to_be_bytes());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame.extend_from_slice(&crc32fast::hash(payload).to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn test_version_1_peer() {
+        // SubmitData is variant 0, then its fields in order, little endian
+        let mut payload = 0u32.to_le_bytes().to_vec();
+        payload.extend_from_slice(&123u128.to_le_bytes());
+        payload.extend_from_slice(&100u64.to_le_bytes());
+        payload.extend_from_slice(&50u64.to_le_bytes());
+        payload.extend_from_slice(&0.5f32.to_le_bytes());
+        let frame = v1_frame(&payload);
+        assert_eq!(frame_version(frame[..HEADER_SIZE].try_into().unwrap()), 1);
+        assert_eq!(
+            try_decode_v1(&frame),
+            Ok((1_700_000_000, CollectorCommandV1::SubmitData {
+                collector_id: 123,
+                total_memory: 100,
+                used_memory: 50,
+                average_cpu_usage: 0.5,
+            }))
+        );
+
+        // A version 1 peer can't have meant SetLabels
+        let set_labels = bincode::serialize(&CollectorCommandV1::SetLabels { collector_id: 123, labels: vec![] }).unwrap();
+        assert_eq!(try_decode_v1(&v1_frame(&set_labels)), Err(DecodeError::CommandNeedsVersion(2)));
+
+        // An Ack is what a version 1 peer expects, an Error it can't decode
+        let mut ack = 0u32.to_le_bytes().to_vec();
+        ack.extend_from_slice(&0u128.to_le_bytes());
+        assert_eq!(encode_response_for(CollectorResponseV1::Ack(0), 1), Some(ack));
+        assert_eq!(encode_response_for(CollectorResponseV1::Error(ErrorCode::InvalidFrame), 1), None);
+        assert!(encode_response_for(CollectorResponseV1::Error(ErrorCode::InvalidFrame), VERSION_NUMBER).is_some());
+
+        let mut too_new = encode_v1(&CollectorCommandV1::SetLabels { collector_id: 123, labels: vec![] });
+        too_new[2..4].copy_from_slice(&(VERSION_NUMBER + 1).to_be_bytes());
+        assert_eq!(try_decode_v1(&too_new), Err(DecodeError::UnsupportedVersion(VERSION_NUMBER + 1)));
     }
 }